@@ -0,0 +1,22 @@
+/*
+
+Fuzzing the ReadOnlyProperties parser
+
+Any bytes must parse or fail without panicking, and whatever parses must
+encode back to the same properties.
+
+*/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reasha::asha::ReadOnlyProperties;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(properties) = ReadOnlyProperties::parse(data) {
+        assert_eq!(
+            ReadOnlyProperties::parse(&properties.to_bytes()),
+            Ok(properties)
+        );
+    }
+});
@@ -0,0 +1,94 @@
+/*
+
+ASHA (Audio Streaming for Hearing Aids) protocol constants and types
+
+*/
+
+pub mod audio;
+mod control;
+mod properties;
+
+pub use control::{AudioType, ControlCommand, StatusError, Update, parse_status};
+pub use properties::{Capabilities, Codecs, FeatureMap, PropertiesError, ReadOnlyProperties, Side};
+
+use bluer::Uuid;
+use std::{fmt, str::FromStr};
+
+pub const ASHA_SERVICE_U16: u16 = 0xFDF0;
+
+/// Static device information, readable once connected.
+pub const READ_ONLY_PROPERTIES_UUID: Uuid = Uuid::from_u128(0x6333651e_c481_4a3e_9169_7c902aad37bb);
+
+/// Starts and stops streams, written by the central.
+pub const AUDIO_CONTROL_POINT_UUID: Uuid = Uuid::from_u128(0xf0d4de7e_4a88_476c_9d9f_1937b0996cc0);
+
+/// The PSM of the L2CAP channel audio is streamed over.
+pub const LE_PSM_OUT_UUID: Uuid = Uuid::from_u128(0x2d410339_82b6_42aa_b34e_e2e01df8cc1a);
+
+/// Stream volume, written without response as an `i8` attenuation in dB.
+pub const VOLUME_UUID: Uuid = Uuid::from_u128(0x00e4ca9e_ab14_41e4_8823_f9e70c7e91df);
+
+/// The lowest Volume value, which mutes the stream.
+pub const MUTED: i8 = -128;
+
+/// Result of the last AudioControlPoint command, notified by the device.
+pub const AUDIO_STATUS_POINT_UUID: Uuid = Uuid::from_u128(0x38663f1a_e711_4cac_b641_326b56404837);
+
+/// Identifier shared by both devices of a binaural set. Stored in the byte
+/// order it is transmitted in (little-endian).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HiSyncId(pub [u8; 8]);
+
+impl HiSyncId {
+    /// Bluetooth SIG company identifier of the manufacturer.
+    pub fn manufacturer(&self) -> u16 {
+        u16::from_le_bytes([self.0[0], self.0[1]])
+    }
+
+    /// Advertisements only carry the four least significant bytes.
+    pub fn matches_truncated(&self, truncated: &[u8]) -> bool {
+        truncated.len() == 4 && self.0[..4] == *truncated
+    }
+}
+
+impl fmt::Display for HiSyncId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HiSyncId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = s.chars().filter(|c| *c != ':' && *c != '-').collect();
+        if hex.len() != 16 || !hex.is_ascii() {
+            return Err(format!("HiSyncId `{s}` must be 8 bytes of hex"));
+        }
+
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| format!("HiSyncId `{s}` is not valid hex"))?;
+        }
+        Ok(HiSyncId(bytes))
+    }
+}
+
+/// Parses the ASHA advertisement service data:
+/// protocol version, capability, truncated HiSyncId.
+pub fn truncated_hisyncid(service_data: &[u8]) -> Option<&[u8]> {
+    service_data.get(2..6)
+}
+
+/// LE_PSM_OUT is a little-endian `u16`.
+pub fn parse_psm(value: &[u8]) -> Option<u16> {
+    value.first_chunk().copied().map(u16::from_le_bytes)
+}
+
+pub fn advertised_capabilities(service_data: &[u8]) -> Option<Capabilities> {
+    service_data.get(1).copied().map(Capabilities)
+}
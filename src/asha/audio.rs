@@ -0,0 +1,78 @@
+/*
+
+ASHA audio packets
+
+Audio is sent over the L2CAP channel as G.722 at 16 kHz, 20 ms per packet:
+a sequence number followed by 160 bytes of G.722. The sequence number
+starts at 0 after each Start command and wraps around after 255.
+
+*/
+
+use crate::g722;
+use std::time::Duration;
+
+pub const SAMPLE_RATE: u32 = 16_000;
+
+/// Audio carried by one packet.
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// PCM samples per packet.
+pub const FRAME_SAMPLES: usize = 320;
+
+/// G.722 bytes per packet.
+pub const FRAME_BYTES: usize = FRAME_SAMPLES / 2;
+
+/// A frame with its sequence number.
+pub const PACKET_BYTES: usize = FRAME_BYTES + 1;
+
+/// Turns 16 kHz mono PCM into ASHA packets.
+#[derive(Clone, Debug, Default)]
+pub struct Packetizer {
+    encoder: g722::Encoder,
+    sequence: u8,
+    /// Encoded bytes not yet filling a frame.
+    encoded: Vec<u8>,
+}
+
+impl Packetizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encodes `pcm` and returns every packet completed by it. The rest is
+    /// kept for the next call.
+    pub fn push(&mut self, pcm: &[i16]) -> Vec<[u8; PACKET_BYTES]> {
+        self.encoder.encode(pcm, &mut self.encoded);
+
+        let mut packets = Vec::new();
+        let mut frames = self.encoded.chunks_exact(FRAME_BYTES);
+
+        for frame in frames.by_ref() {
+            let mut packet = [0; PACKET_BYTES];
+            packet[0] = self.sequence;
+            packet[1..].copy_from_slice(frame);
+            packets.push(packet);
+
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+
+        let rest = frames.remainder().len();
+        self.encoded.drain(..self.encoded.len() - rest);
+        packets
+    }
+
+    /// Pads the frame under way with silence and returns it, unless
+    /// nothing of it was pushed. For the end of a stream.
+    pub fn finish(&mut self) -> Option<[u8; PACKET_BYTES]> {
+        let pending = 2 * self.encoded.len() + self.encoder.pending();
+        if pending == 0 {
+            return None;
+        }
+        self.push(&vec![0; FRAME_SAMPLES - pending]).pop()
+    }
+
+    /// Starts over for a new stream, as after a Start command.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
@@ -0,0 +1,240 @@
+/*
+
+AudioControlPoint commands and AudioStatusPoint results
+
+The central starts and stops a stream by writing to AudioControlPoint:
+
+    opcode  name    parameters
+    1       Start   codec id, audio type, volume (i8), other side state
+    2       Stop    -
+    3       Status  other side state or connection update
+
+The device answers every command with a notification on AudioStatusPoint
+carrying a signed status code, 0 meaning success.
+
+*/
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioType {
+    Unknown,
+    Ringtone,
+    PhoneCall,
+    Media,
+}
+
+impl AudioType {
+    fn byte(self) -> u8 {
+        match self {
+            AudioType::Unknown => 0,
+            AudioType::Ringtone => 1,
+            AudioType::PhoneCall => 2,
+            AudioType::Media => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<AudioType> {
+        match byte {
+            0 => Some(AudioType::Unknown),
+            1 => Some(AudioType::Ringtone),
+            2 => Some(AudioType::PhoneCall),
+            3 => Some(AudioType::Media),
+            _ => None,
+        }
+    }
+}
+
+/// What the Status command tells the device about the rest of the set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Update {
+    OtherSideDisconnected,
+    OtherSideConnected,
+    ParametersUpdated,
+}
+
+impl Update {
+    fn byte(self) -> u8 {
+        match self {
+            Update::OtherSideDisconnected => 0,
+            Update::OtherSideConnected => 1,
+            Update::ParametersUpdated => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Update> {
+        match byte {
+            0 => Some(Update::OtherSideDisconnected),
+            1 => Some(Update::OtherSideConnected),
+            2 => Some(Update::ParametersUpdated),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Update {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Update::OtherSideDisconnected => write!(f, "other side disconnected"),
+            Update::OtherSideConnected => write!(f, "other side connected"),
+            Update::ParametersUpdated => write!(f, "connection parameters updated"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    Start {
+        codec: u8,
+        audio_type: AudioType,
+        /// Attenuation in dB, -128 (muted) to 0.
+        volume: i8,
+        other_side_connected: bool,
+    },
+    Stop,
+    Status(Update),
+}
+
+impl ControlCommand {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            ControlCommand::Start {
+                codec,
+                audio_type,
+                volume,
+                other_side_connected,
+            } => vec![
+                1,
+                codec,
+                audio_type.byte(),
+                volume as u8,
+                other_side_connected.into(),
+            ],
+            ControlCommand::Stop => vec![2],
+            ControlCommand::Status(update) => vec![3, update.byte()],
+        }
+    }
+
+    /// Decodes a written value, as a device would.
+    pub fn parse(bytes: &[u8]) -> Option<ControlCommand> {
+        match *bytes {
+            [1, codec, audio_type, volume, other_side] => Some(ControlCommand::Start {
+                codec,
+                audio_type: AudioType::from_byte(audio_type)?,
+                volume: volume as i8,
+                other_side_connected: match other_side {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                },
+            }),
+            [2] => Some(ControlCommand::Stop),
+            [3, update] => Some(ControlCommand::Status(Update::from_byte(update)?)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlCommand::Start { codec, volume, .. } => {
+                write!(f, "Start (codec {codec}, volume {volume} dB)")
+            }
+            ControlCommand::Stop => write!(f, "Stop"),
+            ControlCommand::Status(update) => write!(f, "Status ({update})"),
+        }
+    }
+}
+
+/// A command the device did not accept, decoded from AudioStatusPoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusError {
+    UnknownCommand,
+    IllegalParameters,
+    /// A status code the protocol does not define.
+    Other(i8),
+    /// The value is not a single byte.
+    Malformed(usize),
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusError::UnknownCommand => write!(f, "unknown command"),
+            StatusError::IllegalParameters => write!(f, "illegal parameters"),
+            StatusError::Other(code) => write!(f, "status code {code}"),
+            StatusError::Malformed(length) => {
+                write!(f, "AudioStatusPoint is {length} bytes long, expected 1")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+pub fn parse_status(bytes: &[u8]) -> Result<(), StatusError> {
+    match *bytes {
+        [0] => Ok(()),
+        [code] => Err(match code as i8 {
+            -1 => StatusError::UnknownCommand,
+            -2 => StatusError::IllegalParameters,
+            code => StatusError::Other(code),
+        }),
+        _ => Err(StatusError::Malformed(bytes.len())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_round_trip() {
+        for command in [
+            ControlCommand::Start {
+                codec: 1,
+                audio_type: AudioType::Media,
+                volume: -128,
+                other_side_connected: true,
+            },
+            ControlCommand::Start {
+                codec: 2,
+                audio_type: AudioType::PhoneCall,
+                volume: 0,
+                other_side_connected: false,
+            },
+            ControlCommand::Stop,
+            ControlCommand::Status(Update::OtherSideDisconnected),
+            ControlCommand::Status(Update::ParametersUpdated),
+        ] {
+            assert_eq!(ControlCommand::parse(&command.to_bytes()), Some(command));
+        }
+    }
+
+    #[test]
+    fn malformed_commands() {
+        for bytes in [
+            &[][..],
+            &[0],
+            &[1, 1, 3, 0],
+            &[1, 1, 4, 0, 0],
+            &[1, 1, 3, 0, 2],
+            &[2, 0],
+            &[3, 3],
+            &[4],
+        ] {
+            assert_eq!(ControlCommand::parse(bytes), None, "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(parse_status(&[0]), Ok(()));
+        assert_eq!(parse_status(&[0xff]), Err(StatusError::UnknownCommand));
+        assert_eq!(parse_status(&[0xfe]), Err(StatusError::IllegalParameters));
+        assert_eq!(parse_status(&[0x05]), Err(StatusError::Other(5)));
+        assert_eq!(parse_status(&[]), Err(StatusError::Malformed(0)));
+        assert_eq!(parse_status(&[0, 0]), Err(StatusError::Malformed(2)));
+    }
+}
@@ -0,0 +1,287 @@
+/*
+
+The ReadOnlyProperties characteristic
+
+Static information about a hearing aid, 17 bytes in version 1 of the
+protocol:
+
+    offset  size  field
+    0       1     version
+    1       1     DeviceCapabilities
+    2       8     HiSyncId
+    10      1     FeatureMap
+    11      2     RenderDelay (ms, little-endian)
+    13      2     reserved (PreparationDelay)
+    15      2     supported codec ids (bitmask, little-endian)
+
+The bytes come straight from the radio, so the parser accepts anything
+without panicking. Longer values are accepted and the extra bytes are
+ignored, as newer protocol versions may append fields.
+
+*/
+
+use super::HiSyncId;
+use std::{fmt, time::Duration};
+
+/// Length of a version 1 value.
+pub const LENGTH: usize = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Side::Left => write!(f, "left"),
+            Side::Right => write!(f, "right"),
+        }
+    }
+}
+
+/// The DeviceCapabilities byte, shared by the advertisement and
+/// ReadOnlyProperties.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(pub u8);
+
+impl Capabilities {
+    pub fn side(self) -> Side {
+        if self.0 & 0x01 == 0 {
+            Side::Left
+        } else {
+            Side::Right
+        }
+    }
+
+    /// Whether the device is one of a binaural set.
+    pub fn binaural(self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    /// Whether the device supports the Coordinated Set Identification
+    /// Service.
+    pub fn csis(self) -> bool {
+        self.0 & 0x04 != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeatureMap(pub u8);
+
+impl FeatureMap {
+    /// Whether audio can be streamed over an LE credit based channel.
+    pub fn coc_streaming(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+}
+
+/// Bitmask of the codec ids the device can decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Codecs(pub u16);
+
+impl Codecs {
+    /// Codec id 1: G.722 at 16 kHz.
+    pub const G722_16KHZ: u8 = 1;
+    /// Codec id 2: G.722 at 24 kHz.
+    pub const G722_24KHZ: u8 = 2;
+
+    pub fn supports(self, id: u8) -> bool {
+        id < 16 && self.0 & (1 << id) != 0
+    }
+
+    /// Ids of all supported codecs, lowest first.
+    pub fn ids(self) -> impl Iterator<Item = u8> {
+        (0..16).filter(move |id| self.supports(*id))
+    }
+
+    pub fn name(id: u8) -> Option<&'static str> {
+        match id {
+            Codecs::G722_16KHZ => Some("G.722 16 kHz"),
+            Codecs::G722_24KHZ => Some("G.722 24 kHz"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Codecs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for id in self.ids() {
+            if !first {
+                write!(f, ", ")?;
+            }
+            first = false;
+
+            match Codecs::name(id) {
+                Some(name) => write!(f, "{name}")?,
+                None => write!(f, "unknown codec {id}")?,
+            }
+        }
+
+        if first { write!(f, "none") } else { Ok(()) }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadOnlyProperties {
+    pub version: u8,
+    pub capabilities: Capabilities,
+    pub hisyncid: HiSyncId,
+    pub feature_map: FeatureMap,
+    /// Time from receiving a frame to playing it, in milliseconds.
+    pub render_delay: u16,
+    pub codecs: Codecs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropertiesError {
+    /// Fewer bytes than a version 1 value has.
+    TooShort(usize),
+    /// Version 0 is not a valid protocol version.
+    InvalidVersion,
+}
+
+impl fmt::Display for PropertiesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertiesError::TooShort(length) => write!(
+                f,
+                "ReadOnlyProperties is {length} bytes long, expected at least {LENGTH}"
+            ),
+            PropertiesError::InvalidVersion => write!(f, "ReadOnlyProperties has version 0"),
+        }
+    }
+}
+
+impl std::error::Error for PropertiesError {}
+
+impl ReadOnlyProperties {
+    pub fn parse(bytes: &[u8]) -> Result<ReadOnlyProperties, PropertiesError> {
+        let Some(bytes) = bytes.first_chunk::<LENGTH>() else {
+            return Err(PropertiesError::TooShort(bytes.len()));
+        };
+
+        if bytes[0] == 0 {
+            return Err(PropertiesError::InvalidVersion);
+        }
+
+        let mut hisyncid = [0; 8];
+        hisyncid.copy_from_slice(&bytes[2..10]);
+
+        Ok(ReadOnlyProperties {
+            version: bytes[0],
+            capabilities: Capabilities(bytes[1]),
+            hisyncid: HiSyncId(hisyncid),
+            feature_map: FeatureMap(bytes[10]),
+            render_delay: u16::from_le_bytes([bytes[11], bytes[12]]),
+            codecs: Codecs(u16::from_le_bytes([bytes[15], bytes[16]])),
+        })
+    }
+
+    /// Encodes the value as a device would send it.
+    pub fn to_bytes(&self) -> [u8; LENGTH] {
+        let mut bytes = [0; LENGTH];
+        bytes[0] = self.version;
+        bytes[1] = self.capabilities.0;
+        bytes[2..10].copy_from_slice(&self.hisyncid.0);
+        bytes[10] = self.feature_map.0;
+        bytes[11..13].copy_from_slice(&self.render_delay.to_le_bytes());
+        bytes[15..17].copy_from_slice(&self.codecs.0.to_le_bytes());
+        bytes
+    }
+
+    pub fn render_delay(&self) -> Duration {
+        Duration::from_millis(self.render_delay.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A typical version 1 value: right side of a binaural set, streaming
+    /// over CoC, 160 ms render delay, G.722 at 16 kHz.
+    const VALUE: [u8; LENGTH] = [
+        0x01, 0x03, 0x01, 0x00, 0x7a, 0x3c, 0x11, 0x22, 0x33, 0x44, 0x01, 0xa0, 0x00, 0x00, 0x00,
+        0x02, 0x00,
+    ];
+
+    #[test]
+    fn parses_a_version_1_value() {
+        let properties = ReadOnlyProperties::parse(&VALUE).unwrap();
+
+        assert_eq!(properties.version, 1);
+        assert_eq!(properties.capabilities.side(), Side::Right);
+        assert!(properties.capabilities.binaural());
+        assert!(!properties.capabilities.csis());
+        assert_eq!(
+            properties.hisyncid,
+            HiSyncId([0x01, 0x00, 0x7a, 0x3c, 0x11, 0x22, 0x33, 0x44])
+        );
+        assert!(properties.feature_map.coc_streaming());
+        assert_eq!(properties.render_delay(), Duration::from_millis(160));
+        assert!(properties.codecs.supports(Codecs::G722_16KHZ));
+        assert!(!properties.codecs.supports(Codecs::G722_24KHZ));
+        assert_eq!(properties.codecs.to_string(), "G.722 16 kHz");
+
+        assert_eq!(properties.to_bytes(), VALUE);
+    }
+
+    #[test]
+    fn truncated_values_are_rejected() {
+        for length in 0..LENGTH {
+            assert_eq!(
+                ReadOnlyProperties::parse(&VALUE[..length]),
+                Err(PropertiesError::TooShort(length))
+            );
+        }
+    }
+
+    #[test]
+    fn side_and_binaural_bits() {
+        for (byte, side, binaural) in [
+            (0x00, Side::Left, false),
+            (0x01, Side::Right, false),
+            (0x02, Side::Left, true),
+            (0x03, Side::Right, true),
+            (0xfc, Side::Left, false),
+        ] {
+            let mut value = VALUE;
+            value[1] = byte;
+            let capabilities = ReadOnlyProperties::parse(&value).unwrap().capabilities;
+            assert_eq!(capabilities.side(), side, "{byte:#04x}");
+            assert_eq!(capabilities.binaural(), binaural, "{byte:#04x}");
+        }
+    }
+
+    #[test]
+    fn unknown_versions_keep_the_known_fields() {
+        let mut value = VALUE.to_vec();
+        value[0] = 2;
+        value.extend([0xff; 4]);
+
+        let properties = ReadOnlyProperties::parse(&value).unwrap();
+        assert_eq!(properties.version, 2);
+        assert_eq!(properties.render_delay, 160);
+        assert_eq!(properties.codecs, Codecs(0x0002));
+    }
+
+    #[test]
+    fn version_0_is_invalid() {
+        let mut value = VALUE;
+        value[0] = 0;
+        assert_eq!(
+            ReadOnlyProperties::parse(&value),
+            Err(PropertiesError::InvalidVersion)
+        );
+    }
+
+    #[test]
+    fn codec_listing() {
+        assert_eq!(Codecs(0).to_string(), "none");
+        assert_eq!(Codecs(0x0006).to_string(), "G.722 16 kHz, G.722 24 kHz");
+        assert_eq!(Codecs(0x8000).to_string(), "unknown codec 15");
+    }
+}
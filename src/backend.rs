@@ -0,0 +1,170 @@
+/*
+
+Bluetooth backend abstraction
+
+Everything the daemon needs from the Bluetooth stack goes through these
+traits: `bluez` talks to bluetoothd through bluer, `mock` is an in-memory
+stand-in that can be scripted to exercise the reconnect policy without any
+hardware, and `multi` spreads the devices over several adapters of either.
+
+*/
+
+pub mod bluez;
+pub mod mock;
+pub mod multi;
+
+use bluer::{Address, Result, Uuid, gatt::WriteOp};
+use futures::{Future, stream::BoxStream};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdapterEvent {
+    DeviceAdded(Address),
+    DeviceRemoved(Address),
+    /// An adapter was switched on or off, by index as given by
+    /// `BluetoothBackend::adapter_index`.
+    Powered {
+        adapter: usize,
+        powered: bool,
+    },
+    /// bluetoothd left the bus; nothing obtained from it is valid anymore.
+    ServiceLost,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Connected(bool),
+    ServicesResolved(bool),
+    /// An advertisement was received, reported through its signal strength.
+    Rssi(i16),
+    /// The battery level changed, in percent.
+    Battery(u8),
+}
+
+/// What was negotiated for an L2CAP connection-oriented channel. Credits
+/// are handled inside the kernel and cannot be read back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelInfo {
+    pub psm: u16,
+    pub send_mtu: usize,
+    pub recv_mtu: usize,
+}
+
+/// An open LE credit based channel to a device. Closed when dropped.
+pub trait AudioChannel: Send + 'static {
+    fn info(&self) -> ChannelInfo;
+
+    /// Sends one packet, waiting while the device has no credits left.
+    fn send(&mut self, packet: &[u8]) -> impl Future<Output = Result<()>> + Send;
+}
+
+pub trait BluetoothBackend: Clone + Send + Sync + 'static {
+    type Device: BluetoothDevice;
+
+    /// Name of the adapter, e.g. `hci0`.
+    fn name(&self) -> &str;
+
+    /// Starts discovery and reports devices as BlueZ adds and removes them,
+    /// along with the adapter's power and bluetoothd going away. Already
+    /// known devices are reported as added first, after the adapter being
+    /// reported off if it is. Discovery goes on once it is switched on.
+    fn discover(&self) -> impl Future<Output = Result<BoxStream<'static, AdapterEvent>>> + Send;
+
+    /// Reports the same as `discover` without scanning, so that only devices
+    /// already known or found by a `scan` show up.
+    fn watch(&self) -> impl Future<Output = Result<BoxStream<'static, AdapterEvent>>> + Send;
+
+    /// Scans for devices for `duration`; they are reported through `watch`.
+    /// Returns right away if the adapter is off.
+    fn scan(&self, duration: Duration) -> impl Future<Output = Result<()>> + Send;
+
+    fn device(&self, address: Address) -> Result<Self::Device>;
+
+    /// Addresses of all devices currently known to the adapter.
+    fn devices(&self) -> impl Future<Output = Result<Vec<Address>>> + Send;
+
+    /// The adapter a device is handled on, by index. Backends of a single
+    /// adapter only have index 0.
+    fn adapter_index(&self, address: Address) -> usize {
+        let _ = address;
+        0
+    }
+
+    /// Name of the adapter with that index.
+    fn adapter_name(&self, adapter: usize) -> &str {
+        let _ = adapter;
+        self.name()
+    }
+}
+
+pub trait BluetoothDevice: Clone + Send + Sync + 'static {
+    type Channel: AudioChannel;
+
+    fn address(&self) -> Address;
+
+    fn name(&self) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Signal strength of the last advertisement, if one was seen recently.
+    fn rssi(&self) -> impl Future<Output = Result<Option<i16>>> + Send;
+
+    /// Battery level in percent, if the device reports one.
+    fn battery(&self) -> impl Future<Output = Result<Option<u8>>> + Send;
+
+    /// Service UUIDs from advertisements and, once resolved, the GATT database.
+    fn uuids(&self) -> impl Future<Output = Result<Option<HashSet<Uuid>>>> + Send;
+
+    /// Advertised service data by service UUID.
+    fn service_data(&self) -> impl Future<Output = Result<Option<HashMap<Uuid, Vec<u8>>>>> + Send;
+
+    /// UUIDs of the primary services in the device's GATT database.
+    fn gatt_services(&self) -> impl Future<Output = Result<Vec<Uuid>>> + Send;
+
+    /// Reads a characteristic of a GATT service. Needs resolved services.
+    fn read_characteristic(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Writes a characteristic of a GATT service, either as a request the
+    /// device acknowledges or as a command without response.
+    fn write_characteristic(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        value: Vec<u8>,
+        op: WriteOp,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Values a characteristic notifies, until the link drops or the
+    /// stream is dropped.
+    fn notify_characteristic(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> impl Future<Output = Result<BoxStream<'static, Vec<u8>>>> + Send;
+
+    /// Opens an LE credit based channel to `psm`.
+    fn open_l2cap(&self, psm: u16) -> impl Future<Output = Result<Self::Channel>> + Send;
+
+    fn is_services_resolved(&self) -> impl Future<Output = Result<bool>> + Send;
+
+    fn is_connected(&self) -> impl Future<Output = Result<bool>> + Send;
+
+    fn is_trusted(&self) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Whether the device is bonded with this adapter.
+    fn is_paired(&self) -> impl Future<Output = Result<bool>> + Send;
+
+    fn set_trusted(&self, trusted: bool) -> impl Future<Output = Result<()>> + Send;
+
+    fn connect_profile(&self, uuid: Uuid) -> impl Future<Output = Result<()>> + Send;
+
+    fn disconnect(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Property changes of this device.
+    fn events(&self) -> impl Future<Output = Result<BoxStream<'static, DeviceEvent>>> + Send;
+}
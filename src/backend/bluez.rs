@@ -0,0 +1,425 @@
+/*
+
+BlueZ backend using bluer
+
+bluer does not notice bluetoothd leaving the system bus, so discovery also
+watches `NameOwnerChanged` for `org.bluez` on a connection of its own.
+
+Watching without scanning follows the adapter's object, which BlueZ updates
+for everyone's discovery; a scan is a discovery session that is simply held
+for a while. Discovery is watching while a task holds a session, started
+again whenever the adapter is switched back on, since BlueZ ends it when the
+adapter goes off.
+
+*/
+
+use super::{
+    AdapterEvent, AudioChannel, BluetoothBackend, BluetoothDevice, ChannelInfo, DeviceEvent,
+};
+use bluer::{
+    Adapter, AdapterProperty, Address, Device, DeviceProperty, DiscoveryFilter, Error, ErrorKind,
+    Result, Session, Uuid,
+    gatt::{
+        WriteOp,
+        remote::{Characteristic, CharacteristicWriteRequest},
+    },
+    l2cap::{SeqPacket, SocketAddr},
+};
+use dbus::{
+    message::MatchRule,
+    nonblock::{MsgMatch, SyncConnection},
+};
+use futures::{
+    Stream, StreamExt, future,
+    stream::{self, BoxStream},
+};
+use log::{debug, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+const BLUEZ_NAME: &str = "org.bluez";
+const DBUS_NAME: &str = "org.freedesktop.DBus";
+
+/// How long to wait before trying again when discovery could not be
+/// started while the adapter is on.
+const DISCOVERY_RETRY: Duration = Duration::from_mins(1);
+
+#[derive(Clone)]
+pub struct BluezBackend {
+    adapter: Adapter,
+}
+
+impl BluezBackend {
+    pub fn new(adapter: Adapter) -> Self {
+        Self { adapter }
+    }
+
+    /// Opens the default adapter of a new BlueZ session.
+    pub async fn open() -> Result<Self> {
+        let session = Session::new().await?;
+        Ok(Self::new(session.default_adapter().await?))
+    }
+
+    pub fn adapter(&self) -> &Adapter {
+        &self.adapter
+    }
+}
+
+impl BluetoothBackend for BluezBackend {
+    type Device = BluezDevice;
+
+    fn name(&self) -> &str {
+        self.adapter.name()
+    }
+
+    async fn discover(&self) -> Result<BoxStream<'static, AdapterEvent>> {
+        let events = self.watch().await?;
+        let discovering = Discovering(tokio::spawn(keep_discovering(self.adapter.clone())));
+
+        Ok(events
+            .map(move |event| {
+                let _ = &discovering;
+                event
+            })
+            .boxed())
+    }
+
+    async fn watch(&self) -> Result<BoxStream<'static, AdapterEvent>> {
+        let service_lost = watch_service().await?;
+        // Subscribed before listing, so that no device falls in between.
+        let events = self.adapter.events().await?;
+        let off = !self.adapter.is_powered().await?;
+        let known = self.adapter.device_addresses().await?;
+
+        let events = stream::iter(off.then_some(bluer::AdapterEvent::PropertyChanged(
+            AdapterProperty::Powered(false),
+        )))
+        .chain(stream::iter(known).map(bluer::AdapterEvent::DeviceAdded))
+        .chain(events);
+        Ok(stream::select(adapter_events(events), service_lost).boxed())
+    }
+
+    async fn scan(&self, duration: Duration) -> Result<()> {
+        if !self.adapter.is_powered().await? {
+            debug!("Not scanning on {}, it is off.", self.name());
+            return Ok(());
+        }
+        self.adapter.set_discovery_filter(le_filter()).await?;
+
+        let events = self.adapter.discover_devices().await?;
+        let _ = tokio::time::timeout(duration, events.for_each(|_| future::ready(()))).await;
+        Ok(())
+    }
+
+    fn device(&self, address: Address) -> Result<BluezDevice> {
+        Ok(BluezDevice(self.adapter.device(address)?))
+    }
+
+    async fn devices(&self) -> Result<Vec<Address>> {
+        self.adapter.device_addresses().await
+    }
+}
+
+fn le_filter() -> DiscoveryFilter {
+    DiscoveryFilter {
+        transport: bluer::DiscoveryTransport::Le,
+        rssi: None,
+        discoverable: false,
+        duplicate_data: false,
+        pattern: None,
+        pathloss: None,
+        ..Default::default()
+    }
+}
+
+/// Holds a discovery session whenever the adapter is on.
+async fn keep_discovering(adapter: Adapter) {
+    // Subscribed before checking the power, so that no change falls in between.
+    let Ok(mut changes) = adapter.events().await else {
+        return;
+    };
+
+    loop {
+        if adapter.is_powered().await.unwrap_or(false) {
+            let started = async {
+                adapter.set_discovery_filter(le_filter()).await?;
+                adapter.discover_devices().await
+            };
+            match started.await {
+                // Until BlueZ stops discovering, as the adapter went off.
+                Ok(events) => events.for_each(|_| future::ready(())).await,
+                Err(err) => warn!("Could not start discovery on {}: {}", adapter.name(), err),
+            }
+        }
+
+        let switched_on = async {
+            while let Some(event) = changes.next().await {
+                if matches!(
+                    event,
+                    bluer::AdapterEvent::PropertyChanged(AdapterProperty::Powered(true))
+                ) {
+                    return true;
+                }
+            }
+            false
+        };
+        tokio::select! {
+            switched_on = switched_on => if !switched_on {
+                return;
+            },
+            () = tokio::time::sleep(DISCOVERY_RETRY) => {}
+        }
+    }
+}
+
+/// Stops `keep_discovering` when the events are dropped.
+struct Discovering(JoinHandle<()>);
+
+impl Drop for Discovering {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn adapter_events(
+    events: impl Stream<Item = bluer::AdapterEvent> + Send + 'static,
+) -> impl Stream<Item = AdapterEvent> + Send + 'static {
+    events.filter_map(|event| async move {
+        match event {
+            bluer::AdapterEvent::DeviceAdded(address) => Some(AdapterEvent::DeviceAdded(address)),
+            bluer::AdapterEvent::DeviceRemoved(address) => {
+                Some(AdapterEvent::DeviceRemoved(address))
+            }
+            bluer::AdapterEvent::PropertyChanged(AdapterProperty::Powered(powered)) => {
+                Some(AdapterEvent::Powered {
+                    adapter: 0,
+                    powered,
+                })
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Reports `ServiceLost` whenever bluetoothd gives up its bus name.
+async fn watch_service() -> Result<BoxStream<'static, AdapterEvent>> {
+    let (resource, conn) = dbus_tokio::connection::new_system_sync()?;
+    let resource = tokio::spawn(async {
+        resource.await;
+    });
+
+    let (owner_match, owner_changes) = conn
+        .add_match(MatchRule::new_signal(DBUS_NAME, "NameOwnerChanged").with_sender(DBUS_NAME))
+        .await?
+        .stream::<(String, String, String)>();
+    let watch = ServiceWatch {
+        _conn: conn,
+        _owner_match: owner_match,
+        resource,
+    };
+
+    Ok(owner_changes
+        .filter_map(move |(_, (name, old_owner, _))| {
+            let _ = &watch;
+            future::ready(
+                (name == BLUEZ_NAME && !old_owner.is_empty()).then_some(AdapterEvent::ServiceLost),
+            )
+        })
+        .boxed())
+}
+
+/// Keeps the connection of `watch_service` and its match rule alive as long
+/// as the stream is.
+struct ServiceWatch {
+    _conn: Arc<SyncConnection>,
+    _owner_match: MsgMatch,
+    resource: JoinHandle<()>,
+}
+
+impl Drop for ServiceWatch {
+    fn drop(&mut self) {
+        self.resource.abort();
+    }
+}
+
+#[derive(Clone)]
+pub struct BluezDevice(Device);
+
+impl BluezDevice {
+    pub fn inner(&self) -> &Device {
+        &self.0
+    }
+
+    async fn characteristic(&self, service: Uuid, characteristic: Uuid) -> Result<Characteristic> {
+        for gatt_service in self.0.services().await? {
+            if gatt_service.uuid().await? != service {
+                continue;
+            }
+
+            for gatt_characteristic in gatt_service.characteristics().await? {
+                if gatt_characteristic.uuid().await? == characteristic {
+                    return Ok(gatt_characteristic);
+                }
+            }
+        }
+
+        Err(Error {
+            kind: ErrorKind::NotFound,
+            message: format!("characteristic {characteristic} of service {service} not found"),
+        })
+    }
+}
+
+impl BluetoothDevice for BluezDevice {
+    type Channel = BluezChannel;
+
+    fn address(&self) -> Address {
+        self.0.address()
+    }
+
+    async fn name(&self) -> Result<Option<String>> {
+        self.0.name().await
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>> {
+        self.0.rssi().await
+    }
+
+    async fn battery(&self) -> Result<Option<u8>> {
+        self.0.battery_percentage().await
+    }
+
+    async fn uuids(&self) -> Result<Option<HashSet<Uuid>>> {
+        self.0.uuids().await
+    }
+
+    async fn service_data(&self) -> Result<Option<HashMap<Uuid, Vec<u8>>>> {
+        self.0.service_data().await
+    }
+
+    async fn gatt_services(&self) -> Result<Vec<Uuid>> {
+        let mut uuids = Vec::new();
+        for service in self.0.services().await? {
+            uuids.push(service.uuid().await?);
+        }
+        Ok(uuids)
+    }
+
+    async fn read_characteristic(&self, service: Uuid, characteristic: Uuid) -> Result<Vec<u8>> {
+        self.characteristic(service, characteristic)
+            .await?
+            .read()
+            .await
+    }
+
+    async fn write_characteristic(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        value: Vec<u8>,
+        op: WriteOp,
+    ) -> Result<()> {
+        let request = CharacteristicWriteRequest {
+            op_type: op,
+            ..Default::default()
+        };
+
+        self.characteristic(service, characteristic)
+            .await?
+            .write_ext(&value, &request)
+            .await
+    }
+
+    async fn notify_characteristic(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> Result<BoxStream<'static, Vec<u8>>> {
+        let characteristic = self.characteristic(service, characteristic).await?;
+        Ok(characteristic.notify().await?.boxed())
+    }
+
+    async fn open_l2cap(&self, psm: u16) -> Result<BluezChannel> {
+        let address = SocketAddr::new(self.0.address(), self.0.address_type().await?, psm);
+        let socket = SeqPacket::connect(address).await?;
+
+        Ok(BluezChannel {
+            info: ChannelInfo {
+                psm,
+                send_mtu: socket.send_mtu()?,
+                recv_mtu: socket.recv_mtu()?,
+            },
+            socket,
+        })
+    }
+
+    async fn is_services_resolved(&self) -> Result<bool> {
+        self.0.is_services_resolved().await
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        self.0.is_connected().await
+    }
+
+    async fn is_trusted(&self) -> Result<bool> {
+        self.0.is_trusted().await
+    }
+
+    async fn is_paired(&self) -> Result<bool> {
+        self.0.is_paired().await
+    }
+
+    async fn set_trusted(&self, trusted: bool) -> Result<()> {
+        self.0.set_trusted(trusted).await
+    }
+
+    async fn connect_profile(&self, uuid: Uuid) -> Result<()> {
+        self.0.connect_profile(&uuid).await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.0.disconnect().await
+    }
+
+    async fn events(&self) -> Result<BoxStream<'static, DeviceEvent>> {
+        let events = self.0.events().await?;
+
+        Ok(events
+            .filter_map(|bluer::DeviceEvent::PropertyChanged(property)| async move {
+                match property {
+                    DeviceProperty::Connected(connected) => Some(DeviceEvent::Connected(connected)),
+                    DeviceProperty::ServicesResolved(resolved) => {
+                        Some(DeviceEvent::ServicesResolved(resolved))
+                    }
+                    DeviceProperty::Rssi(rssi) => Some(DeviceEvent::Rssi(rssi)),
+                    DeviceProperty::BatteryPercentage(battery) => {
+                        Some(DeviceEvent::Battery(battery))
+                    }
+                    _ => None,
+                }
+            })
+            .boxed())
+    }
+}
+
+/// An L2CAP socket in sequential packet mode, so that every send is one
+/// SDU.
+pub struct BluezChannel {
+    socket: SeqPacket,
+    info: ChannelInfo,
+}
+
+impl AudioChannel for BluezChannel {
+    fn info(&self) -> ChannelInfo {
+        self.info
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<()> {
+        self.socket.send(packet).await?;
+        Ok(())
+    }
+}
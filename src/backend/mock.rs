@@ -0,0 +1,716 @@
+/*
+
+In-memory backend for exercising the daemon without Bluetooth hardware
+
+Devices are added, removed and disturbed from the outside through
+`MockBackend`; the daemon sees the same events and errors it would get from
+BlueZ. Every call the daemon makes on a device is recorded. The adapter can
+be switched off and bluetoothd stopped the same way. Scans only wait and
+count, since devices are added from the outside whether or not one runs.
+
+GATT characteristics are plain values. A responder can be attached to a
+characteristic to fake a device reacting to writes, e.g. with a
+notification on another characteristic.
+
+L2CAP channels are loopback queues: the device end is taken with
+`MockBackend::accept_channel` and every packet read from it returns a
+credit to the sender.
+
+*/
+
+use super::{
+    AdapterEvent, AudioChannel, BluetoothBackend, BluetoothDevice, ChannelInfo, DeviceEvent,
+};
+use bluer::{Address, Error, ErrorKind, Result, Uuid, gatt::WriteOp};
+use futures::{
+    StreamExt,
+    channel::mpsc::{UnboundedSender, unbounded},
+    stream::{self, BoxStream},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// Credits a device grants when a channel is opened.
+pub const CHANNEL_CREDITS: usize = 8;
+
+/// A device as it should appear to the daemon.
+#[derive(Clone, Debug, Default)]
+pub struct MockDevice {
+    pub address: Address,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+    pub battery: Option<u8>,
+    pub uuids: HashSet<Uuid>,
+    pub service_data: HashMap<Uuid, Vec<u8>>,
+    pub gatt_services: Vec<Uuid>,
+    /// Characteristic values by characteristic UUID, readable and writable
+    /// once the services are resolved.
+    pub characteristics: HashMap<Uuid, Vec<u8>>,
+    /// L2CAP channels the device accepts while connected, by PSM.
+    pub channels: HashMap<u16, ChannelInfo>,
+    pub connected: bool,
+    pub trusted: bool,
+    pub paired: bool,
+}
+
+/// A call the daemon made on a mock device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    SetTrusted(bool),
+    ConnectProfile(Uuid),
+    Disconnect,
+    WriteCharacteristic(Uuid, Vec<u8>),
+    OpenL2cap(u16),
+}
+
+/// Reacts to a value written to a characteristic with notifications, given
+/// as characteristic UUID and value.
+pub type Responder = Box<dyn FnMut(&[u8]) -> Vec<(Uuid, Vec<u8>)> + Send>;
+
+#[derive(Clone)]
+pub struct MockBackend {
+    name: Arc<str>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::named("hci0")
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    devices: HashMap<Address, DeviceState>,
+    adapter_subscribers: Vec<UnboundedSender<AdapterEvent>>,
+    powered_off: bool,
+    service_stopped: bool,
+    scans: usize,
+}
+
+struct DeviceState {
+    device: MockDevice,
+    present: bool,
+    services_resolved: bool,
+    /// Number of upcoming connection attempts to refuse.
+    refuse_connects: usize,
+    calls: Vec<Call>,
+    subscribers: Vec<UnboundedSender<DeviceEvent>>,
+    responders: HashMap<Uuid, Responder>,
+    /// Notification sessions by characteristic UUID.
+    notifications: HashMap<Uuid, Vec<UnboundedSender<Vec<u8>>>>,
+    /// Device ends of the channels opened last, by PSM.
+    open_channels: HashMap<u16, mpsc::Receiver<Vec<u8>>>,
+}
+
+impl DeviceState {
+    fn emit(&mut self, event: DeviceEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event).is_ok());
+    }
+
+    fn notify(&mut self, characteristic: Uuid, value: Vec<u8>) {
+        if let Some(sessions) = self.notifications.get_mut(&characteristic) {
+            sessions.retain(|session| session.unbounded_send(value.clone()).is_ok());
+        }
+        self.device.characteristics.insert(characteristic, value);
+    }
+
+    /// The link went down, taking the GATT database with it.
+    fn unlink(&mut self) {
+        self.device.connected = false;
+        self.services_resolved = false;
+        self.notifications.clear();
+        self.open_channels.clear();
+        self.emit(DeviceEvent::ServicesResolved(false));
+        self.emit(DeviceEvent::Connected(false));
+    }
+}
+
+impl Inner {
+    fn emit(&mut self, event: AdapterEvent) {
+        self.adapter_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event).is_ok());
+    }
+
+    /// Fails while bluetoothd is stopped.
+    fn running(&self) -> Result<()> {
+        if self.service_stopped {
+            return Err(error(ErrorKind::Failed, "bluetoothd is not running"));
+        }
+        Ok(())
+    }
+
+    /// Fails while bluetoothd is stopped or the adapter is off.
+    fn ready(&self) -> Result<()> {
+        self.running()?;
+        if self.powered_off {
+            return Err(error(ErrorKind::NotReady, "adapter is powered off"));
+        }
+        Ok(())
+    }
+}
+
+fn error(kind: ErrorKind, message: &str) -> Error {
+    Error {
+        kind,
+        message: message.to_owned(),
+    }
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A backend for an adapter called `name`, to stand next to others.
+    pub fn named(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            inner: Arc::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("mock backend poisoned")
+    }
+
+    /// The device appears, as if it started advertising.
+    pub fn add_device(&self, device: MockDevice) {
+        let mut inner = self.lock();
+        let address = device.address;
+
+        let (subscribers, responders) = inner
+            .devices
+            .remove(&address)
+            .map(|state| (state.subscribers, state.responders))
+            .unwrap_or_default();
+
+        inner.devices.insert(
+            address,
+            DeviceState {
+                services_resolved: device.connected,
+                device,
+                present: true,
+                refuse_connects: 0,
+                calls: Vec::new(),
+                subscribers,
+                responders,
+                notifications: HashMap::new(),
+                open_channels: HashMap::new(),
+            },
+        );
+        inner.emit(AdapterEvent::DeviceAdded(address));
+    }
+
+    /// The device vanishes and BlueZ drops its object.
+    pub fn remove_device(&self, address: Address) {
+        let mut inner = self.lock();
+
+        let Some(state) = inner.devices.get_mut(&address) else {
+            return;
+        };
+        state.present = false;
+        state.subscribers.clear();
+        state.notifications.clear();
+
+        inner.emit(AdapterEvent::DeviceRemoved(address));
+    }
+
+    /// Refuses the next `attempts` connection attempts.
+    pub fn refuse_connections(&self, address: Address, attempts: usize) {
+        if let Some(state) = self.lock().devices.get_mut(&address) {
+            state.refuse_connects = attempts;
+        }
+    }
+
+    /// The link drops without the daemon asking for it.
+    pub fn drop_link(&self, address: Address) {
+        if let Some(state) = self.lock().devices.get_mut(&address)
+            && state.device.connected
+        {
+            state.unlink();
+        }
+    }
+
+    /// Sets whether BlueZ has the GATT database of a connected device, as
+    /// it does not yet right after connecting.
+    pub fn resolve_services(&self, address: Address, resolved: bool) {
+        if let Some(state) = self.lock().devices.get_mut(&address)
+            && state.device.connected
+            && state.services_resolved != resolved
+        {
+            state.services_resolved = resolved;
+            state.emit(DeviceEvent::ServicesResolved(resolved));
+        }
+    }
+
+    /// The device connects on its own, e.g. from another host tool.
+    pub fn establish_link(&self, address: Address) {
+        if let Some(state) = self.lock().devices.get_mut(&address)
+            && !state.device.connected
+        {
+            state.device.connected = true;
+            state.services_resolved = true;
+            state.emit(DeviceEvent::Connected(true));
+            state.emit(DeviceEvent::ServicesResolved(true));
+        }
+    }
+
+    /// The device is heard advertising.
+    pub fn advertise(&self, address: Address, rssi: i16) {
+        if let Some(state) = self.lock().devices.get_mut(&address) {
+            state.device.rssi = Some(rssi);
+            state.emit(DeviceEvent::Rssi(rssi));
+        }
+    }
+
+    /// The device reports a new battery level.
+    pub fn set_battery(&self, address: Address, battery: u8) {
+        if let Some(state) = self.lock().devices.get_mut(&address) {
+            state.device.battery = Some(battery);
+            state.emit(DeviceEvent::Battery(battery));
+        }
+    }
+
+    /// Calls `responder` with every value written to `characteristic`.
+    pub fn respond_to_writes(
+        &self,
+        address: Address,
+        characteristic: Uuid,
+        responder: impl FnMut(&[u8]) -> Vec<(Uuid, Vec<u8>)> + Send + 'static,
+    ) {
+        if let Some(state) = self.lock().devices.get_mut(&address) {
+            state.responders.insert(characteristic, Box::new(responder));
+        }
+    }
+
+    /// The device changes a characteristic and notifies its new value.
+    pub fn notify(&self, address: Address, characteristic: Uuid, value: Vec<u8>) {
+        if let Some(state) = self.lock().devices.get_mut(&address) {
+            state.notify(characteristic, value);
+        }
+    }
+
+    /// Takes the device end of the channel last opened to `psm`. Until it
+    /// is taken, the sender runs out of credits after [`CHANNEL_CREDITS`]
+    /// packets.
+    pub fn accept_channel(&self, address: Address, psm: u16) -> Option<mpsc::Receiver<Vec<u8>>> {
+        self.lock()
+            .devices
+            .get_mut(&address)
+            .and_then(|state| state.open_channels.remove(&psm))
+    }
+
+    /// The current value of a characteristic, including values written to it.
+    pub fn characteristic(&self, address: Address, characteristic: Uuid) -> Option<Vec<u8>> {
+        self.lock()
+            .devices
+            .get(&address)
+            .and_then(|state| state.device.characteristics.get(&characteristic).cloned())
+    }
+
+    pub fn is_connected(&self, address: Address) -> bool {
+        self.lock()
+            .devices
+            .get(&address)
+            .is_some_and(|state| state.device.connected)
+    }
+
+    /// The adapter is switched off or on. Switching it off drops every link,
+    /// and connecting fails and scans are skipped until it is on again.
+    pub fn set_powered(&self, powered: bool) {
+        let mut inner = self.lock();
+        if inner.powered_off != powered {
+            return;
+        }
+        inner.powered_off = !powered;
+
+        if !powered {
+            for state in inner.devices.values_mut() {
+                if state.device.connected {
+                    state.unlink();
+                }
+            }
+        }
+
+        inner.emit(AdapterEvent::Powered {
+            adapter: 0,
+            powered,
+        });
+    }
+
+    /// bluetoothd exits: every link is gone without a word, the event
+    /// streams end and every call fails until [`MockBackend::start_service`].
+    pub fn stop_service(&self) {
+        let mut inner = self.lock();
+        inner.service_stopped = true;
+
+        for state in inner.devices.values_mut() {
+            state.device.connected = false;
+            state.services_resolved = false;
+            state.subscribers.clear();
+            state.notifications.clear();
+            state.open_channels.clear();
+        }
+
+        inner.emit(AdapterEvent::ServiceLost);
+        inner.adapter_subscribers.clear();
+    }
+
+    /// bluetoothd is back, with every device it knew about.
+    pub fn start_service(&self) {
+        self.lock().service_stopped = false;
+    }
+
+    /// Scans started so far.
+    pub fn scans(&self) -> usize {
+        self.lock().scans
+    }
+
+    /// Calls made on the device so far, oldest first.
+    pub fn calls(&self, address: Address) -> Vec<Call> {
+        self.lock()
+            .devices
+            .get(&address)
+            .map(|state| state.calls.clone())
+            .unwrap_or_default()
+    }
+
+    /// Reports every present device as added, after the adapter being off
+    /// if it is, then what happens next.
+    fn subscribe(&self) -> Result<BoxStream<'static, AdapterEvent>> {
+        let mut inner = self.lock();
+        inner.running()?;
+        let (sender, receiver) = unbounded();
+
+        let off = inner.powered_off.then_some(AdapterEvent::Powered {
+            adapter: 0,
+            powered: false,
+        });
+        let known: Vec<AdapterEvent> = off
+            .into_iter()
+            .chain(
+                inner
+                    .devices
+                    .values()
+                    .filter(|state| state.present)
+                    .map(|state| AdapterEvent::DeviceAdded(state.device.address)),
+            )
+            .collect();
+
+        inner.adapter_subscribers.push(sender);
+
+        Ok(stream::iter(known).chain(receiver).boxed())
+    }
+
+    fn with_device<T>(
+        &self,
+        address: Address,
+        f: impl FnOnce(&mut DeviceState) -> Result<T>,
+    ) -> Result<T> {
+        let mut inner = self.lock();
+        inner.running()?;
+
+        match inner.devices.get_mut(&address) {
+            Some(state) if state.present => f(state),
+            _ => Err(error(ErrorKind::DoesNotExist, "device does not exist")),
+        }
+    }
+}
+
+impl BluetoothBackend for MockBackend {
+    type Device = MockDeviceHandle;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn discover(&self) -> Result<BoxStream<'static, AdapterEvent>> {
+        self.subscribe()
+    }
+
+    async fn watch(&self) -> Result<BoxStream<'static, AdapterEvent>> {
+        self.subscribe()
+    }
+
+    async fn scan(&self, duration: Duration) -> Result<()> {
+        {
+            let mut inner = self.lock();
+            inner.running()?;
+            if inner.powered_off {
+                return Ok(());
+            }
+            inner.scans += 1;
+        }
+        tokio::time::sleep(duration).await;
+        Ok(())
+    }
+
+    fn device(&self, address: Address) -> Result<MockDeviceHandle> {
+        self.with_device(address, |_| Ok(()))?;
+
+        Ok(MockDeviceHandle {
+            backend: self.clone(),
+            address,
+        })
+    }
+
+    async fn devices(&self) -> Result<Vec<Address>> {
+        let inner = self.lock();
+        inner.running()?;
+
+        Ok(inner
+            .devices
+            .values()
+            .filter(|state| state.present)
+            .map(|state| state.device.address)
+            .collect())
+    }
+}
+
+#[derive(Clone)]
+pub struct MockDeviceHandle {
+    backend: MockBackend,
+    address: Address,
+}
+
+impl MockDeviceHandle {
+    fn with<T>(&self, f: impl FnOnce(&mut DeviceState) -> Result<T>) -> Result<T> {
+        self.backend.with_device(self.address, f)
+    }
+}
+
+impl BluetoothDevice for MockDeviceHandle {
+    type Channel = MockChannel;
+
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn name(&self) -> Result<Option<String>> {
+        self.with(|state| Ok(state.device.name.clone()))
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>> {
+        self.with(|state| Ok(state.device.rssi))
+    }
+
+    async fn battery(&self) -> Result<Option<u8>> {
+        self.with(|state| Ok(state.device.battery))
+    }
+
+    async fn uuids(&self) -> Result<Option<HashSet<Uuid>>> {
+        self.with(|state| {
+            let mut uuids = state.device.uuids.clone();
+            if state.services_resolved {
+                uuids.extend(state.device.gatt_services.iter().copied());
+            }
+            Ok(Some(uuids))
+        })
+    }
+
+    async fn service_data(&self) -> Result<Option<HashMap<Uuid, Vec<u8>>>> {
+        self.with(|state| Ok(Some(state.device.service_data.clone())))
+    }
+
+    async fn gatt_services(&self) -> Result<Vec<Uuid>> {
+        self.with(|state| {
+            Ok(if state.services_resolved {
+                state.device.gatt_services.clone()
+            } else {
+                Vec::new()
+            })
+        })
+    }
+
+    async fn read_characteristic(&self, _service: Uuid, characteristic: Uuid) -> Result<Vec<u8>> {
+        self.with(|state| {
+            resolved_characteristic(state, characteristic)?;
+            Ok(state.device.characteristics[&characteristic].clone())
+        })
+    }
+
+    async fn write_characteristic(
+        &self,
+        _service: Uuid,
+        characteristic: Uuid,
+        value: Vec<u8>,
+        _op: WriteOp,
+    ) -> Result<()> {
+        self.with(|state| {
+            resolved_characteristic(state, characteristic)?;
+            state
+                .calls
+                .push(Call::WriteCharacteristic(characteristic, value.clone()));
+
+            let notifications = match state.responders.get_mut(&characteristic) {
+                Some(responder) => responder(&value),
+                None => Vec::new(),
+            };
+
+            state.device.characteristics.insert(characteristic, value);
+            for (characteristic, value) in notifications {
+                state.notify(characteristic, value);
+            }
+            Ok(())
+        })
+    }
+
+    async fn notify_characteristic(
+        &self,
+        _service: Uuid,
+        characteristic: Uuid,
+    ) -> Result<BoxStream<'static, Vec<u8>>> {
+        self.with(|state| {
+            resolved_characteristic(state, characteristic)?;
+
+            let (sender, receiver) = unbounded();
+            state
+                .notifications
+                .entry(characteristic)
+                .or_default()
+                .push(sender);
+            Ok(receiver.boxed())
+        })
+    }
+
+    async fn open_l2cap(&self, psm: u16) -> Result<MockChannel> {
+        self.with(|state| {
+            state.calls.push(Call::OpenL2cap(psm));
+
+            if !state.device.connected {
+                return Err(error(ErrorKind::Failed, "not connected"));
+            }
+
+            let Some(info) = state.device.channels.get(&psm).copied() else {
+                return Err(error(
+                    ErrorKind::ConnectionAttemptFailed,
+                    "connection refused",
+                ));
+            };
+
+            let (sender, receiver) = mpsc::channel(CHANNEL_CREDITS);
+            state.open_channels.insert(psm, receiver);
+
+            Ok(MockChannel {
+                device: self.clone(),
+                info,
+                sender,
+            })
+        })
+    }
+
+    async fn is_services_resolved(&self) -> Result<bool> {
+        self.with(|state| Ok(state.services_resolved))
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        self.with(|state| Ok(state.device.connected))
+    }
+
+    async fn is_trusted(&self) -> Result<bool> {
+        self.with(|state| Ok(state.device.trusted))
+    }
+
+    async fn is_paired(&self) -> Result<bool> {
+        self.with(|state| Ok(state.device.paired))
+    }
+
+    async fn set_trusted(&self, trusted: bool) -> Result<()> {
+        self.with(|state| {
+            state.calls.push(Call::SetTrusted(trusted));
+            state.device.trusted = trusted;
+            Ok(())
+        })
+    }
+
+    async fn connect_profile(&self, uuid: Uuid) -> Result<()> {
+        self.backend.lock().ready()?;
+
+        self.with(|state| {
+            state.calls.push(Call::ConnectProfile(uuid));
+
+            if state.refuse_connects > 0 {
+                state.refuse_connects -= 1;
+                return Err(error(
+                    ErrorKind::ConnectionAttemptFailed,
+                    "br-connection-refused",
+                ));
+            }
+
+            if !state.device.connected {
+                state.device.connected = true;
+                state.services_resolved = true;
+                state.emit(DeviceEvent::Connected(true));
+                state.emit(DeviceEvent::ServicesResolved(true));
+            }
+            Ok(())
+        })
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.with(|state| {
+            state.calls.push(Call::Disconnect);
+
+            if !state.device.connected {
+                return Err(error(ErrorKind::Failed, "not connected"));
+            }
+
+            state.unlink();
+            Ok(())
+        })
+    }
+
+    async fn events(&self) -> Result<BoxStream<'static, DeviceEvent>> {
+        self.with(|state| {
+            let (sender, receiver) = unbounded();
+            state.subscribers.push(sender);
+            Ok(receiver.boxed())
+        })
+    }
+}
+
+pub struct MockChannel {
+    device: MockDeviceHandle,
+    info: ChannelInfo,
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl AudioChannel for MockChannel {
+    fn info(&self) -> ChannelInfo {
+        self.info
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<()> {
+        if !self.device.is_connected().await? {
+            return Err(error(ErrorKind::Failed, "not connected"));
+        }
+
+        self.sender
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| error(ErrorKind::Failed, "channel closed"))
+    }
+}
+
+/// Fails like BlueZ when the GATT database is not there or lacks the
+/// characteristic.
+fn resolved_characteristic(state: &DeviceState, characteristic: Uuid) -> Result<()> {
+    if !state.services_resolved {
+        return Err(error(
+            ErrorKind::ServicesUnresolved,
+            "services not resolved",
+        ));
+    }
+
+    if !state.device.characteristics.contains_key(&characteristic) {
+        return Err(error(ErrorKind::NotFound, "characteristic not found"));
+    }
+
+    Ok(())
+}
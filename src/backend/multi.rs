@@ -0,0 +1,366 @@
+/*
+
+Several adapters behind one backend
+
+Devices are known by address across all adapters, and each belongs to one
+of them: the adapter it is bonded with, or else the first one, in the order
+given, that sees it. Everything done with a device goes through its adapter,
+so a connection is never attempted on a radio the device is not bonded
+with. A device that turns out to belong to another adapter is reported as
+removed and added again, and so is one its adapter loses while another
+still knows it.
+
+Discovery, watching and scans run on every adapter, and the events end as
+soon as they end on any of them. An adapter switched off or on is reported
+by its index, so that only its own devices are let go of and taken up
+again.
+
+*/
+
+use super::{AdapterEvent, BluetoothBackend, BluetoothDevice};
+use bluer::{Address, Error, ErrorKind, Result};
+use futures::{
+    StreamExt, future,
+    stream::{self, BoxStream},
+};
+use log::{debug, info};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// The adapter a device belongs to, by index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Owner {
+    adapter: usize,
+    bonded: bool,
+}
+
+impl Owner {
+    /// Bonded adapters first, then the order of preference.
+    fn outranks(&self, other: &Owner) -> bool {
+        (self.bonded, other.adapter) > (other.bonded, self.adapter)
+    }
+}
+
+/// What seeing a device on an adapter changed.
+enum Claim {
+    /// The device belongs to this adapter now, and did not belong to any.
+    New,
+    /// It already belonged to this adapter.
+    Kept,
+    /// It moved over from the adapter with this index.
+    Moved(usize),
+    /// It belongs to another adapter.
+    Elsewhere,
+}
+
+#[derive(Clone)]
+pub struct MultiBackend<B> {
+    backends: Arc<[B]>,
+    name: Arc<str>,
+    owners: Arc<Mutex<HashMap<Address, Owner>>>,
+}
+
+impl<B: BluetoothBackend> MultiBackend<B> {
+    /// `backends` in order of preference. Panics if there are none.
+    pub fn new(backends: Vec<B>) -> Self {
+        assert!(!backends.is_empty(), "at least one adapter is needed");
+
+        let name = backends
+            .iter()
+            .map(|backend| backend.name())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Self {
+            backends: backends.into(),
+            name: name.into(),
+            owners: Arc::default(),
+        }
+    }
+
+    pub fn backends(&self) -> &[B] {
+        &self.backends
+    }
+
+    /// The adapter a device belongs to, once it was seen.
+    pub fn adapter_of(&self, address: Address) -> Option<&B> {
+        let owner = self.lock().get(&address).copied()?;
+        Some(&self.backends[owner.adapter])
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Address, Owner>> {
+        self.owners.lock().expect("adapter owners poisoned")
+    }
+
+    /// Records that adapter `index` sees the device.
+    async fn claim(&self, index: usize, address: Address) -> Claim {
+        let backend = &self.backends[index];
+        let bonded = match backend.device(address) {
+            Ok(device) => device.is_paired().await.unwrap_or(false),
+            Err(_) => return Claim::Elsewhere,
+        };
+        let candidate = Owner {
+            adapter: index,
+            bonded,
+        };
+
+        let mut owners = self.lock();
+        let claim = match owners.get(&address) {
+            None => Claim::New,
+            Some(owner) if owner.adapter == index => Claim::Kept,
+            Some(owner) if candidate.outranks(owner) => Claim::Moved(owner.adapter),
+            Some(_) => return Claim::Elsewhere,
+        };
+        owners.insert(address, candidate);
+        drop(owners);
+
+        match claim {
+            Claim::New if self.backends.len() > 1 => {
+                debug!(
+                    "{} belongs to {}{}.",
+                    address,
+                    backend.name(),
+                    if bonded { ", bonded" } else { "" }
+                );
+            }
+            Claim::Moved(from) => {
+                info!(
+                    "{} belongs to {} instead of {}{}.",
+                    address,
+                    backend.name(),
+                    self.backends[from].name(),
+                    if bonded { ", it is bonded there" } else { "" }
+                );
+            }
+            _ => {}
+        }
+        claim
+    }
+
+    /// Turns an event of adapter `index` into the events for the devices
+    /// as a whole.
+    async fn route(&self, index: usize, event: AdapterEvent) -> Vec<AdapterEvent> {
+        match event {
+            AdapterEvent::DeviceAdded(address) => match self.claim(index, address).await {
+                Claim::New | Claim::Kept => vec![event],
+                Claim::Moved(_) => vec![AdapterEvent::DeviceRemoved(address), event],
+                Claim::Elsewhere => Vec::new(),
+            },
+            AdapterEvent::DeviceRemoved(address) => {
+                {
+                    let mut owners = self.lock();
+                    if owners
+                        .get(&address)
+                        .is_none_or(|owner| owner.adapter != index)
+                    {
+                        return Vec::new();
+                    }
+                    owners.remove(&address);
+                }
+
+                let mut events = vec![event];
+                if let Some(other) = self.still_known(index, address).await {
+                    self.claim(other, address).await;
+                    events.push(AdapterEvent::DeviceAdded(address));
+                }
+                events
+            }
+            AdapterEvent::Powered { powered, .. } => vec![AdapterEvent::Powered {
+                adapter: index,
+                powered,
+            }],
+            AdapterEvent::ServiceLost => vec![event],
+        }
+    }
+
+    /// Routes the events of every adapter, the stream of adapter `index`
+    /// at that index, until any of them ends.
+    fn merge(
+        &self,
+        streams: Vec<BoxStream<'static, AdapterEvent>>,
+    ) -> BoxStream<'static, AdapterEvent> {
+        let streams = streams.into_iter().enumerate().map(|(index, events)| {
+            events
+                .map(move |event| Some((index, event)))
+                .chain(stream::once(future::ready(None)))
+        });
+
+        let this = self.clone();
+        stream::select_all(streams)
+            .take_while(|event| future::ready(event.is_some()))
+            .filter_map(future::ready)
+            .then(move |(index, event)| {
+                let this = this.clone();
+                async move { this.route(index, event).await }
+            })
+            .flat_map(stream::iter)
+            .boxed()
+    }
+
+    /// Another adapter that still knows the device, bonded ones first.
+    async fn still_known(&self, except: usize, address: Address) -> Option<usize> {
+        let mut found: Option<Owner> = None;
+
+        for (index, backend) in self.backends.iter().enumerate() {
+            if index == except
+                || !backend
+                    .devices()
+                    .await
+                    .is_ok_and(|devices| devices.contains(&address))
+            {
+                continue;
+            }
+
+            let bonded = match backend.device(address) {
+                Ok(device) => device.is_paired().await.unwrap_or(false),
+                Err(_) => continue,
+            };
+            let candidate = Owner {
+                adapter: index,
+                bonded,
+            };
+            if found.is_none_or(|found| candidate.outranks(&found)) {
+                found = Some(candidate);
+            }
+        }
+
+        found.map(|owner| owner.adapter)
+    }
+}
+
+impl<B: BluetoothBackend> BluetoothBackend for MultiBackend<B> {
+    type Device = B::Device;
+
+    /// The names of all adapters.
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn discover(&self) -> Result<BoxStream<'static, AdapterEvent>> {
+        let mut streams = Vec::new();
+        for backend in self.backends.iter() {
+            streams.push(backend.discover().await?);
+        }
+        Ok(self.merge(streams))
+    }
+
+    async fn watch(&self) -> Result<BoxStream<'static, AdapterEvent>> {
+        let mut streams = Vec::new();
+        for backend in self.backends.iter() {
+            streams.push(backend.watch().await?);
+        }
+        Ok(self.merge(streams))
+    }
+
+    /// Scans on every adapter at once; fails if any of them could not.
+    async fn scan(&self, duration: Duration) -> Result<()> {
+        future::join_all(self.backends.iter().map(|backend| backend.scan(duration)))
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Only devices seen through `devices` or the events, as until then it
+    /// is not known which adapter they belong to.
+    fn device(&self, address: Address) -> Result<B::Device> {
+        let owner = self.lock().get(&address).copied();
+
+        match owner {
+            Some(owner) => self.backends[owner.adapter].device(address),
+            None => Err(Error {
+                kind: ErrorKind::DoesNotExist,
+                message: format!("{address} is not known to any adapter"),
+            }),
+        }
+    }
+
+    async fn devices(&self) -> Result<Vec<Address>> {
+        let mut all = BTreeSet::new();
+
+        for (index, backend) in self.backends.iter().enumerate() {
+            for address in backend.devices().await? {
+                self.claim(index, address).await;
+                all.insert(address);
+            }
+        }
+
+        Ok(all.into_iter().collect())
+    }
+
+    fn adapter_index(&self, address: Address) -> usize {
+        self.lock().get(&address).map_or(0, |owner| owner.adapter)
+    }
+
+    fn adapter_name(&self, adapter: usize) -> &str {
+        self.backends[adapter].name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::{MockBackend, MockDevice};
+
+    const FIRST: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    const SECOND: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x77]);
+
+    /// Two adapters, each knowing one device.
+    fn adapters() -> (MockBackend, MockBackend, MultiBackend<MockBackend>) {
+        let first = MockBackend::named("hci0");
+        first.add_device(MockDevice {
+            address: FIRST,
+            ..MockDevice::default()
+        });
+        let second = MockBackend::named("hci1");
+        second.add_device(MockDevice {
+            address: SECOND,
+            ..MockDevice::default()
+        });
+        let multi = MultiBackend::new(vec![first.clone(), second.clone()]);
+        (first, second, multi)
+    }
+
+    #[tokio::test]
+    async fn devices_are_only_known_once_seen() {
+        let (_, _, multi) = adapters();
+
+        assert!(matches!(
+            multi.device(SECOND),
+            Err(Error {
+                kind: ErrorKind::DoesNotExist,
+                ..
+            })
+        ));
+
+        assert_eq!(multi.devices().await.unwrap(), [FIRST, SECOND]);
+        assert_eq!(multi.adapter_index(FIRST), 0);
+        assert_eq!(multi.adapter_index(SECOND), 1);
+        assert_eq!(multi.adapter_of(SECOND).unwrap().name(), "hci1");
+        assert_eq!(multi.device(SECOND).unwrap().address(), SECOND);
+    }
+
+    #[tokio::test]
+    async fn power_is_reported_for_its_adapter() {
+        let (_, second, multi) = adapters();
+        let mut events = multi.watch().await.unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                events.next().await,
+                Some(AdapterEvent::DeviceAdded(_))
+            ));
+        }
+
+        second.set_powered(false);
+        assert_eq!(
+            events.next().await,
+            Some(AdapterEvent::Powered {
+                adapter: 1,
+                powered: false
+            })
+        );
+        assert_eq!(multi.adapter_name(1), "hci1");
+    }
+}
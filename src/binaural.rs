@@ -0,0 +1,200 @@
+/*
+
+Binaural sets
+
+Both hearing aids of a binaural set share a HiSyncId. Advertisements only
+carry its first four bytes; the full id and the side are read from
+ReadOnlyProperties once a device has been connected. Devices are grouped
+by whatever is known so far.
+
+Before connecting or disconnecting, a member announces the action and
+waits for the other side to announce the same one (or to already be in
+the wanted state), so that both sides change together.
+
+*/
+
+use crate::{
+    asha::{HiSyncId, ReadOnlyProperties, Side},
+    state::Action,
+};
+use bluer::Address;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::watch;
+
+/// What is known about a set before joining it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SetKey {
+    pub truncated: [u8; 4],
+    pub hisyncid: Option<HiSyncId>,
+}
+
+impl SetKey {
+    pub fn new(hisyncid: HiSyncId) -> Self {
+        let mut truncated = [0; 4];
+        truncated.copy_from_slice(&hisyncid.0[..4]);
+
+        Self {
+            truncated,
+            hisyncid: Some(hisyncid),
+        }
+    }
+
+    fn matches(&self, other: &SetKey) -> bool {
+        self.truncated == other.truncated
+            && match (self.hisyncid, other.hisyncid) {
+                (Some(ours), Some(theirs)) => ours == theirs,
+                _ => true,
+            }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Member {
+    pub side: Option<Side>,
+    /// Whether the device expects a partner.
+    pub binaural: bool,
+    pub connected: bool,
+    /// The action the member is about to take or is taking.
+    pub pending: Option<Action>,
+}
+
+pub type Members = BTreeMap<Address, Member>;
+
+struct Set {
+    key: SetKey,
+    members: watch::Sender<Members>,
+}
+
+#[derive(Default)]
+struct Inner {
+    sets: Vec<Set>,
+    /// ReadOnlyProperties read so far, kept while the daemon runs.
+    properties: HashMap<Address, ReadOnlyProperties>,
+}
+
+/// All binaural sets the supervisor knows about.
+#[derive(Clone, Default)]
+pub struct Sets {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Sets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("binaural sets poisoned")
+    }
+
+    pub fn properties(&self, address: Address) -> Option<ReadOnlyProperties> {
+        self.lock().properties.get(&address).copied()
+    }
+
+    pub fn remember(&self, address: Address, properties: ReadOnlyProperties) {
+        self.lock().properties.insert(address, properties);
+    }
+
+    /// Adds the device to the set matching `key`, creating it if needed.
+    pub fn join(&self, address: Address, key: SetKey, member: Member) -> Membership {
+        let mut inner = self.lock();
+
+        let index = match inner.sets.iter().position(|set| set.key.matches(&key)) {
+            Some(index) => index,
+            None => {
+                inner.sets.push(Set {
+                    key,
+                    members: watch::Sender::new(Members::new()),
+                });
+                inner.sets.len() - 1
+            }
+        };
+
+        let set = &mut inner.sets[index];
+        if set.key.hisyncid.is_none() {
+            set.key.hisyncid = key.hisyncid;
+        }
+        set.members.send_modify(|members| {
+            members.insert(address, member);
+        });
+
+        Membership {
+            sets: self.clone(),
+            address,
+            key: set.key,
+            members: set.members.clone(),
+            receiver: set.members.subscribe(),
+        }
+    }
+
+    fn leave(&self, address: Address, members: &watch::Sender<Members>) {
+        members.send_modify(|members| {
+            members.remove(&address);
+        });
+
+        self.lock()
+            .sets
+            .retain(|set| !set.members.borrow().is_empty());
+    }
+}
+
+/// A device's place in a set. Leaves the set when dropped.
+pub struct Membership {
+    sets: Sets,
+    address: Address,
+    key: SetKey,
+    members: watch::Sender<Members>,
+    receiver: watch::Receiver<Members>,
+}
+
+impl Membership {
+    pub fn key(&self) -> SetKey {
+        self.key
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut Member)) {
+        self.members.send_if_modified(|members| {
+            let Some(member) = members.get_mut(&self.address) else {
+                return false;
+            };
+            let before = member.clone();
+            f(member);
+            *member != before
+        });
+    }
+
+    /// Whether the other side is ready for `action` too. A binaural device
+    /// never connects on its own unless the caller gives up waiting.
+    pub fn ready(&mut self, action: Action) -> bool {
+        let members = self.receiver.borrow_and_update();
+        let binaural = members.get(&self.address).is_some_and(|me| me.binaural);
+        let mut partners = members
+            .iter()
+            .filter(|(address, _)| **address != self.address)
+            .peekable();
+
+        if action == Action::Connect && binaural && partners.peek().is_none() {
+            return false;
+        }
+
+        partners.all(|(_, partner)| {
+            partner.pending == Some(action) || partner.connected == (action == Action::Connect)
+        })
+    }
+
+    /// Resolves once any member of the set changed.
+    pub async fn changed(&mut self) {
+        if self.receiver.changed().await.is_err() {
+            std::future::pending().await
+        }
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.sets.leave(self.address, &self.members);
+    }
+}
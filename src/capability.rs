@@ -0,0 +1,315 @@
+/*
+
+Capability gate deciding whether a configured device is a hearing aid
+
+A device qualifies if it advertises the ASHA service UUID (0xFDF0) or the
+Hearing Access Service (0x1854), or exposes either GATT service once its
+services are resolved. ASHA wins when a device has both, as that is what
+the rest of the daemon knows best. Devices that get this wrong can be
+forced through as ASHA devices with the `force_asha` option.
+
+*/
+
+use std::fmt;
+
+/// The protocol a hearing aid is managed through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Asha,
+    /// LE Audio with the Hearing Access Service.
+    Has,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Asha => write!(f, "ASHA"),
+            Protocol::Has => write!(f, "HAS"),
+        }
+    }
+}
+
+/// Which hearing aid services were seen in one place.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Services {
+    pub asha: bool,
+    pub has: bool,
+}
+
+impl Services {
+    pub fn protocol(self) -> Option<Protocol> {
+        if self.asha {
+            Some(Protocol::Asha)
+        } else if self.has {
+            Some(Protocol::Has)
+        } else {
+            None
+        }
+    }
+}
+
+/// What has been observed about a device's hearing aid support.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Evidence {
+    /// Services in the advertised UUIDs or service data.
+    pub advertised: Services,
+    /// Primary services present in the device's GATT database.
+    pub gatt_services: Services,
+    /// BlueZ reports `ServicesResolved`.
+    pub services_resolved: bool,
+}
+
+/// Why a device passed the gate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    Advertised(Protocol),
+    GattService(Protocol),
+    Forced,
+}
+
+impl Capability {
+    pub fn protocol(self) -> Protocol {
+        match self {
+            Capability::Advertised(protocol) | Capability::GattService(protocol) => protocol,
+            Capability::Forced => Protocol::Asha,
+        }
+    }
+}
+
+/// Why a device was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Nothing advertised and the GATT database is not available yet.
+    ServicesUnresolved,
+    /// Nothing advertised and the resolved GATT database has neither service.
+    NotHearingAid,
+}
+
+pub fn check(evidence: &Evidence, force: bool) -> Result<Capability, Rejection> {
+    if force {
+        Ok(Capability::Forced)
+    } else if let Some(protocol) = evidence.advertised.protocol() {
+        Ok(Capability::Advertised(protocol))
+    } else if let Some(protocol) = evidence.gatt_services.protocol() {
+        Ok(Capability::GattService(protocol))
+    } else if evidence.services_resolved {
+        Err(Rejection::NotHearingAid)
+    } else {
+        Err(Rejection::ServicesUnresolved)
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Advertised(protocol) => write!(f, "advertises the {} service", protocol),
+            Capability::GattService(protocol) => {
+                write!(f, "exposes the {} GATT service", protocol)
+            }
+            Capability::Forced => write!(f, "ASHA check overridden by `force_asha`"),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::ServicesUnresolved => write!(
+                f,
+                "advertises neither ASHA nor HAS and its GATT services are not resolved"
+            ),
+            Rejection::NotHearingAid => write!(
+                f,
+                "neither advertises ASHA or HAS nor exposes them over GATT"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asha::ASHA_SERVICE_U16,
+        backend::{
+            BluetoothBackend,
+            mock::{MockBackend, MockDevice},
+        },
+        device,
+        has::HAS_SERVICE_U16,
+    };
+    use bluer::{Address, Uuid, UuidExt};
+    use std::collections::{HashMap, HashSet};
+
+    const AID: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+
+    fn uuids(services: &[u16]) -> HashSet<Uuid> {
+        services
+            .iter()
+            .map(|&service| Uuid::from_u16(service))
+            .collect()
+    }
+
+    /// Runs the gate on the mock device as the daemon does.
+    async fn check_device(backend: &MockBackend, force: bool) -> Result<Capability, Rejection> {
+        let device = backend.device(AID).unwrap();
+        let advertisement = device::advertisement(&device).await;
+        device::check_capability(&device, &advertisement, force).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forced_without_evidence() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice {
+            address: AID,
+            ..MockDevice::default()
+        });
+
+        assert_eq!(check_device(&backend, true).await, Ok(Capability::Forced));
+        assert_eq!(Capability::Forced.protocol(), Protocol::Asha);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn advertised_services() {
+        let backend = MockBackend::new();
+
+        // ASHA service data alone is enough.
+        backend.add_device(MockDevice {
+            address: AID,
+            service_data: HashMap::from([(Uuid::from_u16(ASHA_SERVICE_U16), vec![0; 9])]),
+            ..MockDevice::default()
+        });
+        assert_eq!(
+            check_device(&backend, false).await,
+            Ok(Capability::Advertised(Protocol::Asha))
+        );
+
+        // A device with HAS only.
+        backend.add_device(MockDevice {
+            address: AID,
+            uuids: uuids(&[HAS_SERVICE_U16]),
+            ..MockDevice::default()
+        });
+        assert_eq!(
+            check_device(&backend, false).await,
+            Ok(Capability::Advertised(Protocol::Has))
+        );
+
+        // ASHA wins over HAS.
+        backend.add_device(MockDevice {
+            address: AID,
+            uuids: uuids(&[HAS_SERVICE_U16, ASHA_SERVICE_U16]),
+            ..MockDevice::default()
+        });
+        assert_eq!(
+            check_device(&backend, false).await,
+            Ok(Capability::Advertised(Protocol::Asha))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gatt_services_once_resolved() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice {
+            address: AID,
+            connected: true,
+            gatt_services: vec![Uuid::from_u16(HAS_SERVICE_U16)],
+            ..MockDevice::default()
+        });
+        backend.resolve_services(AID, false);
+
+        // Connected but still resolving: the gate waits for the database.
+        let checking = tokio::spawn({
+            let backend = backend.clone();
+            async move { check_device(&backend, false).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        assert!(!checking.is_finished());
+
+        backend.resolve_services(AID, true);
+        assert_eq!(
+            checking.await.unwrap(),
+            Ok(Capability::GattService(Protocol::Has))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejections() {
+        let backend = MockBackend::new();
+
+        // Not connected, so there is no GATT database to look at.
+        backend.add_device(MockDevice {
+            address: AID,
+            uuids: uuids(&[0x180f]),
+            ..MockDevice::default()
+        });
+        assert_eq!(
+            check_device(&backend, false).await,
+            Err(Rejection::ServicesUnresolved)
+        );
+
+        // Connected and resolved, without either service.
+        backend.add_device(MockDevice {
+            address: AID,
+            connected: true,
+            gatt_services: vec![Uuid::from_u16(0x180f)],
+            ..MockDevice::default()
+        });
+        assert_eq!(
+            check_device(&backend, false).await,
+            Err(Rejection::NotHearingAid)
+        );
+
+        // Connected, but the database never arrives.
+        backend.resolve_services(AID, false);
+        assert_eq!(
+            check_device(&backend, false).await,
+            Err(Rejection::ServicesUnresolved)
+        );
+    }
+
+    #[test]
+    fn order_of_evidence() {
+        let asha = Services {
+            asha: true,
+            has: false,
+        };
+        let has = Services {
+            asha: false,
+            has: true,
+        };
+        let evidence = |advertised, gatt_services, services_resolved| Evidence {
+            advertised,
+            gatt_services,
+            services_resolved,
+        };
+
+        // Forcing beats everything, advertising beats the GATT database.
+        assert_eq!(
+            check(&evidence(has, has, true), true),
+            Ok(Capability::Forced)
+        );
+        assert_eq!(
+            check(&evidence(has, asha, true), false),
+            Ok(Capability::Advertised(Protocol::Has))
+        );
+        assert_eq!(
+            check(&evidence(Services::default(), has, false), false),
+            Ok(Capability::GattService(Protocol::Has))
+        );
+        assert_eq!(
+            check(
+                &evidence(Services::default(), Services::default(), true),
+                false
+            ),
+            Err(Rejection::NotHearingAid)
+        );
+        assert_eq!(
+            check(
+                &evidence(Services::default(), Services::default(), false),
+                false
+            ),
+            Err(Rejection::ServicesUnresolved)
+        );
+    }
+}
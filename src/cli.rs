@@ -0,0 +1,600 @@
+/*
+
+Command line parsing
+
+`reasha [--config PATH] [COMMAND]`. Without a command the daemon runs, so
+existing service files keep working.
+
+*/
+
+use crate::{asha::Update, config, logging, state::Mode, stream::PcmFormat};
+use log::LevelFilter;
+use std::{fmt, path::PathBuf, time::Duration};
+
+pub const USAGE: &str = "\
+Usage: reasha [OPTIONS] [COMMAND]
+
+Commands:
+  run                 Keep managed devices connected while audio plays (default)
+  scan [DURATION]     List nearby ASHA devices, scanning for 10s by default
+  status              Show managed devices and their state
+  info <DEVICE>       Show the ASHA properties of a connected device
+  connect <DEVICE>    Connect a device now
+  disconnect <DEVICE> Disconnect a device now
+  trust <DEVICE>      Mark a device as trusted
+  untrust <DEVICE>    Remove the trust mark from a device
+  volume <DEVICE> <VOLUME>
+                      Set the stream volume in dB, -128 (muted) to 0
+  asha start <DEVICE> [VOLUME]
+                      Send Start to AudioControlPoint, VOLUME in dB (-128 to 0)
+  asha stop <DEVICE>  Send Stop to AudioControlPoint
+  asha status <DEVICE> <UPDATE>
+                      Send Status: other-connected, other-disconnected or
+                      parameters-updated
+  asha watch <DEVICE> [DURATION]
+                      Print AudioStatusPoint notifications, forever by default
+  asha probe <DEVICE> Open the L2CAP audio channel and show its MTUs
+  stream <DEVICE> [SOURCE] [rate=HZ] [channels=N] [volume=DB]
+                      Send 16-bit PCM from a file, pipe or stdin (`-`, the
+                      default) to a device and the other side of its set;
+                      raw PCM is 16000 Hz mono unless given, WAV files carry
+                      their own format
+  preset list <DEVICE>
+                      List the presets of a connected HAS device
+  preset set <DEVICE> <PRESET>
+                      Select a preset by index or name
+  mode <DEVICE> [MODE]
+                      Show or set when the daemon connects a device:
+                      on-playback, always, manual or never; kept across
+                      restarts
+  config check        Validate the configuration file
+  help                Show this message
+
+DEVICE is a Bluetooth address or a device name (`*` and `?` allowed).
+
+Options:
+  -c, --config <PATH> Use this configuration file instead of searching for one
+  -v, --verbose       Log more; twice for everything
+  -q, --quiet         Log only warnings and errors; twice for errors only
+      --log-level <LEVEL>
+                      Log level: off, error, warn, info, debug or trace
+      --log-target <TARGET>
+                      Where logs go: auto, stderr or journal
+  -h, --help          Show this message
+  -V, --version       Show the version";
+
+const DEFAULT_SCAN_DURATION: Duration = Duration::from_secs(10);
+
+/// Half way between muted and full volume.
+const DEFAULT_START_VOLUME: i8 = -64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cli {
+    /// Explicit configuration file, overriding the search paths.
+    pub config: Option<PathBuf>,
+    /// Overrides the level from the configuration file.
+    pub log_level: Option<LevelFilter>,
+    /// Overrides the target from the configuration file.
+    pub log_target: Option<logging::Target>,
+    pub command: Command,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Scan {
+        duration: Duration,
+    },
+    Status,
+    Info(String),
+    Connect(String),
+    Disconnect(String),
+    Trust(String),
+    Untrust(String),
+    Volume {
+        device: String,
+        volume: i8,
+    },
+    Asha {
+        device: String,
+        command: AshaCommand,
+    },
+    Stream {
+        device: String,
+        /// Stdin if not given.
+        source: Option<PathBuf>,
+        /// Layout of raw input.
+        format: PcmFormat,
+        volume: i8,
+    },
+    Preset {
+        device: String,
+        command: PresetAction,
+    },
+    Mode {
+        device: String,
+        /// Shows the current mode if not given.
+        mode: Option<Mode>,
+    },
+    ConfigCheck,
+    Help,
+    Version,
+}
+
+/// Commands for the ASHA control plane of a connected device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AshaCommand {
+    Start { volume: i8 },
+    Stop,
+    Status(Update),
+    Watch { duration: Option<Duration> },
+    Probe,
+}
+
+/// Commands for the presets of a HAS device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresetAction {
+    List,
+    /// A preset index or name.
+    Set(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+fn usage_error(message: impl Into<String>) -> UsageError {
+    UsageError(message.into())
+}
+
+/// Parses the arguments following the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, UsageError> {
+    let mut cli = Cli {
+        config: None,
+        log_level: None,
+        log_target: None,
+        command: Command::Run,
+    };
+    let mut help = false;
+    let mut verbosity = 0i8;
+    let mut words = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // `--option=value` is the same as `--option value`.
+        let (option, mut inline) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option, Some(value.to_owned())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| usage_error(format!("`{option}` needs a value")))
+        };
+
+        match option {
+            "-h" | "--help" => help = true,
+            "-V" | "--version" => {
+                cli.command = Command::Version;
+                return Ok(cli);
+            }
+            "-c" | "--config" => cli.config = Some(PathBuf::from(value()?)),
+            "--verbose" => verbosity = verbosity.saturating_add(1),
+            "--quiet" => verbosity = verbosity.saturating_sub(1),
+            // `-v`, `-vv`, `-q`, ...
+            _ if repeated(option, 'v').is_some() || repeated(option, 'q').is_some() => {
+                verbosity = verbosity
+                    .saturating_add(repeated(option, 'v').unwrap_or(0))
+                    .saturating_sub(repeated(option, 'q').unwrap_or(0));
+            }
+            "--log-level" => {
+                cli.log_level = Some(logging::parse_level(&value()?).map_err(UsageError)?)
+            }
+            "--log-target" => cli.log_target = Some(value()?.parse().map_err(UsageError)?),
+            "--" => words.extend(args.by_ref()),
+            // Negative numbers such as volumes are arguments.
+            _ if option.parse::<f64>().is_ok() => words.push(arg),
+            _ if option.starts_with('-') && option.len() > 1 => {
+                return Err(usage_error(format!("unknown option `{option}`")));
+            }
+            _ => words.push(arg),
+        }
+    }
+
+    if cli.log_level.is_none() && verbosity != 0 {
+        cli.log_level = Some(match verbosity {
+            ..=-2 => LevelFilter::Error,
+            -1 => LevelFilter::Warn,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        });
+    }
+
+    if help || words.first().is_some_and(|word| word == "help") {
+        cli.command = Command::Help;
+        return Ok(cli);
+    }
+
+    let mut words = words.into_iter();
+    cli.command = match words.next().as_deref() {
+        None | Some("run") => Command::Run,
+        Some("scan") => Command::Scan {
+            duration: match words.next() {
+                Some(text) => config::parse_duration(&text)
+                    .ok_or_else(|| usage_error(format!("`{text}` is not a duration")))?,
+                None => DEFAULT_SCAN_DURATION,
+            },
+        },
+        Some("status") => Command::Status,
+        Some("info") => Command::Info(device_argument("info", words.next())?),
+        Some("connect") => Command::Connect(device_argument("connect", words.next())?),
+        Some("disconnect") => Command::Disconnect(device_argument("disconnect", words.next())?),
+        Some("trust") => Command::Trust(device_argument("trust", words.next())?),
+        Some("untrust") => Command::Untrust(device_argument("untrust", words.next())?),
+        Some("volume") => Command::Volume {
+            device: device_argument("volume", words.next())?,
+            volume: match words.next() {
+                Some(text) => volume_argument(&text)?,
+                None => return Err(usage_error("`volume` needs a volume in dB")),
+            },
+        },
+        Some("asha") => asha_command(&mut words)?,
+        Some("stream") => stream_command(&mut words)?,
+        Some("preset") => preset_command(&mut words)?,
+        Some("mode") => Command::Mode {
+            device: device_argument("mode", words.next())?,
+            mode: words
+                .next()
+                .map(|mode| mode.parse().map_err(UsageError))
+                .transpose()?,
+        },
+        Some("config") => match words.next().as_deref() {
+            Some("check") => Command::ConfigCheck,
+            Some(other) => return Err(usage_error(format!("unknown config command `{other}`"))),
+            None => return Err(usage_error("`config` needs a command: check")),
+        },
+        Some(other) => return Err(usage_error(format!("unknown command `{other}`"))),
+    };
+
+    if let Some(extra) = words.next() {
+        return Err(usage_error(format!("unexpected argument `{extra}`")));
+    }
+
+    Ok(cli)
+}
+
+/// How many times `flag` is given in `option` if it is `-f`, `-ff`, ...
+fn repeated(option: &str, flag: char) -> Option<i8> {
+    let flags = option.strip_prefix('-')?;
+    (!flags.is_empty() && flags.chars().all(|c| c == flag))
+        .then(|| i8::try_from(flags.len()).unwrap_or(i8::MAX))
+}
+
+fn device_argument(command: &str, argument: Option<String>) -> Result<String, UsageError> {
+    argument.ok_or_else(|| usage_error(format!("`{command}` needs a device address or name")))
+}
+
+fn volume_argument(text: &str) -> Result<i8, UsageError> {
+    text.parse()
+        .ok()
+        .filter(|volume| *volume <= 0)
+        .ok_or_else(|| usage_error(format!("`{text}` is not a volume from -128 to 0")))
+}
+
+fn asha_command(words: &mut impl Iterator<Item = String>) -> Result<Command, UsageError> {
+    let Some(name) = words.next() else {
+        return Err(usage_error(
+            "`asha` needs a command: start, stop, status, watch or probe",
+        ));
+    };
+    if !["start", "stop", "status", "watch", "probe"].contains(&name.as_str()) {
+        return Err(usage_error(format!("unknown asha command `{name}`")));
+    }
+    let device = device_argument(&format!("asha {name}"), words.next())?;
+
+    let command = match name.as_str() {
+        "start" => AshaCommand::Start {
+            volume: match words.next() {
+                Some(text) => volume_argument(&text)?,
+                None => DEFAULT_START_VOLUME,
+            },
+        },
+        "stop" => AshaCommand::Stop,
+        "status" => AshaCommand::Status(match words.next().as_deref() {
+            Some("other-connected") => Update::OtherSideConnected,
+            Some("other-disconnected") => Update::OtherSideDisconnected,
+            Some("parameters-updated") => Update::ParametersUpdated,
+            Some(other) => return Err(usage_error(format!("unknown update `{other}`"))),
+            None => {
+                return Err(usage_error(
+                    "`asha status` needs an update: other-connected, other-disconnected or parameters-updated",
+                ));
+            }
+        }),
+        "watch" => AshaCommand::Watch {
+            duration: match words.next() {
+                Some(text) => Some(
+                    config::parse_duration(&text)
+                        .ok_or_else(|| usage_error(format!("`{text}` is not a duration")))?,
+                ),
+                None => None,
+            },
+        },
+        "probe" => AshaCommand::Probe,
+        _ => unreachable!(),
+    };
+
+    Ok(Command::Asha { device, command })
+}
+
+fn preset_command(words: &mut impl Iterator<Item = String>) -> Result<Command, UsageError> {
+    let Some(name) = words.next() else {
+        return Err(usage_error("`preset` needs a command: list or set"));
+    };
+    if name != "list" && name != "set" {
+        return Err(usage_error(format!("unknown preset command `{name}`")));
+    }
+    let device = device_argument(&format!("preset {name}"), words.next())?;
+
+    let command = match name.as_str() {
+        "list" => PresetAction::List,
+        _ => PresetAction::Set(
+            words
+                .next()
+                .ok_or_else(|| usage_error("`preset set` needs a preset index or name"))?,
+        ),
+    };
+
+    Ok(Command::Preset { device, command })
+}
+
+fn stream_command(words: &mut impl Iterator<Item = String>) -> Result<Command, UsageError> {
+    let device = device_argument("stream", words.next())?;
+    let mut source = None;
+    let mut format = PcmFormat::default();
+    let mut volume = DEFAULT_START_VOLUME;
+
+    for word in words {
+        // Anything else is a path, even with a `=` in it.
+        let setting = word
+            .split_once('=')
+            .filter(|(key, _)| ["rate", "channels", "volume"].contains(key));
+        let Some((key, value)) = setting else {
+            if source.is_some() {
+                return Err(usage_error(format!("unexpected argument `{word}`")));
+            }
+            source = Some(word);
+            continue;
+        };
+
+        match key {
+            "rate" => {
+                format.rate = value
+                    .parse()
+                    .ok()
+                    .filter(|rate| *rate > 0)
+                    .ok_or_else(|| usage_error(format!("`{value}` is not a sample rate")))?
+            }
+            "channels" => {
+                format.channels = value
+                    .parse()
+                    .ok()
+                    .filter(|channels| *channels > 0)
+                    .ok_or_else(|| usage_error(format!("`{value}` is not a channel count")))?
+            }
+            "volume" => volume = volume_argument(value)?,
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(Command::Stream {
+        device,
+        source: source.filter(|source| source != "-").map(PathBuf::from),
+        format,
+        volume,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Cli, UsageError> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse_args(args).unwrap().command
+    }
+
+    fn error(args: &[&str]) -> String {
+        parse_args(args)
+            .expect_err("arguments should be rejected")
+            .0
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(command(&[]), Command::Run);
+        assert_eq!(command(&["run"]), Command::Run);
+        assert_eq!(
+            command(&["scan"]),
+            Command::Scan {
+                duration: DEFAULT_SCAN_DURATION
+            }
+        );
+        assert_eq!(
+            command(&["scan", "500ms"]),
+            Command::Scan {
+                duration: Duration::from_millis(500)
+            }
+        );
+        assert_eq!(
+            command(&["connect", "Aid*"]),
+            Command::Connect("Aid*".into())
+        );
+        assert_eq!(
+            command(&["volume", "Aid", "-20"]),
+            Command::Volume {
+                device: "Aid".into(),
+                volume: -20
+            }
+        );
+        assert_eq!(
+            command(&["asha", "start", "Aid"]),
+            Command::Asha {
+                device: "Aid".into(),
+                command: AshaCommand::Start {
+                    volume: DEFAULT_START_VOLUME
+                }
+            }
+        );
+        assert_eq!(
+            command(&["asha", "status", "Aid", "other-connected"]),
+            Command::Asha {
+                device: "Aid".into(),
+                command: AshaCommand::Status(Update::OtherSideConnected)
+            }
+        );
+        assert_eq!(
+            command(&["preset", "set", "Aid", "Music"]),
+            Command::Preset {
+                device: "Aid".into(),
+                command: PresetAction::Set("Music".into())
+            }
+        );
+        assert_eq!(
+            command(&["mode", "Aid", "manual"]),
+            Command::Mode {
+                device: "Aid".into(),
+                mode: Some(Mode::Manual)
+            }
+        );
+        assert_eq!(command(&["config", "check"]), Command::ConfigCheck);
+        assert_eq!(command(&["status", "--help"]), Command::Help);
+        assert_eq!(command(&["help"]), Command::Help);
+        assert_eq!(command(&["-V", "status"]), Command::Version);
+    }
+
+    #[test]
+    fn bad_commands() {
+        assert_eq!(error(&["fly"]), "unknown command `fly`");
+        assert_eq!(
+            error(&["connect"]),
+            "`connect` needs a device address or name"
+        );
+        assert_eq!(error(&["status", "Aid"]), "unexpected argument `Aid`");
+        assert_eq!(error(&["scan", "soon"]), "`soon` is not a duration");
+        assert_eq!(
+            error(&["volume", "Aid", "5"]),
+            "`5` is not a volume from -128 to 0"
+        );
+        assert_eq!(error(&["asha", "fly", "Aid"]), "unknown asha command `fly`");
+        assert!(error(&["mode", "Aid", "sometimes"]).starts_with("unknown mode `sometimes`"));
+        assert_eq!(error(&["--frobnicate"]), "unknown option `--frobnicate`");
+        assert_eq!(error(&["--config"]), "`--config` needs a value");
+    }
+
+    #[test]
+    fn options() {
+        let cli = parse_args(&["--config=/tmp/a.toml", "status"]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("/tmp/a.toml")));
+        assert_eq!(cli.command, Command::Status);
+
+        let cli = parse_args(&["status", "-c", "b.toml", "--log-target", "stderr"]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("b.toml")));
+        assert_eq!(cli.log_target, Some(logging::Target::Stderr));
+
+        // Anything after `--` is an argument.
+        assert_eq!(
+            command(&["connect", "--", "-v"]),
+            Command::Connect("-v".into())
+        );
+    }
+
+    #[test]
+    fn verbosity() {
+        let level = |args: &[&str]| parse_args(args).unwrap().log_level;
+
+        assert_eq!(level(&[]), None);
+        assert_eq!(level(&["-v"]), Some(LevelFilter::Debug));
+        assert_eq!(level(&["-vv"]), Some(LevelFilter::Trace));
+        assert_eq!(level(&["--verbose", "-v"]), Some(LevelFilter::Trace));
+        assert_eq!(level(&["-q"]), Some(LevelFilter::Warn));
+        assert_eq!(level(&["-qq"]), Some(LevelFilter::Error));
+        assert_eq!(level(&["-v", "-q"]), None);
+        assert_eq!(
+            level(&["-v", "--log-level", "info"]),
+            Some(LevelFilter::Info)
+        );
+
+        // Repeated far beyond what counts, without overflowing.
+        let many = format!("-{}", "v".repeat(300));
+        assert_eq!(level(&[&many, &many]), Some(LevelFilter::Trace));
+        let many = format!("-{}", "q".repeat(300));
+        assert_eq!(level(&[&many, &many]), Some(LevelFilter::Error));
+    }
+
+    #[test]
+    fn stream_settings() {
+        assert_eq!(
+            command(&["stream", "Aid"]),
+            Command::Stream {
+                device: "Aid".into(),
+                source: None,
+                format: PcmFormat::default(),
+                volume: DEFAULT_START_VOLUME,
+            }
+        );
+        assert_eq!(
+            command(&[
+                "stream",
+                "Aid",
+                "-",
+                "rate=48000",
+                "channels=2",
+                "volume=-10"
+            ]),
+            Command::Stream {
+                device: "Aid".into(),
+                source: None,
+                format: PcmFormat {
+                    rate: 48_000,
+                    channels: 2
+                },
+                volume: -10,
+            }
+        );
+
+        // Only known settings are taken as such, other words are the source.
+        assert_eq!(
+            command(&["stream", "Aid", "rate=8000", "take=2.wav"]),
+            Command::Stream {
+                device: "Aid".into(),
+                source: Some(PathBuf::from("take=2.wav")),
+                format: PcmFormat {
+                    rate: 8000,
+                    channels: 1
+                },
+                volume: DEFAULT_START_VOLUME,
+            }
+        );
+
+        assert_eq!(
+            error(&["stream", "Aid", "rate=0"]),
+            "`0` is not a sample rate"
+        );
+        assert_eq!(
+            error(&["stream", "Aid", "a.wav", "b=c.wav"]),
+            "unexpected argument `b=c.wav`"
+        );
+    }
+}
@@ -0,0 +1,1068 @@
+/*
+
+Implementation of the command line subcommands
+
+Everything goes through the same backend, device and configuration code
+as the daemon; the commands only add a way to trigger it by hand.
+
+*/
+
+use crate::{
+    asha::{AudioType, Codecs, ControlCommand},
+    backend::{
+        AdapterEvent, AudioChannel, BluetoothBackend, BluetoothDevice, bluez::BluezBackend,
+        multi::MultiBackend,
+    },
+    capability::Protocol,
+    cli::{AshaCommand, Cli, Command, PresetAction, USAGE},
+    config::{AdapterSelector, Config, ConfigError, DeviceIdentity, DeviceOptions, Discovery},
+    control::{self, ControlError},
+    device::{self, LookupError, ReadError},
+    glob::Glob,
+    has::HearingAidType,
+    logging::{self, DeviceSpan},
+    modes::{self, Modes},
+    playback::{self, mpris},
+    preset::{self, PresetError, PresetSelector},
+    service,
+    state::Mode,
+    status::Registry,
+    stream::{self, PcmFormat, StreamError},
+    supervisor::{Signals, Stop, Supervisor},
+    volume,
+};
+use bluer::{Adapter, Address, Session};
+use dbus::arg::{PropMap, prop_cast};
+use futures::StreamExt;
+use log::{Level, LevelFilter, debug, info, warn};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Every adapter devices are handled on.
+type Adapters = MultiBackend<BluezBackend>;
+
+#[derive(Debug)]
+pub enum CommandError {
+    Config(ConfigError),
+    Bluetooth(bluer::Error),
+    Lookup(LookupError),
+    Read(ReadError),
+    Control(ControlError),
+    Stream(StreamError),
+    Preset(PresetError),
+    UnknownPreset(String),
+    Service(dbus::Error),
+    NotRunning,
+    Modes(PathBuf, io::Error),
+    AdapterOff,
+    NotConnected(Address),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Config(err) => write!(f, "invalid configuration: {}", err),
+            CommandError::Bluetooth(err) => write!(f, "Bluetooth error: {}", err),
+            CommandError::Lookup(err) => write!(f, "{}", err),
+            CommandError::Read(err) => write!(f, "{}", err),
+            CommandError::Control(err) => write!(f, "{}", err),
+            CommandError::Stream(err) => write!(f, "{}", err),
+            CommandError::Preset(err) => write!(f, "{}", err),
+            CommandError::UnknownPreset(preset) => {
+                write!(f, "the device has no preset `{}`", preset)
+            }
+            CommandError::Service(err) => {
+                write!(
+                    f,
+                    "reASHA service: {}",
+                    err.message().unwrap_or("no answer")
+                )
+            }
+            CommandError::NotRunning => write!(f, "reASHA is not running"),
+            CommandError::Modes(path, err) => write!(f, "{}: {}", path.display(), err),
+            CommandError::AdapterOff => write!(f, "no Bluetooth adapter is present and on"),
+            CommandError::NotConnected(address) => {
+                write!(f, "{} is not connected, connect it first", address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<ConfigError> for CommandError {
+    fn from(err: ConfigError) -> Self {
+        CommandError::Config(err)
+    }
+}
+
+impl From<bluer::Error> for CommandError {
+    fn from(err: bluer::Error) -> Self {
+        CommandError::Bluetooth(err)
+    }
+}
+
+impl From<ReadError> for CommandError {
+    fn from(err: ReadError) -> Self {
+        CommandError::Read(err)
+    }
+}
+
+impl From<ControlError> for CommandError {
+    fn from(err: ControlError) -> Self {
+        CommandError::Control(err)
+    }
+}
+
+impl From<StreamError> for CommandError {
+    fn from(err: StreamError) -> Self {
+        CommandError::Stream(err)
+    }
+}
+
+impl From<PresetError> for CommandError {
+    fn from(err: PresetError) -> Self {
+        CommandError::Preset(err)
+    }
+}
+
+impl From<dbus::Error> for CommandError {
+    fn from(err: dbus::Error) -> Self {
+        CommandError::Service(err)
+    }
+}
+
+impl From<LookupError> for CommandError {
+    fn from(err: LookupError) -> Self {
+        CommandError::Lookup(err)
+    }
+}
+
+pub async fn execute(cli: Cli) -> Result<(), CommandError> {
+    logging::init(
+        cli.log_level.unwrap_or(DEFAULT_LOG_LEVEL),
+        cli.log_target.unwrap_or_default(),
+    );
+
+    match &cli.command {
+        Command::Run => run(load_config(&cli)?).await,
+        Command::Scan { duration } => scan(&cli, *duration).await,
+        Command::Status => status(&cli).await,
+        Command::Info(query) => info(&cli, query).await,
+        Command::Connect(query) => connect(&cli, query).await,
+        Command::Disconnect(query) => disconnect(&cli, query).await,
+        Command::Trust(query) => set_trusted(&cli, query, true).await,
+        Command::Untrust(query) => set_trusted(&cli, query, false).await,
+        Command::Volume { device, volume } => set_volume(&cli, device, *volume).await,
+        Command::Asha { device, command } => asha(&cli, device, *command).await,
+        Command::Stream {
+            device,
+            source,
+            format,
+            volume,
+        } => stream(&cli, device, source.as_deref(), *format, *volume).await,
+        Command::Preset { device, command } => preset(&cli, device, command).await,
+        Command::Mode { device, mode } => device_mode(&cli, device, *mode).await,
+        Command::ConfigCheck => config_check(&cli),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Command::Version => {
+            println!("reasha {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+    }
+}
+
+/// Loads the configuration and applies its logging settings, unless the
+/// command line overrides them.
+fn load_config(cli: &Cli) -> Result<Config, ConfigError> {
+    let config = match &cli.config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::load()?,
+    };
+
+    logging::configure(
+        cli.log_level
+            .or(config.log.level)
+            .unwrap_or(DEFAULT_LOG_LEVEL),
+        cli.log_target.or(config.log.target).unwrap_or_default(),
+    );
+
+    Ok(config)
+}
+
+/// Like `load_config`, but devices can be handled by hand without any
+/// configuration.
+fn optional_config(cli: &Cli) -> Result<Option<Config>, ConfigError> {
+    match load_config(cli) {
+        Ok(config) => Ok(Some(config)),
+        Err(ConfigError::NotFound { .. }) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// The adapters selected in the configuration that are on.
+async fn open_adapters(cli: &Cli) -> Result<Adapters, CommandError> {
+    let selected = optional_config(cli)?
+        .map(|config| config.bluetooth.adapters)
+        .unwrap_or_default();
+    let session = Session::new().await?;
+
+    let mut backends = Vec::new();
+    for adapter in find_adapters(&session, &selected).await? {
+        if adapter.is_powered().await? {
+            backends.push(BluezBackend::new(adapter));
+        }
+    }
+
+    if backends.is_empty() {
+        return Err(CommandError::AdapterOff);
+    }
+
+    Ok(MultiBackend::new(backends))
+}
+
+/// The adapters in `selected` that are present, in that order, or the
+/// default adapter if none are selected.
+async fn find_adapters(
+    session: &Session,
+    selected: &[AdapterSelector],
+) -> bluer::Result<Vec<Adapter>> {
+    if selected.is_empty() {
+        return Ok(vec![session.default_adapter().await?]);
+    }
+
+    let mut present = Vec::new();
+    for name in session.adapter_names().await? {
+        let adapter = session.adapter(&name)?;
+        let address = adapter.address().await?;
+        present.push((adapter, address));
+    }
+
+    let mut found: Vec<Adapter> = Vec::new();
+    for selector in selected {
+        let adapter = present
+            .iter()
+            .find(|(adapter, address)| selector.matches(adapter.name(), *address));
+
+        match adapter {
+            Some((adapter, _)) if !found.iter().any(|other| other.name() == adapter.name()) => {
+                found.push(adapter.clone())
+            }
+            Some(_) => {}
+            None => debug!("Adapter {} is not present.", selector),
+        }
+    }
+
+    Ok(found)
+}
+
+/// The daemon: supervises managed devices forever, starting over whenever
+/// the adapter or discovery goes away.
+async fn run(config: Config) -> Result<(), CommandError> {
+    let config = Arc::new(config);
+
+    info!(
+        "Loaded {} managed device(s) from {}.",
+        config.devices.len(),
+        config.path.display()
+    );
+
+    let play_state = playback::audio_wanted(&config.playback).await;
+
+    // Only needed to pick presets or devices by player.
+    let players = if config.devices.iter().any(|device| {
+        !device.options.player_presets.is_empty() || !device.options.players.is_empty()
+    }) {
+        match mpris::watch_players(config.playback.players.clone()).await {
+            Ok(players) => Some(players),
+            Err(err) => {
+                warn!("Unable to follow MPRIS players: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let volume = if config.volume.sync {
+        match volume::watch_default_sink().await {
+            Ok(volume) => Some(volume),
+            Err(err) => {
+                warn!("Unable to follow the default sink volume: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let registry = match modes::path() {
+        Some(path) => {
+            let saved = modes::load(&path).unwrap_or_else(|err| {
+                warn!(
+                    "Could not read device modes from {}: {}",
+                    path.display(),
+                    err
+                );
+                Modes::new()
+            });
+            let registry = Registry::with_modes(saved);
+            tokio::spawn(save_modes(path, registry.clone()));
+            registry
+        }
+        None => Registry::new(),
+    };
+    match service::serve(registry.clone()).await {
+        Ok(()) => info!("Published {} on the session bus.", service::BUS_NAME),
+        Err(err) => warn!("Unable to publish the D-Bus service: {}", err),
+    }
+
+    let signals = Signals {
+        audio: play_state,
+        players,
+        volume,
+    };
+
+    let mut waiting = Waiting::default();
+
+    loop {
+        let session = match bluer::Session::new().await {
+            Ok(session) => session,
+            Err(err) => {
+                waiting.report(Level::Warn, format!("Unable to get D-Bus session: {err}"));
+                tokio::time::sleep(Duration::from_mins(1)).await;
+                continue;
+            }
+        };
+
+        let selected = &config.bluetooth.adapters;
+        let adapters = match find_adapters(&session, selected).await {
+            Ok(adapters) if !adapters.is_empty() => adapters,
+            Ok(_) => {
+                waiting.report(
+                    Level::Info,
+                    format!("None of the adapters {} is present.", selectors(selected)),
+                );
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+            Err(err) => {
+                waiting.report(Level::Warn, format!("Unable to get adapters: {err}"));
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        // Adapters that are off are kept, their devices are managed once
+        // they are switched on.
+        let backend = MultiBackend::new(adapters.into_iter().map(BluezBackend::new).collect());
+
+        let discovery = config.bluetooth.discovery;
+        let adapter_events = match discovery {
+            Discovery::Continuous => backend.discover().await,
+            Discovery::Bursts { .. } => backend.watch().await,
+        };
+        let adapter_events = match adapter_events {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("Could not start discovery: {}", err);
+                tokio::time::sleep(Duration::from_mins(1)).await;
+                continue;
+            }
+        };
+
+        match discovery {
+            Discovery::Continuous => info!("Discovering devices on {}...", backend.name()),
+            Discovery::Bursts { .. } => {
+                info!(
+                    "Managing devices on {}, scanning in {}...",
+                    backend.name(),
+                    discovery
+                )
+            }
+        }
+        waiting = Waiting::default();
+
+        let stop = Supervisor::new(
+            Arc::clone(&config),
+            backend,
+            signals.clone(),
+            registry.clone(),
+        )
+        .run(adapter_events)
+        .await;
+
+        match stop {
+            Stop::DiscoveryEnded => warn!("Discovery ended, starting over."),
+            Stop::ServiceLost => {
+                info!(
+                    "Stopped managing devices: {}, waiting for it to come back.",
+                    stop
+                )
+            }
+        }
+    }
+}
+
+/// Logs why the daemon cannot manage devices yet, once per reason rather
+/// than on every retry.
+#[derive(Default)]
+struct Waiting(Option<String>);
+
+impl Waiting {
+    fn report(&mut self, level: Level, message: String) {
+        if self.0.as_deref() != Some(message.as_str()) {
+            log::log!(level, "{}", message);
+            self.0 = Some(message);
+        }
+    }
+}
+
+/// Saves the modes set at runtime whenever one of them changes.
+async fn save_modes(path: PathBuf, registry: Registry) {
+    let mut modes = registry.watch_modes();
+
+    while modes.changed().await.is_ok() {
+        let current = modes.borrow_and_update().clone();
+        if let Err(err) = modes::save(&path, &current) {
+            warn!("Could not save device modes to {}: {}", path.display(), err);
+        }
+    }
+}
+
+async fn scan(cli: &Cli, duration: Duration) -> Result<(), CommandError> {
+    let backend = open_adapters(cli).await?;
+    let mut events = backend.discover().await?;
+    let deadline = Instant::now() + duration;
+    let mut found = BTreeMap::new();
+
+    println!("Scanning for {:.0}s...", duration.as_secs_f64());
+
+    while let Ok(Some(event)) = tokio::time::timeout_at(deadline, events.next()).await {
+        let AdapterEvent::DeviceAdded(address) = event else {
+            continue;
+        };
+        let Ok(device) = backend.device(address) else {
+            continue;
+        };
+
+        let advertisement = device::advertisement(&device).await;
+        if let Some(protocol) = device::advertised_services(&device, &advertisement)
+            .await
+            .protocol()
+        {
+            found.insert(address, (advertisement, protocol));
+        }
+    }
+
+    if found.is_empty() {
+        println!("No ASHA or HAS devices found.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<17}  {:<24}  {:>4}  {:<8}  HISYNCID",
+        "ADDRESS", "NAME", "RSSI", "PROTOCOL"
+    );
+
+    for (address, (advertisement, protocol)) in &found {
+        // Read at the end so that the most recent advertisement is shown.
+        let rssi = match backend.device(*address) {
+            Ok(device) => device.rssi().await.ok().flatten(),
+            Err(_) => None,
+        };
+
+        println!(
+            "{:<17}  {:<24}  {:>4}  {:<8}  {}",
+            address,
+            advertisement.display_name(),
+            rssi.map_or_else(|| "-".to_owned(), |rssi| rssi.to_string()),
+            protocol.to_string(),
+            truncated_hisyncid(&advertisement.identity.truncated_hisyncid),
+        );
+    }
+
+    Ok(())
+}
+
+/// Advertisements only carry the first four bytes of the HiSyncId.
+fn truncated_hisyncid(truncated: &Option<[u8; 4]>) -> String {
+    match truncated {
+        Some(truncated) => truncated
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .chain(["…".to_owned()])
+            .collect(),
+        None => "-".to_owned(),
+    }
+}
+
+/// What the daemon knows about its devices, or else what BlueZ does.
+async fn status(cli: &Cli) -> Result<(), CommandError> {
+    let devices = match service::Client::connect() {
+        Ok(client) => match client.devices().await {
+            Ok(devices) => Some(devices),
+            Err(err) if service::is_not_running(&err) => None,
+            Err(err) => return Err(err.into()),
+        },
+        // Without a session bus there is no daemon to ask either.
+        Err(_) => None,
+    };
+
+    match devices {
+        Some(devices) => {
+            daemon_status(devices.into_iter().collect());
+            Ok(())
+        }
+        None => {
+            println!("reASHA is not running, showing what BlueZ knows.\n");
+            bluez_status(cli, &load_config(cli)?).await
+        }
+    }
+}
+
+fn daemon_status(devices: BTreeMap<String, PropMap>) {
+    if devices.is_empty() {
+        println!("reASHA manages no devices right now.");
+        return;
+    }
+
+    println!(
+        "{:<17}  {:<24}  {:<8}  {:<13}  {:<11}  {:>7}  {:>4}  {:<6}  {:>4}",
+        "ADDRESS", "NAME", "PROTOCOL", "STATE", "MODE", "BATTERY", "RSSI", "PSM", "MTU"
+    );
+
+    for (address, properties) in &devices {
+        let text = |key| prop_cast::<String>(properties, key).map_or("-", String::as_str);
+        let number = |value: Option<String>| value.unwrap_or_else(|| "-".to_owned());
+
+        println!(
+            "{:<17}  {:<24}  {:<8}  {:<13}  {:<11}  {:>7}  {:>4}  {:<6}  {:>4}",
+            address,
+            text("Name"),
+            text("Protocol"),
+            text("State"),
+            text("Mode"),
+            number(prop_cast::<u8>(properties, "Battery").map(|battery| format!("{battery}%"))),
+            number(prop_cast::<i16>(properties, "RSSI").map(i16::to_string)),
+            number(prop_cast::<u16>(properties, "PSM").map(|psm| format!("0x{psm:04x}"))),
+            number(prop_cast::<u32>(properties, "MTU").map(u32::to_string)),
+        );
+    }
+}
+
+/// The managed devices as BlueZ sees them, for when the daemon is not
+/// running.
+async fn bluez_status(cli: &Cli, config: &Config) -> Result<(), CommandError> {
+    let backend = open_adapters(cli).await?;
+    let mut managed: Vec<(Address, String, usize)> = Vec::new();
+
+    for address in backend.devices().await? {
+        let device = backend.device(address)?;
+        let advertisement = device::advertisement(&device).await;
+
+        if let Some(index) = config
+            .devices
+            .iter()
+            .position(|entry| entry.matcher.matches(&advertisement.identity))
+        {
+            managed.push((address, advertisement.display_name().to_owned(), index));
+        }
+    }
+
+    println!(
+        "{:<17}  {:<24}  {:<13}  {:<7}  {:<6}  {:<7}  MATCHED BY",
+        "ADDRESS", "NAME", "STATE", "TRUSTED", "PSM", "ADAPTER"
+    );
+
+    for (address, name, index) in &managed {
+        let device = backend.device(*address)?;
+        let connected = device.is_connected().await?;
+        let state = match (connected, device.rssi().await?) {
+            (true, _) => "connected",
+            (false, Some(_)) => "in range",
+            (false, None) => "not in range",
+        };
+
+        // Only readable while connected.
+        let psm = if connected {
+            device::read_psm(&device).await.ok()
+        } else {
+            None
+        };
+
+        println!(
+            "{:<17}  {:<24}  {:<13}  {:<7}  {:<6}  {:<7}  {}",
+            address,
+            name,
+            state,
+            if device.is_trusted().await? {
+                "yes"
+            } else {
+                "no"
+            },
+            psm.map_or_else(|| "-".to_owned(), |psm| format!("0x{psm:04x}")),
+            backend
+                .adapter_of(*address)
+                .map_or("-", |adapter| adapter.name()),
+            config.devices[*index].matcher
+        );
+    }
+
+    for (index, entry) in config.devices.iter().enumerate() {
+        if !managed.iter().any(|(_, _, matched)| *matched == index) {
+            println!("No known device matches {}.", entry.matcher);
+        }
+    }
+
+    Ok(())
+}
+
+async fn info(cli: &Cli, query: &str) -> Result<(), CommandError> {
+    let device = device::lookup(&open_adapters(cli).await?, query).await?;
+    let advertisement = device::advertisement(&device).await;
+
+    if !device.is_connected().await? {
+        return Err(CommandError::NotConnected(device.address()));
+    }
+
+    let properties = device::read_properties(&device).await?;
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+    println!("Address:       {}", device.address());
+    println!("Name:          {}", advertisement.display_name());
+    println!("Version:       {}", properties.version);
+    println!("Side:          {}", properties.capabilities.side());
+    println!(
+        "Binaural:      {}",
+        yes_no(properties.capabilities.binaural())
+    );
+    println!("CSIS:          {}", yes_no(properties.capabilities.csis()));
+    println!(
+        "HiSyncId:      {} (manufacturer 0x{:04x})",
+        properties.hisyncid,
+        properties.hisyncid.manufacturer()
+    );
+    println!(
+        "CoC streaming: {}",
+        yes_no(properties.feature_map.coc_streaming())
+    );
+    println!("Render delay:  {} ms", properties.render_delay);
+    println!("Codecs:        {}", properties.codecs);
+    println!("LE PSM:        0x{:04x}", device::read_psm(&device).await?);
+
+    Ok(())
+}
+
+async fn connect(cli: &Cli, query: &str) -> Result<(), CommandError> {
+    let config = optional_config(cli)?;
+
+    let device = device::lookup(&open_adapters(cli).await?, query).await?;
+    let advertisement = device::advertisement(&device).await;
+
+    let auto_trust = config
+        .as_ref()
+        .and_then(|config| config.find(&advertisement.identity))
+        .map_or(DeviceOptions::default().auto_trust, |entry| {
+            entry.options.auto_trust
+        });
+
+    // Without a sign of HAS, connect ASHA as always.
+    let protocol = device::advertised_services(&device, &advertisement)
+        .await
+        .protocol()
+        .unwrap_or(Protocol::Asha);
+
+    let span = DeviceSpan::new(device.address(), advertisement.display_name());
+    device::connect(&device, &span, protocol, auto_trust).await?;
+    println!(
+        "Connected {} ({}).",
+        advertisement.display_name(),
+        device.address()
+    );
+
+    Ok(())
+}
+
+async fn disconnect(cli: &Cli, query: &str) -> Result<(), CommandError> {
+    let device = device::lookup(&open_adapters(cli).await?, query).await?;
+    device.disconnect().await?;
+
+    println!("Disconnected {}.", device.address());
+
+    Ok(())
+}
+
+async fn set_trusted(cli: &Cli, query: &str, trusted: bool) -> Result<(), CommandError> {
+    let device = device::lookup(&open_adapters(cli).await?, query).await?;
+    device.set_trusted(trusted).await?;
+
+    println!(
+        "{} {}.",
+        if trusted { "Trusted" } else { "Untrusted" },
+        device.address()
+    );
+
+    Ok(())
+}
+
+async fn set_volume(cli: &Cli, query: &str, volume: i8) -> Result<(), CommandError> {
+    let device = device::lookup(&open_adapters(cli).await?, query).await?;
+
+    if !device.is_connected().await? {
+        return Err(CommandError::NotConnected(device.address()));
+    }
+
+    device::set_volume(&device, volume).await?;
+    println!("Set the volume of {} to {} dB.", device.address(), volume);
+
+    Ok(())
+}
+
+async fn asha(cli: &Cli, query: &str, command: AshaCommand) -> Result<(), CommandError> {
+    let device = device::lookup(&open_adapters(cli).await?, query).await?;
+
+    if !device.is_connected().await? {
+        return Err(CommandError::NotConnected(device.address()));
+    }
+
+    let command = match command {
+        AshaCommand::Start { volume } => {
+            let codecs = device::read_properties(&device).await?.codecs;
+            // Every device should take G.722 at 16 kHz; otherwise try what
+            // it lists.
+            let codec = if codecs.supports(Codecs::G722_16KHZ) {
+                Codecs::G722_16KHZ
+            } else {
+                codecs.ids().next().unwrap_or(Codecs::G722_16KHZ)
+            };
+
+            ControlCommand::Start {
+                codec,
+                audio_type: AudioType::Media,
+                volume,
+                other_side_connected: false,
+            }
+        }
+        AshaCommand::Stop => ControlCommand::Stop,
+        AshaCommand::Status(update) => ControlCommand::Status(update),
+        AshaCommand::Watch { duration } => return watch_status(&device, duration).await,
+        AshaCommand::Probe => {
+            let psm = device::read_psm(&device).await?;
+            let channel = device.open_l2cap(psm).await?.info();
+            println!(
+                "Opened PSM 0x{:04x} of {}: send MTU {}, receive MTU {}.",
+                psm,
+                device.address(),
+                channel.send_mtu,
+                channel.recv_mtu
+            );
+            return Ok(());
+        }
+    };
+
+    control::send(&device, command).await?;
+    println!("{} accepted {}.", device.address(), command);
+
+    Ok(())
+}
+
+async fn watch_status<D: BluetoothDevice>(
+    device: &D,
+    duration: Option<Duration>,
+) -> Result<(), CommandError> {
+    let mut statuses = control::statuses(device).await?;
+    let start = Instant::now();
+
+    match control::last_status(device).await? {
+        Ok(()) => println!("Last status: ok"),
+        Err(err) => println!("Last status: {}", err),
+    }
+
+    loop {
+        let next = match duration {
+            Some(duration) => {
+                match tokio::time::timeout_at(start + duration, statuses.next()).await {
+                    Ok(next) => next,
+                    Err(_) => return Ok(()),
+                }
+            }
+            None => statuses.next().await,
+        };
+
+        let Some(status) = next else {
+            println!("{} disconnected.", device.address());
+            return Ok(());
+        };
+
+        let elapsed = start.elapsed().as_secs_f64();
+        match status {
+            Ok(()) => println!("{elapsed:8.3}s  ok"),
+            Err(err) => println!("{elapsed:8.3}s  {err}"),
+        }
+    }
+}
+
+async fn stream(
+    cli: &Cli,
+    query: &str,
+    source: Option<&Path>,
+    format: PcmFormat,
+    volume: i8,
+) -> Result<(), CommandError> {
+    let backend = open_adapters(cli).await?;
+    let device = device::lookup(&backend, query).await?;
+
+    if !device.is_connected().await? {
+        return Err(CommandError::NotConnected(device.address()));
+    }
+
+    let properties = device::read_properties(&device).await?;
+    let mut devices = vec![device.clone()];
+    if properties.capabilities.binaural()
+        && let Some(partner) = device::partner(&backend, &device, properties.hisyncid).await?
+    {
+        devices.push(partner);
+    }
+
+    let (source, format) = stream::open_source(source, format).await?;
+    println!(
+        "Streaming {} to {}, Ctrl-C to stop.",
+        format,
+        devices
+            .iter()
+            .map(|device| device.address().to_string())
+            .collect::<Vec<_>>()
+            .join(" and ")
+    );
+
+    stream::run(devices, source, format, volume, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await?;
+
+    Ok(())
+}
+
+async fn preset(cli: &Cli, query: &str, action: &PresetAction) -> Result<(), CommandError> {
+    let device = device::lookup(&open_adapters(cli).await?, query).await?;
+
+    if !device.is_connected().await? {
+        return Err(CommandError::NotConnected(device.address()));
+    }
+
+    let features = preset::read_features(&device).await?;
+    let presets = preset::read_presets(&device).await?;
+
+    match action {
+        PresetAction::List => {
+            let active = preset::active_preset(&device).await?;
+
+            println!(
+                "{} is a {} hearing aid{}.",
+                device.address(),
+                features.hearing_aid_type(),
+                if features.preset_sync() {
+                    ", presets follow the other side"
+                } else {
+                    ""
+                }
+            );
+            println!("  {:>5}  NAME", "INDEX");
+
+            for preset in &presets {
+                println!(
+                    "{} {:>5}  {}{}",
+                    if preset.index == active { "*" } else { " " },
+                    preset.index,
+                    preset.name,
+                    if preset.available {
+                        ""
+                    } else {
+                        " (unavailable)"
+                    }
+                );
+            }
+        }
+        PresetAction::Set(wanted) => {
+            let Some(preset) = PresetSelector::from(wanted.as_str()).find(&presets) else {
+                return Err(CommandError::UnknownPreset(wanted.clone()));
+            };
+
+            // Let a binaural aid take the other side along.
+            let synced =
+                features.preset_sync() && features.hearing_aid_type() == HearingAidType::Binaural;
+
+            preset::set_active(&device, preset.index, synced).await?;
+            println!(
+                "Selected preset {} (`{}`) on {}.",
+                preset.index,
+                preset.name,
+                device.address()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Goes through the daemon if it is running, and otherwise through the saved
+/// modes it starts with. Only a name needs the adapters to be looked up; an
+/// address is taken as it is, so that devices not around can be set too.
+async fn device_mode(cli: &Cli, query: &str, mode: Option<Mode>) -> Result<(), CommandError> {
+    let identity = match query.parse::<Address>() {
+        Ok(address) => DeviceIdentity {
+            address,
+            ..DeviceIdentity::default()
+        },
+        Err(_) => {
+            let device = device::lookup(&open_adapters(cli).await?, query).await?;
+            device::advertisement(&device).await.identity
+        }
+    };
+    let address = identity.address;
+
+    let reply = match service::Client::connect() {
+        Ok(client) => Some(match mode {
+            Some(mode) => client.set_mode(address, mode).await.map(|()| None),
+            None => client.device(address).await.map(Some),
+        }),
+        // Without a session bus there is no daemon to ask either.
+        Err(_) => None,
+    };
+
+    let running = match reply {
+        Some(Ok(Some(properties))) => {
+            println!(
+                "{} is in {} mode and {}.",
+                address,
+                prop_cast::<String>(&properties, "Mode").map_or("unknown", |mode| mode),
+                prop_cast::<String>(&properties, "State").map_or("unknown", |state| state),
+            );
+            return Ok(());
+        }
+        Some(Ok(None)) => {
+            println!("{} is now in {} mode.", address, mode.unwrap_or_default());
+            return Ok(());
+        }
+        // Not managed right now, the saved modes tell what it will be in.
+        Some(Err(err)) if service::is_unknown_device(&err) => true,
+        Some(Err(err)) if !service::is_not_running(&err) => return Err(err.into()),
+        _ => false,
+    };
+
+    let Some(path) = modes::path() else {
+        return Err(CommandError::NotRunning);
+    };
+    let mut saved = modes::load(&path).map_err(|err| CommandError::Modes(path.clone(), err))?;
+
+    match mode {
+        // The daemon takes modes for any device, so it is not running.
+        Some(mode) => {
+            saved.insert(address, mode);
+            modes::save(&path, &saved).map_err(|err| CommandError::Modes(path.clone(), err))?;
+            println!(
+                "reASHA is not running, {} will start in {} mode.",
+                address, mode
+            );
+        }
+        None => {
+            let configured = load_config(cli)?
+                .find(&identity)
+                .map(|entry| entry.options.mode);
+
+            match saved.get(&address).copied().or(configured) {
+                Some(mode) if running => println!(
+                    "{} is not managed right now, it will be in {} mode.",
+                    address, mode
+                ),
+                Some(mode) => println!(
+                    "reASHA is not running, {} will start in {} mode.",
+                    address, mode
+                ),
+                None => println!("{} is not a managed device.", address),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn config_check(cli: &Cli) -> Result<(), CommandError> {
+    let config = load_config(cli)?;
+
+    println!("{} is valid.", config.path.display());
+    println!(
+        "Playback sources: {} (combined with {}).",
+        config
+            .playback
+            .sources
+            .iter()
+            .map(|source| source.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        config.playback.combine
+    );
+
+    if !config.bluetooth.adapters.is_empty() {
+        println!("Adapters: {}.", selectors(&config.bluetooth.adapters));
+    }
+    if config.bluetooth.discovery != Discovery::default() {
+        println!("Discovery: {}.", config.bluetooth.discovery);
+    }
+
+    let players = &config.playback.players;
+    if !players.include.is_empty() {
+        println!("MPRIS players followed: {}.", patterns(&players.include));
+    }
+    if !players.exclude.is_empty() {
+        println!("MPRIS players ignored: {}.", patterns(&players.exclude));
+    }
+
+    for entry in &config.devices {
+        println!("Managed device: {}", entry.matcher);
+
+        let options = &entry.options;
+        if options.mode != Mode::default() {
+            println!("  Mode: {}", options.mode);
+        }
+        if !options.players.is_empty() {
+            println!("  Connects for: {}", patterns(&options.players));
+        }
+        if let Some(preset) = &options.media_preset {
+            println!("  Media preset: {}", preset);
+        }
+        for (player, preset) in &options.player_presets {
+            println!("  Preset for {}: {}", player, preset);
+        }
+    }
+
+    Ok(())
+}
+
+fn selectors(selectors: &[AdapterSelector]) -> String {
+    selectors
+        .iter()
+        .map(|selector| selector.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn patterns(patterns: &[Glob]) -> String {
+    patterns
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,1164 @@
+/*
+
+Configuration file loading and validation
+
+The configuration lives in `$XDG_CONFIG_HOME/reasha/config.toml`, falling
+back to `/etc/reasha/config.toml`. Each `[[device]]` entry selects one
+managed device by exactly one of `address`, `name` (a glob) or `hisyncid`
+and carries that device's options.
+
+*/
+
+mod toml;
+
+use crate::{
+    asha::HiSyncId,
+    glob::Glob,
+    logging,
+    playback::mpris::MPRIS_PREFIX,
+    preset::PresetSelector,
+    state::{Mode, Policy, RetryPolicy},
+};
+use bluer::Address;
+use log::LevelFilter;
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use toml::{Table, Value};
+
+pub use toml::ParseError;
+
+const FILE_NAME: &str = "config.toml";
+const SYSTEM_DIR: &str = "/etc/reasha";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub path: PathBuf,
+    pub bluetooth: BluetoothConfig,
+    pub playback: PlaybackConfig,
+    pub log: LogConfig,
+    pub volume: VolumeConfig,
+    pub devices: Vec<DeviceConfig>,
+}
+
+/// Which Bluetooth adapters are used.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BluetoothConfig {
+    /// Adapters to manage devices on, the preferred one first. Just the
+    /// default adapter if empty.
+    pub adapters: Vec<AdapterSelector>,
+    pub discovery: Discovery,
+}
+
+/// When the adapters scan for devices.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Discovery {
+    /// All the time.
+    #[default]
+    Continuous,
+    /// For `duration` every `interval`, and early for a device about to be
+    /// connected that was not seen within `interval`.
+    Bursts {
+        duration: Duration,
+        interval: Duration,
+    },
+}
+
+impl Discovery {
+    pub const DEFAULT_SCAN_DURATION: Duration = Duration::from_secs(10);
+    pub const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_mins(5);
+}
+
+/// An adapter, by name (`hci1`) or address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdapterSelector {
+    Name(String),
+    Address(Address),
+}
+
+impl AdapterSelector {
+    pub fn matches(&self, name: &str, address: Address) -> bool {
+        match self {
+            AdapterSelector::Name(wanted) => wanted == name,
+            AdapterSelector::Address(wanted) => *wanted == address,
+        }
+    }
+}
+
+impl From<&str> for AdapterSelector {
+    /// Anything that is not an address is a name.
+    fn from(text: &str) -> Self {
+        match text.parse() {
+            Ok(address) => AdapterSelector::Address(address),
+            Err(_) => AdapterSelector::Name(text.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for AdapterSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdapterSelector::Name(name) => write!(f, "{}", name),
+            AdapterSelector::Address(address) => write!(f, "{}", address),
+        }
+    }
+}
+
+/// Logging settings; command line options take precedence.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: Option<LevelFilter>,
+    pub target: Option<logging::Target>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VolumeConfig {
+    /// Mirror the default sink's volume and mute state onto connected
+    /// devices.
+    pub sync: bool,
+}
+
+/// Which playback sources decide that audio is wanted, and how their
+/// answers are combined.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlaybackConfig {
+    pub sources: Vec<SourceKind>,
+    pub combine: Combine,
+    pub players: PlayerFilter,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceKind {
+    Mpris,
+    Pulse,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Combine {
+    /// Audio is wanted if any source has audio.
+    Or,
+    /// Audio is wanted only if every source has audio.
+    And,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        Self {
+            sources: vec![SourceKind::Mpris],
+            combine: Combine::Or,
+            players: PlayerFilter::default(),
+        }
+    }
+}
+
+/// Which MPRIS players are followed at all, by name pattern. Names are
+/// bus names without the `org.mpris.MediaPlayer2.` prefix.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlayerFilter {
+    /// Only these players are followed, unless empty.
+    pub include: Vec<Glob>,
+    /// These players are never followed.
+    pub exclude: Vec<Glob>,
+}
+
+impl PlayerFilter {
+    pub fn allows(&self, player: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| pattern.is_match(player)))
+            && !self.exclude.iter().any(|pattern| pattern.is_match(player))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    pub matcher: DeviceMatcher,
+    pub options: DeviceOptions,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceMatcher {
+    Address(Address),
+    Name(Glob),
+    HiSyncId(HiSyncId),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceOptions {
+    /// When the device should be connected. A mode set at runtime takes
+    /// precedence.
+    pub mode: Mode,
+    /// Mark the device as trusted before connecting.
+    pub auto_trust: bool,
+    /// Manage the device even if it does not appear to support ASHA.
+    pub force_asha: bool,
+    /// How long audio must stay unwanted before disconnecting.
+    pub idle_timeout: Duration,
+    /// How long to keep a link up at least, even if audio stops.
+    pub min_connected: Duration,
+    /// How failed connection attempts are retried.
+    pub retry: RetryPolicy,
+    /// How long to wait for the other side of a binaural set before
+    /// connecting or disconnecting alone.
+    pub binaural_timeout: Duration,
+    /// Open an L2CAP channel to the audio PSM after connecting, to check
+    /// that the data path is reachable.
+    pub probe_l2cap: bool,
+    /// HAS preset to select while audio is wanted; the previous one is
+    /// selected again once it stops.
+    pub media_preset: Option<PresetSelector>,
+    /// Presets for particular MPRIS players by name pattern, taking
+    /// precedence over `media_preset`.
+    pub player_presets: Vec<(Glob, PresetSelector)>,
+    /// MPRIS players, by name pattern, whose playback connects the device.
+    /// Any audio does if empty.
+    pub players: Vec<Glob>,
+}
+
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        let policy = Policy::default();
+
+        Self {
+            mode: Mode::default(),
+            auto_trust: true,
+            force_asha: false,
+            idle_timeout: policy.idle_timeout,
+            min_connected: policy.min_connected,
+            retry: policy.retry,
+            binaural_timeout: Duration::from_secs(5),
+            probe_l2cap: false,
+            media_preset: None,
+            player_presets: Vec::new(),
+            players: Vec::new(),
+        }
+    }
+}
+
+impl DeviceOptions {
+    /// The preset to select while audio plays, given the names of the MPRIS
+    /// players that are playing.
+    pub fn preset_for<'a>(
+        &self,
+        mut playing: impl Iterator<Item = &'a str>,
+    ) -> Option<&PresetSelector> {
+        playing
+            .find_map(|player| {
+                self.player_presets
+                    .iter()
+                    .find(|(pattern, _)| pattern.is_match(player))
+            })
+            .map(|(_, preset)| preset)
+            .or(self.media_preset.as_ref())
+    }
+
+    /// Whether playback should connect the device, given the names of the
+    /// MPRIS players that are playing.
+    pub fn follows<'a>(&self, mut playing: impl Iterator<Item = &'a str>) -> bool {
+        self.players.is_empty()
+            || playing.any(|player| self.players.iter().any(|pattern| pattern.is_match(player)))
+    }
+
+    pub fn switches_presets(&self) -> bool {
+        self.media_preset.is_some() || !self.player_presets.is_empty()
+    }
+
+    pub fn policy(&self) -> Policy {
+        Policy {
+            idle_timeout: self.idle_timeout,
+            min_connected: self.min_connected,
+            retry: self.retry,
+        }
+    }
+}
+
+/// What is known about a device when deciding whether it is managed.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceIdentity {
+    pub address: Address,
+    pub name: Option<String>,
+    pub hisyncid: Option<HiSyncId>,
+    /// Truncated HiSyncId from the ASHA advertisement service data.
+    pub truncated_hisyncid: Option<[u8; 4]>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NotFound {
+        searched: Vec<PathBuf>,
+    },
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Syntax {
+        path: PathBuf,
+        source: ParseError,
+    },
+    Invalid {
+        path: PathBuf,
+        field: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotFound { searched } => {
+                write!(f, "no configuration file found, searched:")?;
+                for path in searched {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                Ok(())
+            }
+            ConfigError::Read { path, source } => {
+                write!(f, "could not read {}: {}", path.display(), source)
+            }
+            ConfigError::Syntax { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            ConfigError::Invalid {
+                path,
+                field,
+                message,
+            } => write!(f, "{}: `{}`: {}", path.display(), field, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Syntax { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Locations searched for the configuration file, in priority order.
+pub fn search_paths() -> Vec<PathBuf> {
+    let user_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    user_dir
+        .map(|dir| dir.join("reasha").join(FILE_NAME))
+        .into_iter()
+        .chain([Path::new(SYSTEM_DIR).join(FILE_NAME)])
+        .collect()
+}
+
+impl Config {
+    /// Loads the first configuration file found in [`search_paths`].
+    pub fn load() -> Result<Config, ConfigError> {
+        let searched = search_paths();
+
+        match searched.iter().find(|path| path.is_file()) {
+            Some(path) => Config::from_file(path),
+            None => Err(ConfigError::NotFound { searched }),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        Config::parse(&text, path)
+    }
+
+    pub fn parse(text: &str, path: &Path) -> Result<Config, ConfigError> {
+        let table = toml::parse(text).map_err(|source| ConfigError::Syntax {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        Config::from_table(table, path).map_err(|Invalid { field, message }| ConfigError::Invalid {
+            path: path.to_owned(),
+            field,
+            message,
+        })
+    }
+
+    fn from_table(table: Table, path: &Path) -> Result<Config, Invalid> {
+        let mut root = Section::new(table, "");
+
+        let bluetooth = match root.table("bluetooth")? {
+            Some(section) => BluetoothConfig::from_section(section)?,
+            None => BluetoothConfig::default(),
+        };
+
+        let playback = match root.table("playback")? {
+            Some(section) => PlaybackConfig::from_section(section)?,
+            None => PlaybackConfig::default(),
+        };
+
+        let log = match root.table("log")? {
+            Some(section) => LogConfig::from_section(section)?,
+            None => LogConfig::default(),
+        };
+
+        let volume = match root.table("volume")? {
+            Some(mut section) => {
+                let volume = VolumeConfig {
+                    sync: section.bool("sync")?.unwrap_or_default(),
+                };
+                section.finish()?;
+                volume
+            }
+            None => VolumeConfig::default(),
+        };
+
+        let devices = root
+            .tables("device")?
+            .into_iter()
+            .map(DeviceConfig::from_section)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        root.finish()?;
+
+        for (i, device) in devices.iter().enumerate() {
+            if devices[..i]
+                .iter()
+                .any(|other| other.matcher == device.matcher)
+            {
+                return Err(Invalid::new(
+                    format!("device[{i}]"),
+                    format!("{} is already listed by an earlier entry", device.matcher),
+                ));
+            }
+        }
+
+        Ok(Config {
+            path: path.to_owned(),
+            bluetooth,
+            playback,
+            log,
+            volume,
+            devices,
+        })
+    }
+
+    /// Returns the first entry that selects this device, if any.
+    pub fn find(&self, identity: &DeviceIdentity) -> Option<&DeviceConfig> {
+        self.devices
+            .iter()
+            .find(|device| device.matcher.matches(identity))
+    }
+}
+
+impl BluetoothConfig {
+    fn from_section(mut section: Section) -> Result<BluetoothConfig, Invalid> {
+        let adapters = section
+            .strings("adapters")?
+            .unwrap_or_default()
+            .iter()
+            .map(|adapter| AdapterSelector::from(adapter.as_str()))
+            .collect();
+
+        let bursts = match section.string("discovery")?.as_deref() {
+            None | Some("continuous") => false,
+            Some("bursts") => true,
+            Some(other) => {
+                return Err(section.invalid(
+                    "discovery",
+                    format!("unknown discovery `{other}`, expected `continuous` or `bursts`"),
+                ));
+            }
+        };
+
+        let duration = section.duration("scan_duration")?;
+        let interval = section.duration("scan_interval")?;
+
+        let discovery = if bursts {
+            let duration = duration.unwrap_or(Discovery::DEFAULT_SCAN_DURATION);
+            if duration.is_zero() {
+                return Err(section.invalid("scan_duration", "must be longer than zero"));
+            }
+            let interval = interval.unwrap_or(Discovery::DEFAULT_SCAN_INTERVAL);
+            if interval.is_zero() {
+                return Err(section.invalid("scan_interval", "must be longer than zero"));
+            }
+            if interval < duration {
+                return Err(section.invalid("scan_interval", "must not be below `scan_duration`"));
+            }
+            Discovery::Bursts { duration, interval }
+        } else {
+            for (key, value) in [("scan_duration", duration), ("scan_interval", interval)] {
+                if value.is_some() {
+                    return Err(section.invalid(key, "only applies with `discovery = \"bursts\"`"));
+                }
+            }
+            Discovery::Continuous
+        };
+
+        section.finish()?;
+
+        Ok(BluetoothConfig {
+            adapters,
+            discovery,
+        })
+    }
+}
+
+impl PlaybackConfig {
+    fn from_section(mut section: Section) -> Result<PlaybackConfig, Invalid> {
+        let defaults = PlaybackConfig::default();
+
+        let sources = match section.strings("sources")? {
+            Some(names) => names
+                .iter()
+                .map(|name| match name.as_str() {
+                    "mpris" => Ok(SourceKind::Mpris),
+                    "pulse" => Ok(SourceKind::Pulse),
+                    other => Err(section.invalid(
+                        "sources",
+                        format!("unknown source `{other}`, expected `mpris` or `pulse`"),
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => defaults.sources,
+        };
+
+        if sources.is_empty() {
+            return Err(section.invalid("sources", "at least one source is required"));
+        }
+
+        let combine = match section.string("combine")?.as_deref() {
+            None => defaults.combine,
+            Some("or") => Combine::Or,
+            Some("and") => Combine::And,
+            Some(other) => {
+                return Err(section.invalid(
+                    "combine",
+                    format!("unknown mode `{other}`, expected `or` or `and`"),
+                ));
+            }
+        };
+
+        let players = PlayerFilter {
+            include: section.players("players")?,
+            exclude: section.players("ignore_players")?,
+        };
+
+        section.finish()?;
+
+        Ok(PlaybackConfig {
+            sources,
+            combine,
+            players,
+        })
+    }
+}
+
+impl LogConfig {
+    fn from_section(mut section: Section) -> Result<LogConfig, Invalid> {
+        let level = match section.string("level")? {
+            Some(level) => Some(
+                logging::parse_level(&level)
+                    .map_err(|message| section.invalid("level", message))?,
+            ),
+            None => None,
+        };
+
+        let target = match section.string("target")? {
+            Some(target) => Some(
+                target
+                    .parse()
+                    .map_err(|message| section.invalid("target", message))?,
+            ),
+            None => None,
+        };
+
+        section.finish()?;
+
+        Ok(LogConfig { level, target })
+    }
+}
+
+impl DeviceConfig {
+    fn from_section(mut section: Section) -> Result<DeviceConfig, Invalid> {
+        let address = section.string("address")?;
+        let name = section.string("name")?;
+        let hisyncid = section.string("hisyncid")?;
+
+        let matcher = match (address, name, hisyncid) {
+            (Some(address), None, None) => {
+                DeviceMatcher::Address(address.parse().map_err(|_| {
+                    section.invalid("address", format!("`{address}` is not a Bluetooth address"))
+                })?)
+            }
+            (None, Some(name), None) => DeviceMatcher::Name(Glob::new(&name)),
+            (None, None, Some(hisyncid)) => DeviceMatcher::HiSyncId(
+                hisyncid
+                    .parse()
+                    .map_err(|message| section.invalid("hisyncid", message))?,
+            ),
+            _ => {
+                return Err(Invalid::new(
+                    section.path.clone(),
+                    "exactly one of `address`, `name` or `hisyncid` must be set",
+                ));
+            }
+        };
+
+        let defaults = DeviceOptions::default();
+        let options = DeviceOptions {
+            mode: match section.string("mode")? {
+                Some(mode) => mode
+                    .parse()
+                    .map_err(|message| section.invalid("mode", message))?,
+                None => defaults.mode,
+            },
+            auto_trust: section.bool("auto_trust")?.unwrap_or(defaults.auto_trust),
+            force_asha: section.bool("force_asha")?.unwrap_or(defaults.force_asha),
+            idle_timeout: section
+                .duration("idle_timeout")?
+                .unwrap_or(defaults.idle_timeout),
+            min_connected: section
+                .duration("min_connected")?
+                .unwrap_or(defaults.min_connected),
+            retry: match section.table("retry")? {
+                Some(retry) => retry_policy(retry, defaults.retry)?,
+                None => defaults.retry,
+            },
+            binaural_timeout: section
+                .duration("binaural_timeout")?
+                .unwrap_or(defaults.binaural_timeout),
+            probe_l2cap: section.bool("probe_l2cap")?.unwrap_or(defaults.probe_l2cap),
+            media_preset: section.preset("media_preset")?,
+            player_presets: section.player_presets("player_presets")?,
+            players: section.players("players")?,
+        };
+
+        section.finish()?;
+
+        Ok(DeviceConfig { matcher, options })
+    }
+}
+
+/// MPRIS players are matched without the bus name prefix.
+fn player_pattern(pattern: &str) -> Glob {
+    Glob::new(pattern.strip_prefix(MPRIS_PREFIX).unwrap_or(pattern))
+}
+
+fn retry_policy(mut section: Section, defaults: RetryPolicy) -> Result<RetryPolicy, Invalid> {
+    let retry = RetryPolicy {
+        initial_delay: section
+            .duration("initial_delay")?
+            .unwrap_or(defaults.initial_delay),
+        max_delay: section.duration("max_delay")?.unwrap_or(defaults.max_delay),
+        multiplier: section.float("multiplier")?.unwrap_or(defaults.multiplier),
+        jitter: section.float("jitter")?.unwrap_or(defaults.jitter),
+        max_attempts: section
+            .count("max_attempts")?
+            .unwrap_or(defaults.max_attempts),
+        cooldown: section.duration("cooldown")?.unwrap_or(defaults.cooldown),
+    };
+
+    if retry.multiplier < 1.0 {
+        return Err(section.invalid("multiplier", "must be at least 1"));
+    }
+    if !(0.0..=1.0).contains(&retry.jitter) {
+        return Err(section.invalid("jitter", "must be between 0 and 1"));
+    }
+    if retry.max_delay < retry.initial_delay {
+        return Err(section.invalid("max_delay", "must not be below `initial_delay`"));
+    }
+
+    section.finish()?;
+
+    Ok(retry)
+}
+
+impl DeviceMatcher {
+    pub fn matches(&self, identity: &DeviceIdentity) -> bool {
+        match self {
+            DeviceMatcher::Address(address) => identity.address == *address,
+            DeviceMatcher::Name(glob) => identity
+                .name
+                .as_deref()
+                .is_some_and(|name| glob.is_match(name)),
+            DeviceMatcher::HiSyncId(hisyncid) => {
+                match (identity.hisyncid, identity.truncated_hisyncid) {
+                    (Some(known), _) => known == *hisyncid,
+                    (None, Some(truncated)) => hisyncid.matches_truncated(&truncated),
+                    (None, None) => false,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceKind::Mpris => write!(f, "mpris"),
+            SourceKind::Pulse => write!(f, "pulse"),
+        }
+    }
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discovery::Continuous => write!(f, "continuous"),
+            Discovery::Bursts { duration, interval } => {
+                write!(f, "bursts of {:?} every {:?}", duration, interval)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Combine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Combine::Or => write!(f, "or"),
+            Combine::And => write!(f, "and"),
+        }
+    }
+}
+
+impl fmt::Display for DeviceMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceMatcher::Address(address) => write!(f, "address {address}"),
+            DeviceMatcher::Name(glob) => write!(f, "name \"{glob}\""),
+            DeviceMatcher::HiSyncId(hisyncid) => write!(f, "HiSyncId {hisyncid}"),
+        }
+    }
+}
+
+/// A validation failure, before the file path is attached.
+struct Invalid {
+    field: String,
+    message: String,
+}
+
+impl Invalid {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// A table being converted into typed configuration. Keys are removed as
+/// they are read so that anything left over can be reported as unknown.
+struct Section {
+    table: Table,
+    path: String,
+}
+
+impl Section {
+    fn new(table: Table, path: impl Into<String>) -> Self {
+        Self {
+            table,
+            path: path.into(),
+        }
+    }
+
+    fn field(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn invalid(&self, key: &str, message: impl Into<String>) -> Invalid {
+        Invalid::new(self.field(key), message)
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str, found: &Value) -> Invalid {
+        self.invalid(
+            key,
+            format!("expected {expected}, found {}", found.type_name()),
+        )
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>, Invalid> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(other) => Err(self.wrong_type(key, "a string", &other)),
+        }
+    }
+
+    fn bool(&mut self, key: &str) -> Result<Option<bool>, Invalid> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Boolean(value)) => Ok(Some(value)),
+            Some(other) => Err(self.wrong_type(key, "a boolean", &other)),
+        }
+    }
+
+    /// Reads a number, accepting integers as well.
+    fn float(&mut self, key: &str) -> Result<Option<f64>, Invalid> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Float(value)) if value.is_finite() => Ok(Some(value)),
+            Some(Value::Integer(value)) => Ok(Some(value as f64)),
+            Some(other) => Err(self.wrong_type(key, "a number", &other)),
+        }
+    }
+
+    /// Reads a non-negative integer.
+    fn count(&mut self, key: &str) -> Result<Option<u32>, Invalid> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Integer(value)) => u32::try_from(value)
+                .map(Some)
+                .map_err(|_| self.invalid(key, format!("`{value}` is out of range"))),
+            Some(other) => Err(self.wrong_type(key, "an integer", &other)),
+        }
+    }
+
+    /// Reads a duration given as seconds or as a string with a unit
+    /// (`"500ms"`, `"15s"`, `"2m"`, `"1h"`).
+    fn duration(&mut self, key: &str) -> Result<Option<Duration>, Invalid> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Integer(seconds)) => u64::try_from(seconds)
+                .map(|seconds| Some(Duration::from_secs(seconds)))
+                .map_err(|_| self.invalid(key, "must not be negative")),
+            Some(Value::String(text)) => parse_duration(&text)
+                .map(Some)
+                .ok_or_else(|| self.invalid(key, format!("`{text}` is not a duration"))),
+            Some(other) => Err(self.wrong_type(key, "a duration", &other)),
+        }
+    }
+
+    /// Reads a preset index (1 to 255) or name.
+    fn preset(&mut self, key: &str) -> Result<Option<PresetSelector>, Invalid> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(value) => self.preset_value(key, value).map(Some),
+        }
+    }
+
+    fn preset_value(&self, key: &str, value: Value) -> Result<PresetSelector, Invalid> {
+        match value {
+            Value::Integer(index) => match u8::try_from(index) {
+                Ok(index) if index > 0 => Ok(PresetSelector::Index(index)),
+                _ => Err(self.invalid(key, format!("`{index}` is not a preset index"))),
+            },
+            Value::String(name) => Ok(PresetSelector::Name(name)),
+            other => Err(self.wrong_type(key, "a preset index or name", &other)),
+        }
+    }
+
+    /// Reads a table of player name patterns to presets.
+    fn player_presets(&mut self, key: &str) -> Result<Vec<(Glob, PresetSelector)>, Invalid> {
+        let Some(mut section) = self.table(key)? else {
+            return Ok(Vec::new());
+        };
+
+        std::mem::take(&mut section.table)
+            .into_iter()
+            .map(|(pattern, value)| {
+                let preset = section.preset_value(&pattern, value)?;
+                Ok((player_pattern(&pattern), preset))
+            })
+            .collect()
+    }
+
+    /// Reads MPRIS player name patterns.
+    fn players(&mut self, key: &str) -> Result<Vec<Glob>, Invalid> {
+        Ok(self
+            .strings(key)?
+            .unwrap_or_default()
+            .iter()
+            .map(|pattern| player_pattern(pattern))
+            .collect())
+    }
+
+    fn strings(&mut self, key: &str) -> Result<Option<Vec<String>>, Invalid> {
+        let items = match self.table.remove(key) {
+            None => return Ok(None),
+            Some(Value::Array(items)) => items,
+            Some(other) => return Err(self.wrong_type(key, "an array of strings", &other)),
+        };
+
+        items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| match item {
+                Value::String(value) => Ok(value),
+                other => Err(self.wrong_type(&format!("{key}[{i}]"), "a string", &other)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    fn table(&mut self, key: &str) -> Result<Option<Section>, Invalid> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Table(table)) => Ok(Some(Section::new(table, self.field(key)))),
+            Some(other) => Err(self.wrong_type(key, "a table", &other)),
+        }
+    }
+
+    /// Reads an array of tables (`[[key]]`), or an empty list if absent.
+    fn tables(&mut self, key: &str) -> Result<Vec<Section>, Invalid> {
+        let items = match self.table.remove(key) {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(items)) => items,
+            Some(other) => return Err(self.wrong_type(key, "an array of tables", &other)),
+        };
+
+        items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| match item {
+                Value::Table(table) => Ok(Section::new(table, format!("{}[{i}]", self.field(key)))),
+                other => Err(self.wrong_type(&format!("{key}[{i}]"), "a table", &other)),
+            })
+            .collect()
+    }
+
+    fn finish(self) -> Result<(), Invalid> {
+        match self.table.keys().next() {
+            Some(key) => Err(self.invalid(key, "unknown option")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses `"500ms"`, `"15s"`, `"2m"`, `"1h"` or a plain number of seconds.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok()?;
+
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" | "min" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None,
+    };
+
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        Config::parse(text, Path::new("config.toml")).unwrap()
+    }
+
+    /// The field and message `text` is rejected with.
+    fn invalid(text: &str) -> (String, String) {
+        match Config::parse(text, Path::new("config.toml")) {
+            Err(ConfigError::Invalid { field, message, .. }) => (field, message),
+            other => panic!("{text}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("15s"), Some(Duration::from_secs(15)));
+        assert_eq!(parse_duration("15"), Some(Duration::from_secs(15)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration(" 2 m "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("2min"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+
+        for text in ["", " ", "s", "5d", "-1s", "1.2.3s", "1e3s", "1 h m"] {
+            assert_eq!(parse_duration(text), None, "{text:?}");
+        }
+        // Too long for a `Duration`.
+        assert_eq!(parse_duration("1000000000000000000000h"), None);
+    }
+
+    #[test]
+    fn durations_in_the_file() {
+        let config = parse(
+            "[[device]]\n\
+             name = \"Aid\"\n\
+             idle_timeout = 90\n\
+             min_connected = \"2m\"\n",
+        );
+        let options = &config.devices[0].options;
+        assert_eq!(options.idle_timeout, Duration::from_secs(90));
+        assert_eq!(options.min_connected, Duration::from_secs(120));
+
+        assert_eq!(
+            invalid("[[device]]\nname = \"Aid\"\nidle_timeout = -1\n"),
+            (
+                "device[0].idle_timeout".to_owned(),
+                "must not be negative".to_owned()
+            )
+        );
+        assert_eq!(
+            invalid("[[device]]\nname = \"Aid\"\nidle_timeout = \"soon\"\n").1,
+            "`soon` is not a duration"
+        );
+        assert_eq!(
+            invalid("[[device]]\nname = \"Aid\"\nidle_timeout = true\n").1,
+            "expected a duration, found boolean"
+        );
+    }
+
+    #[test]
+    fn unknown_keys() {
+        for (text, field) in [
+            ("colour = 1\n", "colour"),
+            ("[bluetooth]\nadapter = \"hci0\"\n", "bluetooth.adapter"),
+            ("[playback]\nsource = [\"mpris\"]\n", "playback.source"),
+            ("[volume]\nsync = true\nmute = true\n", "volume.mute"),
+            (
+                "[[device]]\nname = \"Aid\"\n[device.retry]\ndelay = 1\n",
+                "device[0].retry.delay",
+            ),
+            (
+                "[[device]]\nname = \"A\"\n[[device]]\nname = \"B\"\ntimeout = 1\n",
+                "device[1].timeout",
+            ),
+        ] {
+            assert_eq!(
+                invalid(text),
+                (field.to_owned(), "unknown option".to_owned()),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn modes() {
+        for (mode, expected) in [
+            ("on-playback", Mode::OnPlayback),
+            ("always", Mode::Always),
+            ("manual", Mode::Manual),
+            ("never", Mode::Never),
+        ] {
+            let config = parse(&format!("[[device]]\nname = \"Aid\"\nmode = \"{mode}\"\n"));
+            assert_eq!(config.devices[0].options.mode, expected);
+        }
+        assert_eq!(
+            parse("[[device]]\nname = \"Aid\"\n").devices[0]
+                .options
+                .mode,
+            Mode::default()
+        );
+
+        let (field, message) = invalid("[[device]]\nname = \"Aid\"\nmode = \"sometimes\"\n");
+        assert_eq!(field, "device[0].mode");
+        assert!(message.starts_with("unknown mode `sometimes`"), "{message}");
+        assert_eq!(
+            invalid("[[device]]\nname = \"Aid\"\nmode = 1\n").1,
+            "expected a string, found integer"
+        );
+    }
+
+    #[test]
+    fn adapter_selectors() {
+        let address: Address = "00:11:22:33:44:55".parse().unwrap();
+        let config = parse("[bluetooth]\nadapters = [\"hci1\", \"00:11:22:33:44:55\"]\n");
+        assert_eq!(
+            config.bluetooth.adapters,
+            [
+                AdapterSelector::Name("hci1".to_owned()),
+                AdapterSelector::Address(address),
+            ]
+        );
+
+        let [by_name, by_address] = &config.bluetooth.adapters[..] else {
+            panic!("{:?}", config.bluetooth.adapters);
+        };
+        assert!(by_name.matches("hci1", Address::any()));
+        assert!(!by_name.matches("hci0", address));
+        assert!(by_address.matches("hci0", address));
+        assert!(!by_address.matches("hci1", Address::any()));
+
+        // Anything that is not an address names an adapter.
+        assert_eq!(
+            AdapterSelector::from("00:11:22:33:44"),
+            AdapterSelector::Name("00:11:22:33:44".to_owned())
+        );
+        assert!(parse("").bluetooth.adapters.is_empty());
+        assert_eq!(
+            invalid("[bluetooth]\nadapters = [\"hci0\", 1]\n"),
+            (
+                "bluetooth.adapters[1]".to_owned(),
+                "expected a string, found integer".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn discovery() {
+        assert_eq!(parse("").bluetooth.discovery, Discovery::Continuous);
+        assert_eq!(
+            parse("[bluetooth]\ndiscovery = \"bursts\"\n")
+                .bluetooth
+                .discovery,
+            Discovery::Bursts {
+                duration: Discovery::DEFAULT_SCAN_DURATION,
+                interval: Discovery::DEFAULT_SCAN_INTERVAL,
+            }
+        );
+        assert_eq!(
+            parse(
+                "[bluetooth]\ndiscovery = \"bursts\"\nscan_duration = 5\nscan_interval = \"5s\"\n"
+            )
+            .bluetooth
+            .discovery,
+            Discovery::Bursts {
+                duration: Duration::from_secs(5),
+                interval: Duration::from_secs(5),
+            }
+        );
+
+        for (settings, key, expected) in [
+            (
+                "scan_duration = \"0s\"",
+                "scan_duration",
+                "must be longer than zero",
+            ),
+            (
+                "scan_interval = \"0s\"",
+                "scan_interval",
+                "must be longer than zero",
+            ),
+            (
+                "scan_duration = 10\nscan_interval = 9",
+                "scan_interval",
+                "must not be below `scan_duration`",
+            ),
+            // Below the default duration.
+            (
+                "scan_interval = \"5s\"",
+                "scan_interval",
+                "must not be below `scan_duration`",
+            ),
+        ] {
+            let (field, message) = invalid(&format!(
+                "[bluetooth]\ndiscovery = \"bursts\"\n{settings}\n"
+            ));
+            assert_eq!(field, format!("bluetooth.{key}"), "{settings}");
+            assert_eq!(message, expected, "{settings}");
+        }
+
+        assert_eq!(
+            invalid("[bluetooth]\nscan_interval = \"1m\"\n"),
+            (
+                "bluetooth.scan_interval".to_owned(),
+                "only applies with `discovery = \"bursts\"`".to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,691 @@
+/*
+
+Small TOML reader for the configuration file
+
+Covers the subset of TOML that a hand-written config needs: comments,
+bare/quoted/dotted keys, `[tables]`, `[[arrays of tables]]`, basic and
+literal strings, integers, floats, booleans, arrays and inline tables.
+Multi-line strings and date-times are not supported.
+
+*/
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse(input: &str) -> Result<Table, ParseError> {
+    Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    }
+    .document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => self.error(format!("expected `{expected}`, found `{c}`")),
+            None => self.error(format!("expected `{expected}`, found end of file")),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => {
+                    self.bump();
+                }
+                Some('\r') if self.peek_at(1) == Some('\n') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        match self.bump() {
+            None | Some('\n') => Ok(()),
+            Some(c) => self.error(format!("unexpected `{c}` after value")),
+        }
+    }
+
+    fn document(mut self) -> Result<Table, ParseError> {
+        let mut root = Table::new();
+        let mut current: Vec<String> = Vec::new();
+        // Headers seen so far; a table may be created implicitly by one of
+        // its sub-tables and still get its own header, but only once.
+        let mut defined: HashSet<Vec<String>> = HashSet::new();
+        // Inline tables and arrays given as values, which nothing may add to.
+        let mut inline: HashSet<Vec<String>> = HashSet::new();
+
+        loop {
+            self.skip_blank();
+            let Some(c) = self.peek() else {
+                return Ok(root);
+            };
+
+            if c == '[' {
+                self.bump();
+                let is_array = self.peek() == Some('[');
+                if is_array {
+                    self.bump();
+                }
+
+                self.skip_spaces();
+                let path = self.key_path()?;
+                self.skip_spaces();
+                self.expect(']')?;
+                if is_array {
+                    self.expect(']')?;
+                }
+                let line = self.line;
+                self.end_of_line()?;
+
+                check_open(&inline, &path).map_err(|message| ParseError { line, message })?;
+                let (last, parents) = path.split_last().expect("key path is never empty");
+                let parent =
+                    table_at(&mut root, parents).map_err(|message| ParseError { line, message })?;
+
+                if is_array {
+                    let entry = parent
+                        .entry(last.clone())
+                        .or_insert_with(|| Value::Array(Vec::new()));
+                    let Value::Array(array) = entry else {
+                        return Err(ParseError {
+                            line,
+                            message: format!("`{last}` is already defined as a non-array"),
+                        });
+                    };
+                    array.push(Value::Table(Table::new()));
+                    // A new element starts with none of its sub-tables.
+                    defined.retain(|seen| !seen.starts_with(&path));
+                    inline.retain(|seen| !seen.starts_with(&path));
+                } else {
+                    if !defined.insert(path.clone()) {
+                        return Err(ParseError {
+                            line,
+                            message: format!("table `{}` is defined twice", path.join(".")),
+                        });
+                    }
+                    match parent.get(last) {
+                        None => {
+                            parent.insert(last.clone(), Value::Table(Table::new()));
+                        }
+                        Some(Value::Table(_)) => {}
+                        Some(_) => {
+                            return Err(ParseError {
+                                line,
+                                message: format!("`{last}` is already defined as a value"),
+                            });
+                        }
+                    }
+                }
+
+                current = path;
+                continue;
+            }
+
+            let line = self.line;
+            let (key, value) = self.key_value()?;
+            self.end_of_line()?;
+
+            let path = [current.as_slice(), &key].concat();
+            check_open(&inline, &path).map_err(|message| ParseError { line, message })?;
+            if matches!(value, Value::Table(_) | Value::Array(_)) {
+                inline.insert(path);
+            }
+
+            let table =
+                table_at(&mut root, &current).map_err(|message| ParseError { line, message })?;
+            insert_dotted(table, &key, value).map_err(|message| ParseError { line, message })?;
+        }
+    }
+
+    fn key_value(&mut self) -> Result<(Vec<String>, Value), ParseError> {
+        let key = self.key_path()?;
+        self.skip_spaces();
+        self.expect('=')?;
+        self.skip_spaces();
+        let value = self.value()?;
+        Ok((key, value))
+    }
+
+    fn key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = vec![self.key()?];
+        loop {
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                return Ok(path);
+            }
+            self.bump();
+            self.skip_spaces();
+            path.push(self.key()?);
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.bump();
+                }
+                if start == self.pos {
+                    return match self.peek() {
+                        Some(c) => self.error(format!("expected a key, found `{c}`")),
+                        None => self.error("expected a key, found end of file"),
+                    };
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => self.error("expected a value, found end of file"),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(out),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.unicode_escape(4)?,
+                        Some('U') => self.unicode_escape(8)?,
+                        Some(c) => return self.error(format!("invalid escape `\\{c}`")),
+                        None => return self.unterminated(),
+                    };
+                    out.push(escaped);
+                }
+                Some('\n') | None => return self.unterminated(),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    /// Reports an unterminated string on the line it started on, even if
+    /// the terminating newline has already been consumed.
+    fn unterminated<T>(&self) -> Result<T, ParseError> {
+        let consumed_newline = self.pos > 0 && self.chars[self.pos - 1] == '\n';
+        Err(ParseError {
+            line: self.line - usize::from(consumed_newline),
+            message: "unterminated string".to_owned(),
+        })
+    }
+
+    fn unicode_escape(&mut self, len: usize) -> Result<char, ParseError> {
+        let mut digits = String::new();
+        for _ in 0..len {
+            match self.bump() {
+                Some(c) => digits.push(c),
+                None => return self.unterminated(),
+            }
+        }
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .map_or_else(
+                || self.error(format!("invalid unicode escape `{digits}`")),
+                Ok,
+            )
+    }
+
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        self.expect('\'')?;
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(out),
+                Some('\n') | None => return self.unterminated(),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(']') => {}
+                Some(c) => return self.error(format!("expected `,` or `]` in array, found `{c}`")),
+                None => return self.error("unterminated array"),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        let mut inline = HashSet::new();
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_spaces();
+            let line = self.line;
+            let (key, value) = self.key_value()?;
+            check_open(&inline, &key).map_err(|message| ParseError { line, message })?;
+            if matches!(value, Value::Table(_) | Value::Array(_)) {
+                inline.insert(key.clone());
+            }
+            insert_dotted(&mut table, &key, value)
+                .map_err(|message| ParseError { line, message })?;
+            self.skip_spaces();
+            match self.bump() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Table(table)),
+                Some(c) => {
+                    return self
+                        .error(format!("expected `,` or `}}` in inline table, found `{c}`"));
+                }
+                None => return self.error("unterminated inline table"),
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-._".contains(c)) {
+            self.bump();
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+
+        match token.as_str() {
+            "" => match self.peek() {
+                Some(c) => return self.error(format!("expected a value, found `{c}`")),
+                None => return self.error("expected a value, found end of file"),
+            },
+            "true" => return Ok(Value::Boolean(true)),
+            "false" => return Ok(Value::Boolean(false)),
+            _ => {}
+        }
+
+        let digits = token.replace('_', "");
+        let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+            // Unlike `from_str_radix`, TOML takes no sign after the prefix.
+            hex.chars()
+                .all(|c| c.is_ascii_hexdigit())
+                .then(|| i64::from_str_radix(hex, 16).ok())
+                .flatten()
+                .map(Value::Integer)
+        } else if digits.contains(['.', 'e', 'E']) {
+            digits.parse().ok().map(Value::Float)
+        } else {
+            digits.parse().ok().map(Value::Integer)
+        };
+
+        parsed.map_or_else(|| self.error(format!("invalid value `{token}`")), Ok)
+    }
+}
+
+/// Walks `path` from `root`, creating tables as needed. An array of tables
+/// resolves to its most recently added element.
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    let mut table = root;
+    for segment in path {
+        let entry = table
+            .entry(segment.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(inner) => inner,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(inner)) => inner,
+                _ => return Err(format!("`{segment}` is not a table")),
+            },
+            _ => return Err(format!("`{segment}` is not a table")),
+        };
+    }
+    Ok(table)
+}
+
+/// Fails if `path` is or lies within an inline table or array.
+fn check_open(inline: &HashSet<Vec<String>>, path: &[String]) -> Result<(), String> {
+    match (1..=path.len()).find(|&len| inline.contains(&path[..len])) {
+        Some(len) => Err(format!(
+            "`{}` is defined inline and cannot be extended",
+            path[..len].join(".")
+        )),
+        None => Ok(()),
+    }
+}
+
+fn insert_dotted(table: &mut Table, key: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = key.split_last().expect("key path is never empty");
+    let table = table_at(table, parents)?;
+    if table.contains_key(last) {
+        return Err(format!("duplicate key `{last}`"));
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &str) -> ParseError {
+        parse(input).expect_err("input should be rejected")
+    }
+
+    fn string(input: &str) -> String {
+        match parse(&format!("key = {input}\n")).unwrap().remove("key") {
+            Some(Value::String(value)) => value,
+            other => panic!("{input}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn tables_and_arrays_of_tables() {
+        let table = parse(
+            "top = 1\n\
+             [a.b]\n\
+             x = 2\n\
+             [a]\n\
+             y = 3\n\
+             [[device]]\n\
+             name = \"L\"\n\
+             [device.options]\n\
+             mode = \"always\"\n\
+             [[device]]\n\
+             name = \"R\"\n\
+             [device.options]\n\
+             mode = \"never\"\n",
+        )
+        .unwrap();
+
+        let Some(Value::Table(a)) = table.get("a") else {
+            panic!("{table:?}");
+        };
+        assert_eq!(a.get("y"), Some(&Value::Integer(3)));
+        assert!(
+            matches!(a.get("b"), Some(Value::Table(b)) if b.get("x") == Some(&Value::Integer(2)))
+        );
+
+        let Some(Value::Array(devices)) = table.get("device") else {
+            panic!("{table:?}");
+        };
+        assert_eq!(devices.len(), 2);
+    }
+
+    #[test]
+    fn duplicate_tables() {
+        assert_eq!(
+            error("[a]\nx = 1\n[b]\n[a]\ny = 2\n"),
+            ParseError {
+                line: 4,
+                message: "table `a` is defined twice".to_owned()
+            }
+        );
+        assert_eq!(error("[a.b]\n[a.b]\n").line, 2);
+        assert_eq!(error("[[d]]\n[d.o]\n[d.o]\n").line, 3);
+        assert_eq!(
+            error("a = 1\n[a]\n").message,
+            "`a` is already defined as a value"
+        );
+        assert_eq!(
+            error("[a]\n[[a]]\n").message,
+            "`a` is already defined as a non-array"
+        );
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        assert_eq!(
+            error("x = 1\ny = 2\nx = 3\n"),
+            ParseError {
+                line: 3,
+                message: "duplicate key `x`".to_owned()
+            }
+        );
+        assert_eq!(error("[t]\na.b = 1\na.b = 2\n").line, 3);
+        assert_eq!(error("t = { a = 1, a = 2 }\n").message, "duplicate key `a`");
+        assert_eq!(error("a.b = 1\na.b.c = 2\n").message, "`b` is not a table");
+    }
+
+    #[test]
+    fn inline_values_are_closed() {
+        assert_eq!(
+            error("a = { x = 1 }\n[a]\ny = 2\n"),
+            ParseError {
+                line: 2,
+                message: "`a` is defined inline and cannot be extended".to_owned()
+            }
+        );
+        assert_eq!(error("a = { x = 1 }\n[a.b]\n").line, 2);
+        assert_eq!(error("a = { x = 1 }\na.y = 2\n").line, 2);
+        assert_eq!(error("[t]\na = { x = 1 }\n[t.a]\n").line, 3);
+        assert_eq!(
+            error("a = [{ x = 1 }]\n[[a]]\n").message,
+            "`a` is defined inline and cannot be extended"
+        );
+        assert_eq!(
+            error("t = { a = { x = 1 }, a.y = 2 }\n").message,
+            "`a` is defined inline and cannot be extended"
+        );
+
+        // Each element of an array of tables starts afresh.
+        let table = parse("[[d]]\no = { x = 1 }\n[[d]]\n[d.o]\nx = 2\n").unwrap();
+        assert!(matches!(&table["d"], Value::Array(devices) if devices.len() == 2));
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        assert_eq!(string(r#""plain""#), "plain");
+        assert_eq!(
+            string(r#""tab\there\nnew \"quoted\" \\""#),
+            "tab\there\nnew \"quoted\" \\"
+        );
+        assert_eq!(string(r#""\u00e9\U0001F50A""#), "é🔊");
+        assert_eq!(string(r"'C:\no\escapes'"), r"C:\no\escapes");
+        assert_eq!(string(r##""# not a comment""##), "# not a comment");
+
+        assert_eq!(error("key = \"\\q\"\n").message, "invalid escape `\\q`");
+        assert_eq!(
+            error("key = \"\\uD800\"\n").message,
+            "invalid unicode escape `D800`"
+        );
+    }
+
+    #[test]
+    fn values() {
+        let table = parse(
+            "hex = 0xff\n\
+             big = 1_000\n\
+             negative = -5\n\
+             float = 0.5\n\
+             exponent = 1e3\n\
+             yes = true\n\
+             list = [1, 'two', [3], ]\n\
+             inline = { a = 1, b.c = 'd' }\n",
+        )
+        .unwrap();
+
+        assert_eq!(table["hex"], Value::Integer(255));
+        assert_eq!(table["big"], Value::Integer(1000));
+        assert_eq!(table["negative"], Value::Integer(-5));
+        assert_eq!(table["float"], Value::Float(0.5));
+        assert_eq!(table["exponent"], Value::Float(1000.0));
+        assert_eq!(table["yes"], Value::Boolean(true));
+        assert_eq!(
+            table["list"],
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::String("two".to_owned()),
+                Value::Array(vec![Value::Integer(3)]),
+            ])
+        );
+        assert!(matches!(&table["inline"], Value::Table(inline) if inline.len() == 2));
+    }
+
+    #[test]
+    fn comments_and_line_endings() {
+        let table = parse("# header\r\n\r\nx = 1 # trailing\r\n  [t]  # table\r\ny = 2").unwrap();
+        assert_eq!(table["x"], Value::Integer(1));
+        assert!(matches!(&table["t"], Value::Table(t) if t["y"] == Value::Integer(2)));
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(
+            error("a = 1\n\nb = \"open\nc = 2\n"),
+            ParseError {
+                line: 3,
+                message: "unterminated string".to_owned()
+            }
+        );
+        assert_eq!(
+            error("a = 1\r\nb = 2 3\r\n"),
+            ParseError {
+                line: 2,
+                message: "unexpected `3` after value".to_owned()
+            }
+        );
+        assert_eq!(
+            error("a = [\n1,\n2\n3]\n"),
+            ParseError {
+                line: 4,
+                message: "expected `,` or `]` in array, found `3`".to_owned()
+            }
+        );
+        assert_eq!(error("\n\n= 1\n").line, 3);
+        assert_eq!(error("a = \n").message, "expected a value, found `\n`");
+        assert_eq!(error("a = nope\n").message, "invalid value `nope`");
+        assert_eq!(error("a = -0x10\n").message, "invalid value `-0x10`");
+        assert_eq!(error("a = 0x-10\n").message, "invalid value `0x-10`");
+        assert_eq!(error("a = 0x+10\n").message, "invalid value `0x+10`");
+        assert_eq!(error("a = 0x\n").message, "invalid value `0x`");
+        assert_eq!(error("[a\n").message, "expected `]`, found `\n`");
+        assert_eq!(error("a = [1, 2").message, "unterminated array");
+    }
+}
@@ -0,0 +1,241 @@
+/*
+
+Client for the ASHA control plane
+
+Connecting the profile only brings the link up; whether an aid actually
+plays depends on the commands written to AudioControlPoint and the status
+it answers with on AudioStatusPoint. These helpers send commands and
+decode the answers, for `reasha asha` and for the daemon's logs.
+
+*/
+
+use crate::{
+    asha::{self, ASHA_SERVICE_U16, ControlCommand, StatusError},
+    backend::BluetoothDevice,
+};
+use bluer::{Uuid, UuidExt, gatt::WriteOp};
+use futures::{StreamExt, stream::BoxStream};
+use std::{fmt, time::Duration};
+
+/// How long a device may take to answer a command.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum ControlError {
+    Bluetooth(bluer::Error),
+    Rejected(StatusError),
+    /// The device did not answer on AudioStatusPoint.
+    NoStatus,
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::Bluetooth(err) => write!(f, "{}", err),
+            ControlError::Rejected(err) => write!(f, "command rejected: {}", err),
+            ControlError::NoStatus => write!(
+                f,
+                "no answer on AudioStatusPoint within {}s",
+                STATUS_TIMEOUT.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+impl From<bluer::Error> for ControlError {
+    fn from(err: bluer::Error) -> Self {
+        ControlError::Bluetooth(err)
+    }
+}
+
+impl From<StatusError> for ControlError {
+    fn from(err: StatusError) -> Self {
+        ControlError::Rejected(err)
+    }
+}
+
+/// Writes a command and waits for the device to answer it.
+pub async fn send<D: BluetoothDevice>(
+    device: &D,
+    command: ControlCommand,
+) -> Result<(), ControlError> {
+    // Subscribe first so that a quick answer is not missed.
+    let mut statuses = statuses(device).await?;
+
+    device
+        .write_characteristic(
+            Uuid::from_u16(ASHA_SERVICE_U16),
+            asha::AUDIO_CONTROL_POINT_UUID,
+            command.to_bytes(),
+            WriteOp::Request,
+        )
+        .await?;
+
+    match tokio::time::timeout(STATUS_TIMEOUT, statuses.next()).await {
+        Ok(Some(status)) => Ok(status?),
+        _ => Err(ControlError::NoStatus),
+    }
+}
+
+/// Decoded AudioStatusPoint notifications.
+pub async fn statuses<D: BluetoothDevice>(
+    device: &D,
+) -> bluer::Result<BoxStream<'static, Result<(), StatusError>>> {
+    let values = device
+        .notify_characteristic(
+            Uuid::from_u16(ASHA_SERVICE_U16),
+            asha::AUDIO_STATUS_POINT_UUID,
+        )
+        .await?;
+
+    Ok(values.map(|value| asha::parse_status(&value)).boxed())
+}
+
+/// The result of the last command, as the device remembers it.
+pub async fn last_status<D: BluetoothDevice>(device: &D) -> bluer::Result<Result<(), StatusError>> {
+    let value = device
+        .read_characteristic(
+            Uuid::from_u16(ASHA_SERVICE_U16),
+            asha::AUDIO_STATUS_POINT_UUID,
+        )
+        .await?;
+
+    Ok(asha::parse_status(&value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asha::{AudioType, Update},
+        backend::{
+            BluetoothBackend,
+            mock::{Call, MockBackend, MockDevice},
+        },
+    };
+    use bluer::Address;
+    use std::collections::HashMap;
+
+    const ADDRESS: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+
+    const START: ControlCommand = ControlCommand::Start {
+        codec: 1,
+        audio_type: AudioType::Media,
+        volume: -20,
+        other_side_connected: false,
+    };
+
+    /// A connected aid whose AudioControlPoint accepts Start only while
+    /// stopped and Stop only while streaming, like a real one.
+    fn aid(backend: &MockBackend) {
+        backend.add_device(MockDevice {
+            address: ADDRESS,
+            connected: true,
+            gatt_services: vec![Uuid::from_u16(ASHA_SERVICE_U16)],
+            characteristics: HashMap::from([
+                (asha::AUDIO_CONTROL_POINT_UUID, Vec::new()),
+                (asha::AUDIO_STATUS_POINT_UUID, vec![0]),
+            ]),
+            ..MockDevice::default()
+        });
+
+        let mut streaming = false;
+        backend.respond_to_writes(ADDRESS, asha::AUDIO_CONTROL_POINT_UUID, move |value| {
+            let status: i8 = match ControlCommand::parse(value) {
+                Some(ControlCommand::Start { .. }) if !streaming => {
+                    streaming = true;
+                    0
+                }
+                Some(ControlCommand::Stop) if streaming => {
+                    streaming = false;
+                    0
+                }
+                Some(ControlCommand::Status(_)) => 0,
+                Some(_) => -2,
+                None => -1,
+            };
+            vec![(asha::AUDIO_STATUS_POINT_UUID, vec![status as u8])]
+        });
+    }
+
+    #[tokio::test]
+    async fn start_status_stop() {
+        let backend = MockBackend::new();
+        aid(&backend);
+        let device = backend.device(ADDRESS).unwrap();
+
+        send(&device, START).await.unwrap();
+        send(&device, ControlCommand::Status(Update::OtherSideConnected))
+            .await
+            .unwrap();
+        send(&device, ControlCommand::Stop).await.unwrap();
+
+        let written: Vec<_> = backend
+            .calls(ADDRESS)
+            .into_iter()
+            .map(|call| match call {
+                Call::WriteCharacteristic(uuid, value) => {
+                    assert_eq!(uuid, asha::AUDIO_CONTROL_POINT_UUID);
+                    value
+                }
+                other => panic!("unexpected call {other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            written,
+            [vec![1, 1, 3, (-20i8) as u8, 0], vec![3, 1], vec![2]]
+        );
+        assert_eq!(last_status(&device).await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn out_of_order_commands_are_rejected() {
+        let backend = MockBackend::new();
+        aid(&backend);
+        let device = backend.device(ADDRESS).unwrap();
+
+        assert!(matches!(
+            send(&device, ControlCommand::Stop).await,
+            Err(ControlError::Rejected(StatusError::IllegalParameters))
+        ));
+        assert_eq!(
+            last_status(&device).await.unwrap(),
+            Err(StatusError::IllegalParameters)
+        );
+
+        send(&device, START).await.unwrap();
+        assert!(matches!(
+            send(&device, START).await,
+            Err(ControlError::Rejected(StatusError::IllegalParameters))
+        ));
+        send(&device, ControlCommand::Stop).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_device() {
+        let backend = MockBackend::new();
+        aid(&backend);
+        backend.respond_to_writes(ADDRESS, asha::AUDIO_CONTROL_POINT_UUID, |_| Vec::new());
+        let device = backend.device(ADDRESS).unwrap();
+
+        assert!(matches!(
+            send(&device, START).await,
+            Err(ControlError::NoStatus)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unresolved_services() {
+        let backend = MockBackend::new();
+        aid(&backend);
+        backend.drop_link(ADDRESS);
+        let device = backend.device(ADDRESS).unwrap();
+
+        assert!(matches!(
+            send(&device, START).await,
+            Err(ControlError::Bluetooth(_))
+        ));
+    }
+}
@@ -0,0 +1,317 @@
+/*
+
+Operations on a single device
+
+Shared by the daemon and the one-shot commands: working out who a device
+is, whether it speaks ASHA or HAS, and bringing its link up or down.
+
+*/
+
+use crate::{
+    asha::{self, ASHA_SERVICE_U16, HiSyncId, PropertiesError, ReadOnlyProperties},
+    backend::{BluetoothBackend, BluetoothDevice, DeviceEvent},
+    capability::{self, Capability, Evidence, Protocol, Rejection, Services},
+    config::DeviceIdentity,
+    device_log,
+    glob::Glob,
+    has::{HAS_SERVICE_U16, PACS_SERVICE_U16},
+    logging::DeviceSpan,
+};
+use bluer::{Address, Uuid, UuidExt, gatt::WriteOp};
+use futures::StreamExt;
+use log::Level;
+use std::{fmt, time::Duration};
+
+const SERVICES_RESOLVED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a device tells about itself without connecting to it.
+#[derive(Clone, Debug)]
+pub struct Advertisement {
+    pub identity: DeviceIdentity,
+    /// Service data advertised for the ASHA service, if any.
+    pub asha_service_data: Option<Vec<u8>>,
+}
+
+impl Advertisement {
+    pub fn display_name(&self) -> &str {
+        self.identity.name.as_deref().unwrap_or("Unknown")
+    }
+}
+
+pub async fn advertisement<D: BluetoothDevice>(device: &D) -> Advertisement {
+    let asha_service_data = device
+        .service_data()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|mut data| data.remove(&Uuid::from_u16(ASHA_SERVICE_U16)));
+
+    let truncated_hisyncid = asha_service_data
+        .as_deref()
+        .and_then(asha::truncated_hisyncid)
+        .and_then(|truncated| truncated.try_into().ok());
+
+    Advertisement {
+        identity: DeviceIdentity {
+            address: device.address(),
+            name: device.name().await.ok().flatten(),
+            hisyncid: None,
+            truncated_hisyncid,
+        },
+        asha_service_data,
+    }
+}
+
+/// Hearing aid services in the advertisement or the service list.
+pub async fn advertised_services<D: BluetoothDevice>(
+    device: &D,
+    advertisement: &Advertisement,
+) -> Services {
+    let uuids = device.uuids().await.ok().flatten().unwrap_or_default();
+    let service_data = device
+        .service_data()
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let advertised = |service: u16| {
+        uuids.contains(&Uuid::from_u16(service))
+            || service_data.contains_key(&Uuid::from_u16(service))
+    };
+
+    Services {
+        asha: advertisement.asha_service_data.is_some() || advertised(ASHA_SERVICE_U16),
+        has: advertised(HAS_SERVICE_U16),
+    }
+}
+
+pub async fn check_capability<D: BluetoothDevice>(
+    device: &D,
+    advertisement: &Advertisement,
+    force: bool,
+) -> Result<Capability, Rejection> {
+    let mut evidence = Evidence {
+        advertised: advertised_services(device, advertisement).await,
+        gatt_services: gatt_services(device).await,
+        services_resolved: device.is_services_resolved().await.unwrap_or(false),
+    };
+
+    let result = capability::check(&evidence, force);
+
+    if result != Err(Rejection::ServicesUnresolved) || !device.is_connected().await.unwrap_or(false)
+    {
+        return result;
+    }
+
+    // Connected but still resolving; give BlueZ a chance to finish before
+    // judging the GATT database.
+    if wait_for_services_resolved(device).await {
+        evidence.services_resolved = true;
+        evidence.gatt_services = gatt_services(device).await;
+    }
+
+    capability::check(&evidence, force)
+}
+
+async fn gatt_services<D: BluetoothDevice>(device: &D) -> Services {
+    let services = device.gatt_services().await.unwrap_or_default();
+    let exposed = |service: u16| services.contains(&Uuid::from_u16(service));
+
+    Services {
+        asha: exposed(ASHA_SERVICE_U16),
+        has: exposed(HAS_SERVICE_U16),
+    }
+}
+
+async fn wait_for_services_resolved<D: BluetoothDevice>(device: &D) -> bool {
+    let Ok(mut events) = device.events().await else {
+        return false;
+    };
+
+    let resolved = async {
+        while let Some(event) = events.next().await {
+            if event == DeviceEvent::ServicesResolved(true) {
+                return true;
+            }
+        }
+        false
+    };
+
+    tokio::time::timeout(SERVICES_RESOLVED_TIMEOUT, resolved)
+        .await
+        .unwrap_or(false)
+}
+
+/// Connects the profile audio goes over, trusting the device first if
+/// asked to.
+pub async fn connect<D: BluetoothDevice>(
+    device: &D,
+    span: &DeviceSpan,
+    protocol: Protocol,
+    auto_trust: bool,
+) -> bluer::Result<()> {
+    if auto_trust && !device.is_trusted().await.unwrap_or(true) {
+        match device.set_trusted(true).await {
+            Ok(_) => device_log!(span, Level::Info, "Trusted {}.", span.name),
+            Err(err) => device_log!(span, Level::Warn, "Could not trust {}: {}", span.name, err),
+        }
+    }
+
+    let profile = match protocol {
+        Protocol::Asha => ASHA_SERVICE_U16,
+        Protocol::Has => PACS_SERVICE_U16,
+    };
+    device.connect_profile(Uuid::from_u16(profile)).await
+}
+
+/// Sets the stream volume, from [`asha::MUTED`] to 0 dB.
+pub async fn set_volume<D: BluetoothDevice>(device: &D, volume: i8) -> bluer::Result<()> {
+    device
+        .write_characteristic(
+            Uuid::from_u16(ASHA_SERVICE_U16),
+            asha::VOLUME_UUID,
+            vec![volume as u8],
+            WriteOp::Command,
+        )
+        .await
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Bluetooth(bluer::Error),
+    Properties(PropertiesError),
+    /// LE_PSM_OUT is shorter than two bytes.
+    InvalidPsm(usize),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Bluetooth(err) => write!(f, "{}", err),
+            ReadError::Properties(err) => write!(f, "{}", err),
+            ReadError::InvalidPsm(length) => {
+                write!(f, "LE_PSM_OUT is {} bytes long, expected 2", length)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Reads and parses the ReadOnlyProperties characteristic.
+pub async fn read_properties<D: BluetoothDevice>(
+    device: &D,
+) -> Result<ReadOnlyProperties, ReadError> {
+    let bytes = device
+        .read_characteristic(
+            Uuid::from_u16(ASHA_SERVICE_U16),
+            asha::READ_ONLY_PROPERTIES_UUID,
+        )
+        .await
+        .map_err(ReadError::Bluetooth)?;
+
+    ReadOnlyProperties::parse(&bytes).map_err(ReadError::Properties)
+}
+
+/// Reads the PSM the device listens on for audio.
+pub async fn read_psm<D: BluetoothDevice>(device: &D) -> Result<u16, ReadError> {
+    let bytes = device
+        .read_characteristic(Uuid::from_u16(ASHA_SERVICE_U16), asha::LE_PSM_OUT_UUID)
+        .await
+        .map_err(ReadError::Bluetooth)?;
+
+    asha::parse_psm(&bytes).ok_or(ReadError::InvalidPsm(bytes.len()))
+}
+
+/// Finds the other connected member of the set with `hisyncid`.
+pub async fn partner<B: BluetoothBackend>(
+    backend: &B,
+    device: &B::Device,
+    hisyncid: HiSyncId,
+) -> bluer::Result<Option<B::Device>> {
+    for address in backend.devices().await? {
+        if address == device.address() {
+            continue;
+        }
+
+        let other = backend.device(address)?;
+        if !other.is_connected().await.unwrap_or(false) {
+            continue;
+        }
+
+        // Devices without ASHA fail to read, which is fine.
+        if let Ok(properties) = read_properties(&other).await
+            && properties.hisyncid == hisyncid
+        {
+            return Ok(Some(other));
+        }
+    }
+
+    Ok(None)
+}
+
+#[derive(Debug)]
+pub enum LookupError {
+    Bluetooth(bluer::Error),
+    NotFound(String),
+    Ambiguous(String, Vec<Address>),
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupError::Bluetooth(err) => write!(f, "{}", err),
+            LookupError::NotFound(query) => write!(f, "no known device matches `{}`", query),
+            LookupError::Ambiguous(query, addresses) => {
+                write!(f, "`{}` matches several devices:", query)?;
+                for address in addresses {
+                    write!(f, " {}", address)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+impl From<bluer::Error> for LookupError {
+    fn from(err: bluer::Error) -> Self {
+        LookupError::Bluetooth(err)
+    }
+}
+
+/// Finds a known device by address or by name. Names may be globs but must
+/// select exactly one device.
+pub async fn lookup<B: BluetoothBackend>(
+    backend: &B,
+    query: &str,
+) -> Result<B::Device, LookupError> {
+    let glob = Glob::new(query);
+    let mut found = Vec::new();
+
+    for address in backend.devices().await? {
+        let matches = match query.parse::<Address>() {
+            Ok(wanted) => address == wanted,
+            Err(_) => {
+                let device = backend.device(address)?;
+                device
+                    .name()
+                    .await
+                    .ok()
+                    .flatten()
+                    .is_some_and(|name| glob.is_match(&name))
+            }
+        };
+
+        if matches {
+            found.push(address);
+        }
+    }
+
+    match found.as_slice() {
+        [] => Err(LookupError::NotFound(query.to_owned())),
+        [address] => Ok(backend.device(*address)?),
+        _ => Err(LookupError::Ambiguous(query.to_owned(), found)),
+    }
+}
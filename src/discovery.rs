@@ -0,0 +1,158 @@
+/*
+
+Discovery in bursts
+
+With `discovery = "bursts"` the adapters do not scan all the time. Devices
+BlueZ already knows, bonded hearing aids among them, are managed from the
+start and connected without scanning for them first. Scans run for
+`scan_duration` at startup and every `scan_interval` after that, to find
+new devices and hear known ones advertise.
+
+A device about to be connected that was not heard within `scan_interval`
+asks for a scan right away and waits for it to end before connecting. A
+request is kept until a scan starts, even one made while another scan
+runs, but the next scan never starts sooner than `scan_duration` after
+the last one ended, so that the controller scans at most half the time.
+
+*/
+
+use crate::backend::BluetoothBackend;
+use log::{debug, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{Notify, watch},
+    time::Instant,
+};
+
+/// Scans started and ended so far.
+#[derive(Clone, Copy, Debug, Default)]
+struct Progress {
+    started: u64,
+    ended: u64,
+}
+
+/// Runs the scans of one backend and takes requests for early ones.
+#[derive(Clone)]
+pub struct Bursts {
+    duration: Duration,
+    interval: Duration,
+    requested: Arc<Notify>,
+    progress: Arc<watch::Sender<Progress>>,
+}
+
+impl Bursts {
+    pub fn new(duration: Duration, interval: Duration) -> Self {
+        Self {
+            duration,
+            interval,
+            requested: Arc::default(),
+            progress: Arc::new(watch::Sender::default()),
+        }
+    }
+
+    /// Whether a device last heard at `seen` may be out of range.
+    pub fn is_stale(&self, seen: Option<Instant>) -> bool {
+        seen.is_none_or(|seen| seen.elapsed() >= self.interval)
+    }
+
+    /// Asks for a scan and waits until one started since has ended. Gives
+    /// up after the scan under way, the pause after it and the one asked
+    /// for could all have run, and tells whether it ended by then.
+    pub async fn scan_now(&self) -> bool {
+        let mut progress = self.progress.subscribe();
+        let wanted = progress.borrow_and_update().started + 1;
+        self.requested.notify_one();
+
+        tokio::time::timeout(
+            self.duration * 4,
+            progress.wait_for(|progress| progress.ended >= wanted),
+        )
+        .await
+        .is_ok_and(|ended| ended.is_ok())
+    }
+
+    /// Scans in bursts until dropped.
+    pub async fn run<B: BluetoothBackend>(&self, backend: B) {
+        loop {
+            self.progress.send_modify(|progress| progress.started += 1);
+            debug!("Scanning on {} for {:?}.", backend.name(), self.duration);
+            if let Err(err) = backend.scan(self.duration).await {
+                warn!("Could not scan on {}: {}", backend.name(), err);
+            }
+            self.progress.send_modify(|progress| progress.ended += 1);
+
+            let ended = Instant::now();
+            tokio::select! {
+                () = tokio::time::sleep_until(ended + self.interval) => {}
+                () = async {
+                    self.requested.notified().await;
+                    tokio::time::sleep_until(ended + self.duration).await;
+                } => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+
+    const DURATION: Duration = Duration::from_millis(100);
+
+    /// Bursts of `DURATION` that would otherwise not come again for long.
+    /// The tests run on a paused clock, so their sleeps take no real time.
+    fn start(backend: &MockBackend) -> (Bursts, tokio::task::JoinHandle<()>) {
+        let bursts = Bursts::new(DURATION, Duration::from_secs(60));
+        let running = {
+            let bursts = bursts.clone();
+            let backend = backend.clone();
+            tokio::spawn(async move { bursts.run(backend).await })
+        };
+        (bursts, running)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_a_scan_started_after_asking() {
+        let backend = MockBackend::new();
+        let (bursts, running) = start(&backend);
+        tokio::time::sleep(DURATION / 2).await;
+        assert_eq!(backend.scans(), 1);
+
+        // Asked while the first scan runs, so only the next one counts.
+        let asked = Instant::now();
+        assert!(bursts.scan_now().await);
+        assert_eq!(backend.scans(), 2);
+        // It ended, waited `DURATION` and the next one ran.
+        assert_eq!(asked.elapsed(), DURATION / 2 + DURATION * 2);
+
+        running.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_requests_made_while_scanning() {
+        let backend = MockBackend::new();
+        let (bursts, running) = start(&backend);
+        tokio::time::sleep(DURATION / 2).await;
+
+        let waiting = tokio::spawn({
+            let bursts = bursts.clone();
+            async move { bursts.scan_now().await }
+        });
+        tokio::time::sleep(DURATION * 4).await;
+        assert_eq!(backend.scans(), 2);
+        assert!(waiting.await.unwrap());
+
+        // Nothing more was asked for.
+        tokio::time::sleep(DURATION * 3).await;
+        assert_eq!(backend.scans(), 2);
+
+        running.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_without_scans() {
+        let bursts = Bursts::new(DURATION, Duration::from_secs(60));
+        assert!(!bursts.scan_now().await);
+    }
+}
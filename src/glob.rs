@@ -0,0 +1,64 @@
+/*
+
+Minimal shell-style glob matching
+
+Supports `*` (any run of characters, including none) and `?` (exactly
+one character). Everything else matches literally.
+
+*/
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Glob {
+    pattern: Vec<char>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.chars().collect(),
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+
+        let (mut p, mut t) = (0, 0);
+        let mut star: Option<(usize, usize)> = None;
+
+        while t < text.len() {
+            match self.pattern.get(p) {
+                Some('*') => {
+                    star = Some((p, t));
+                    p += 1;
+                }
+                Some('?') => {
+                    p += 1;
+                    t += 1;
+                }
+                Some(c) if *c == text[t] => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => {
+                    // Backtrack: let the last `*` swallow one more character.
+                    let Some((star_p, star_t)) = star else {
+                        return false;
+                    };
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+            }
+        }
+
+        self.pattern[p..].iter().all(|c| *c == '*')
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern.iter().collect::<String>())
+    }
+}
@@ -0,0 +1,232 @@
+/*
+
+Hearing Access Service (HAS) constants and types
+
+LE Audio hearing aids expose HAS (0x1854) instead of, or next to, ASHA.
+Audio goes over LE Audio (BAP), which BlueZ connects through the Published
+Audio Capabilities profile; HAS itself carries the hearing aid type and the
+presets, the aid's programs such as "Universal" or "Restaurant".
+
+Presets are read and selected through the Hearing Aid Preset Control
+Point. The central writes a request and the aid answers with indications
+on the same characteristic:
+
+    opcode  name                    parameters
+    1       Read Presets Request    start index, number of presets
+    2       Read Preset Response    is last, index, properties, name
+    3       Preset Changed          change id, is last, change parameters
+    5       Set Active Preset       index
+    8       Set Active Preset       index, passed on to the other side
+            (synchronized locally)
+
+*/
+
+use std::fmt;
+
+pub const HAS_SERVICE_U16: u16 = 0x1854;
+
+/// Published Audio Capabilities, the profile BlueZ connects LE Audio
+/// devices with.
+pub const PACS_SERVICE_U16: u16 = 0x1850;
+
+pub const HEARING_AID_FEATURES_U16: u16 = 0x2bda;
+pub const PRESET_CONTROL_POINT_U16: u16 = 0x2bdb;
+pub const ACTIVE_PRESET_INDEX_U16: u16 = 0x2bdc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HearingAidType {
+    Binaural,
+    Monaural,
+    Banded,
+    Reserved,
+}
+
+impl fmt::Display for HearingAidType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HearingAidType::Binaural => write!(f, "binaural"),
+            HearingAidType::Monaural => write!(f, "monaural"),
+            HearingAidType::Banded => write!(f, "banded"),
+            HearingAidType::Reserved => write!(f, "unknown type"),
+        }
+    }
+}
+
+/// The Hearing Aid Features characteristic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Features(pub u8);
+
+impl Features {
+    pub fn hearing_aid_type(self) -> HearingAidType {
+        match self.0 & 0x03 {
+            0 => HearingAidType::Binaural,
+            1 => HearingAidType::Monaural,
+            2 => HearingAidType::Banded,
+            _ => HearingAidType::Reserved,
+        }
+    }
+
+    /// Whether the aid passes a preset change on to the other side.
+    pub fn preset_sync(self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    /// Whether the two sides of a set have different preset lists.
+    pub fn independent_presets(self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    /// Whether presets come and go while connected.
+    pub fn dynamic_presets(self) -> bool {
+        self.0 & 0x10 != 0
+    }
+
+    pub fn writable_presets(self) -> bool {
+        self.0 & 0x20 != 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preset {
+    /// 1 to 255, unique on the device.
+    pub index: u8,
+    pub name: String,
+    /// Whether the name can be changed.
+    pub writable: bool,
+    /// Whether the preset can be selected right now.
+    pub available: bool,
+}
+
+impl Preset {
+    fn parse(bytes: &[u8]) -> Result<Preset, MessageError> {
+        let [index, properties, ref name @ ..] = *bytes else {
+            return Err(MessageError::TooShort(bytes.len()));
+        };
+
+        Ok(Preset {
+            index,
+            name: String::from_utf8_lossy(name).into_owned(),
+            writable: properties & 0x01 != 0,
+            available: properties & 0x02 != 0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresetCommand {
+    ReadPresets {
+        start_index: u8,
+        count: u8,
+    },
+    SetActive(u8),
+    /// Sets the preset on this aid, which passes it on to the other side.
+    SetActiveSynced(u8),
+}
+
+impl PresetCommand {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            PresetCommand::ReadPresets { start_index, count } => vec![1, start_index, count],
+            PresetCommand::SetActive(index) => vec![5, index],
+            PresetCommand::SetActiveSynced(index) => vec![8, index],
+        }
+    }
+}
+
+impl fmt::Display for PresetCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetCommand::ReadPresets { start_index, count } => {
+                write!(f, "Read Presets ({count} from {start_index})")
+            }
+            PresetCommand::SetActive(index) => write!(f, "Set Active Preset ({index})"),
+            PresetCommand::SetActiveSynced(index) => {
+                write!(f, "Set Active Preset, synchronized ({index})")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresetChange {
+    /// A preset was added or changed; `previous_index` is the preset before
+    /// it, so that the list stays ordered.
+    Updated {
+        previous_index: u8,
+        preset: Preset,
+    },
+    Deleted(u8),
+    Available(u8),
+    Unavailable(u8),
+}
+
+/// An indication of the Hearing Aid Preset Control Point.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresetMessage {
+    ReadResponse { last: bool, preset: Preset },
+    Changed { last: bool, change: PresetChange },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageError {
+    TooShort(usize),
+    UnknownOpcode(u8),
+    UnknownChange(u8),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::TooShort(length) => {
+                write!(
+                    f,
+                    "preset control point message of {length} bytes is too short"
+                )
+            }
+            MessageError::UnknownOpcode(opcode) => {
+                write!(f, "unknown preset control point opcode {opcode}")
+            }
+            MessageError::UnknownChange(change) => write!(f, "unknown preset change id {change}"),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl PresetMessage {
+    pub fn parse(bytes: &[u8]) -> Result<PresetMessage, MessageError> {
+        let too_short = || MessageError::TooShort(bytes.len());
+
+        match *bytes {
+            [2, last, ref preset @ ..] => Ok(PresetMessage::ReadResponse {
+                last: last != 0,
+                preset: Preset::parse(preset).map_err(|_| too_short())?,
+            }),
+            [3, change, last, ref parameters @ ..] => {
+                let index = || parameters.first().copied().ok_or_else(too_short);
+                let change = match change {
+                    0 => {
+                        let [previous_index, ref preset @ ..] = *parameters else {
+                            return Err(too_short());
+                        };
+                        PresetChange::Updated {
+                            previous_index,
+                            preset: Preset::parse(preset).map_err(|_| too_short())?,
+                        }
+                    }
+                    1 => PresetChange::Deleted(index()?),
+                    2 => PresetChange::Available(index()?),
+                    3 => PresetChange::Unavailable(index()?),
+                    change => return Err(MessageError::UnknownChange(change)),
+                };
+
+                Ok(PresetMessage::Changed {
+                    last: last != 0,
+                    change,
+                })
+            }
+            [2 | 3, ..] | [] => Err(too_short()),
+            [opcode, ..] => Err(MessageError::UnknownOpcode(opcode)),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+pub mod asha;
+pub mod backend;
+pub mod binaural;
+pub mod capability;
+pub mod cli;
+pub mod commands;
+pub mod config;
+pub mod control;
+pub mod device;
+pub mod discovery;
+pub mod g722;
+pub mod glob;
+pub mod has;
+pub mod logging;
+pub mod modes;
+pub mod playback;
+pub mod preset;
+pub mod service;
+pub mod state;
+pub mod status;
+pub mod stream;
+pub mod supervisor;
+pub mod volume;
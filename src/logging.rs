@@ -0,0 +1,307 @@
+/*
+
+Logging to stderr or the systemd journal
+
+Messages go through the `log` facade. Per-device messages carry the
+device's address and name as key-values (see `DeviceSpan`); on stderr they
+are appended as `key=value`, in the journal they become fields such as
+`DEVICE_ADDRESS` that `journalctl` can filter on.
+
+The journal is written through its native datagram socket, so no
+libsystemd is needed. By default it is used whenever stderr is already
+connected to the journal, i.e. when running as a systemd service.
+
+*/
+
+use bluer::Address;
+use log::{
+    Level, LevelFilter, Log, Metadata, Record,
+    kv::{self, Key, Source, Value, VisitSource},
+};
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    os::unix::{fs::MetadataExt, net::UnixDatagram},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "reasha";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Target {
+    /// The journal when running under systemd, stderr otherwise.
+    #[default]
+    Auto,
+    Stderr,
+    Journal,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Target::Auto),
+            "stderr" => Ok(Target::Stderr),
+            "journal" => Ok(Target::Journal),
+            other => Err(format!(
+                "unknown log target `{other}`, expected `auto`, `stderr` or `journal`"
+            )),
+        }
+    }
+}
+
+/// Parses `off`, `error`, `warn`, `info`, `debug` or `trace`.
+pub fn parse_level(text: &str) -> Result<LevelFilter, String> {
+    text.parse()
+        .map_err(|_| format!("unknown log level `{text}`"))
+}
+
+enum Output {
+    Stderr,
+    Journal(UnixDatagram),
+}
+
+struct Logger {
+    output: Mutex<Output>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Installs the logger. Later calls only change the level and target.
+pub fn init(level: LevelFilter, target: Target) {
+    let logger = LOGGER.get_or_init(|| Logger {
+        output: Mutex::new(Output::Stderr),
+    });
+
+    let _ = log::set_logger(logger);
+    configure(level, target);
+}
+
+/// Changes the level and target of the installed logger.
+pub fn configure(level: LevelFilter, target: Target) {
+    log::set_max_level(level);
+
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+
+    let output = match target {
+        Target::Stderr => Output::Stderr,
+        Target::Journal => match journal() {
+            Ok(socket) => Output::Journal(socket),
+            Err(err) => {
+                eprintln!(
+                    "Unable to open the systemd journal, logging to stderr: {}",
+                    err
+                );
+                Output::Stderr
+            }
+        },
+        Target::Auto => match stderr_is_journal().then(journal) {
+            Some(Ok(socket)) => Output::Journal(socket),
+            _ => Output::Stderr,
+        },
+    };
+
+    *logger.output.lock().expect("logger poisoned") = output;
+}
+
+fn journal() -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(JOURNAL_SOCKET)?;
+    Ok(socket)
+}
+
+/// systemd sets `JOURNAL_STREAM` to the device and inode of the stream it
+/// connects a service's stdout and stderr to.
+fn stderr_is_journal() -> bool {
+    let Some(stream) = env::var_os("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Ok(stderr) = fs::metadata("/proc/self/fd/2") else {
+        return false;
+    };
+
+    stream.to_string_lossy() == format!("{}:{}", stderr.dev(), stderr.ino())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let output = self.output.lock().expect("logger poisoned");
+
+        if let Output::Journal(socket) = &*output
+            && socket.send(&journal_entry(record)).is_ok()
+        {
+            return;
+        }
+
+        // Also the fallback when the journal refuses an entry.
+        let mut line = format!("{:<5} {}", record.level(), record.args());
+        let _ = record.key_values().visit(&mut StderrFields(&mut line));
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+struct StderrFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for StderrFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        if value.contains(char::is_whitespace) || value.is_empty() {
+            self.0.push_str(&format!(" {key}={value:?}"));
+        } else {
+            self.0.push_str(&format!(" {key}={value}"));
+        }
+        Ok(())
+    }
+}
+
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Serializes a record in the journal's native protocol.
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let mut entry = Vec::new();
+
+    journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+    journal_field(
+        &mut entry,
+        "PRIORITY",
+        &priority(record.level()).to_string(),
+    );
+    journal_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    journal_field(&mut entry, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        journal_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        journal_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+
+    let _ = record.key_values().visit(&mut JournalFields(&mut entry));
+
+    entry
+}
+
+struct JournalFields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for JournalFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let name: String = key
+            .as_str()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' | '0'..='9' => c,
+                _ => '_',
+            })
+            .collect();
+
+        journal_field(self.0, name.trim_start_matches('_'), &value.to_string());
+        Ok(())
+    }
+}
+
+fn journal_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+
+    if value.contains('\n') {
+        // Multi-line values are sent with an explicit length.
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// The device a message is about, attached to it as key-values.
+#[derive(Clone, Debug)]
+pub struct DeviceSpan {
+    pub address: Address,
+    pub name: String,
+}
+
+impl DeviceSpan {
+    pub fn new(address: Address, name: impl Into<String>) -> Self {
+        Self {
+            address,
+            name: name.into(),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn log(
+        &self,
+        level: Level,
+        target: &str,
+        file: &'static str,
+        line: u32,
+        args: fmt::Arguments,
+    ) {
+        if level > log::max_level() {
+            return;
+        }
+
+        log::logger().log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .file_static(Some(file))
+                .line(Some(line))
+                .args(args)
+                .key_values(self)
+                .build(),
+        );
+    }
+}
+
+impl Source for DeviceSpan {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        visitor.visit_pair(
+            Key::from_str("device_address"),
+            Value::from_display(&self.address),
+        )?;
+        visitor.visit_pair(
+            Key::from_str("device_name"),
+            Value::from(self.name.as_str()),
+        )
+    }
+}
+
+/// Logs a message about the device of a [`DeviceSpan`]:
+/// `device_log!(span, Level::Info, "connected")`.
+#[macro_export]
+macro_rules! device_log {
+    ($span:expr, $level:expr, $($arg:tt)+) => {
+        $crate::logging::DeviceSpan::log(
+            &$span,
+            $level,
+            module_path!(),
+            file!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
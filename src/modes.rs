@@ -0,0 +1,91 @@
+/*
+
+Device modes kept across restarts
+
+Modes set at runtime are saved to `$XDG_STATE_HOME/reasha/modes`, falling
+back to `~/.local/state/reasha/modes`, one `ADDRESS MODE` line per device.
+When the daemon starts they take precedence over the `mode` in the
+configuration; deleting a line returns that device to its configured mode.
+
+*/
+
+use crate::state::Mode;
+use bluer::Address;
+use log::warn;
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+const FILE_NAME: &str = "modes";
+
+pub type Modes = BTreeMap<Address, Mode>;
+
+/// The file modes are kept in, or `None` without a home directory.
+pub fn path() -> Option<PathBuf> {
+    let state_dir = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+
+    Some(state_dir.join("reasha").join(FILE_NAME))
+}
+
+/// Reads the modes saved in `path`. A missing file holds no modes, and
+/// lines that cannot be parsed are skipped with a warning.
+pub fn load(path: &Path) -> io::Result<Modes> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Modes::new()),
+        Err(err) => return Err(err),
+    };
+
+    Ok(text
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| match parse_line(line) {
+            Ok(entry) => entry,
+            Err(message) => {
+                warn!("{}:{}: {}", path.display(), i + 1, message);
+                None
+            }
+        })
+        .collect())
+}
+
+fn parse_line(line: &str) -> Result<Option<(Address, Mode)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let Some((address, mode)) = line.split_once(char::is_whitespace) else {
+        return Err(format!("expected `ADDRESS MODE`, found `{line}`"));
+    };
+    let address = address
+        .parse()
+        .map_err(|_| format!("`{address}` is not a Bluetooth address"))?;
+
+    Ok(Some((address, mode.trim().parse()?)))
+}
+
+/// Replaces the contents of `path` with `modes`, creating its directory if
+/// needed. The file is written next to it first and renamed into place, so
+/// that it is never left half written.
+pub fn save(path: &Path, modes: &Modes) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut text = String::from("# Device modes set at runtime by reASHA.\n");
+    for (address, mode) in modes {
+        text.push_str(&format!("{address} {mode}\n"));
+    }
+
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, text)?;
+    fs::rename(&temporary, path)
+}
@@ -0,0 +1,97 @@
+/*
+
+Playback monitoring
+
+Each `PlaybackSource` reports whether it currently has audio; the sources
+configured under `[playback]` are combined into the single "audio wanted"
+signal that drives the device supervisor.
+
+*/
+
+pub mod mpris;
+pub mod pulse;
+
+use crate::config::{Combine, PlaybackConfig, SourceKind};
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use tokio::sync::{mpsc, watch};
+
+pub type SourceError = Box<dyn std::error::Error + Send + Sync>;
+
+pub trait PlaybackSource: Send {
+    fn name(&self) -> &'static str;
+
+    /// Starts watching. The receiver holds whether this source currently
+    /// has audio and is updated whenever that changes.
+    fn start(self: Box<Self>) -> BoxFuture<'static, Result<watch::Receiver<bool>, SourceError>>;
+}
+
+pub fn sources(config: &PlaybackConfig) -> Vec<Box<dyn PlaybackSource>> {
+    config
+        .sources
+        .iter()
+        .map(|kind| -> Box<dyn PlaybackSource> {
+            match kind {
+                SourceKind::Mpris => Box::new(mpris::MprisSource(config.players.clone())),
+                SourceKind::Pulse => Box::new(pulse::PulseSource),
+            }
+        })
+        .collect()
+}
+
+/// Starts every configured source and combines them. Sources that fail to
+/// start are logged and left out.
+pub async fn audio_wanted(config: &PlaybackConfig) -> watch::Receiver<bool> {
+    let mut started = Vec::new();
+
+    for source in sources(config) {
+        let name = source.name();
+        match source.start().await {
+            Ok(receiver) => started.push(receiver),
+            Err(err) => warn!("Unable to start {} playback source: {}", name, err),
+        }
+    }
+
+    combine(started, config.combine)
+}
+
+/// Folds several sources into one signal using `mode`.
+pub fn combine(mut sources: Vec<watch::Receiver<bool>>, mode: Combine) -> watch::Receiver<bool> {
+    let evaluate = move |sources: &mut [watch::Receiver<bool>]| {
+        let values: Vec<bool> = sources
+            .iter_mut()
+            .map(|source| *source.borrow_and_update())
+            .collect();
+
+        !values.is_empty()
+            && match mode {
+                Combine::Or => values.contains(&true),
+                Combine::And => !values.contains(&false),
+            }
+    };
+
+    let (sender, receiver) = watch::channel(evaluate(&mut sources));
+    let (notify, mut notified) = mpsc::unbounded_channel();
+
+    for mut source in sources.iter().cloned() {
+        let notify = notify.clone();
+        tokio::spawn(async move {
+            while source.changed().await.is_ok() {
+                if notify.send(()).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(notify);
+
+    tokio::spawn(async move {
+        while notified.recv().await.is_some() {
+            let wanted = evaluate(&mut sources);
+            debug!("Audio wanted: {}", wanted);
+            sender.send_if_modified(|current| std::mem::replace(current, wanted) != wanted);
+        }
+    });
+
+    receiver
+}
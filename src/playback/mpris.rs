@@ -0,0 +1,230 @@
+/*
+
+Event-driven MPRIS player tracking
+
+Follows `NameOwnerChanged` to see players come and go, and the
+`PropertiesChanged` signal of `org.mpris.MediaPlayer2.Player` for their
+playback status. Nothing is polled. Players left out by the `[playback]`
+player patterns are tracked but never reported.
+
+*/
+
+use super::{PlaybackSource, SourceError};
+use crate::config::PlayerFilter;
+use dbus::{
+    arg::prop_cast,
+    message::{MatchRule, SignalArgs},
+    nonblock::{
+        Proxy, SyncConnection,
+        stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged},
+    },
+};
+use futures::{FutureExt, StreamExt, future::BoxFuture};
+use log::{debug, error};
+use mpris::PlaybackStatus;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::watch;
+
+pub const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+const DBUS_NAME: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Every followed player by bus name, with its playback status.
+pub type Players = BTreeMap<String, PlaybackStatus>;
+
+/// Well-known player name to its unique bus name and last known status.
+type Tracked = HashMap<String, (String, PlaybackStatus)>;
+
+/// Has audio while any followed MPRIS player is playing.
+pub struct MprisSource(pub PlayerFilter);
+
+impl PlaybackSource for MprisSource {
+    fn name(&self) -> &'static str {
+        "MPRIS"
+    }
+
+    fn start(self: Box<Self>) -> BoxFuture<'static, Result<watch::Receiver<bool>, SourceError>> {
+        async {
+            let players = watch_players(self.0).await?;
+            Ok(any_playing(players))
+        }
+        .boxed()
+    }
+}
+
+/// Publishes whether any player is currently playing.
+pub fn any_playing(mut players: watch::Receiver<Players>) -> watch::Receiver<bool> {
+    let playing = |players: &Players| {
+        players
+            .values()
+            .any(|status| *status == PlaybackStatus::Playing)
+    };
+
+    let (sender, receiver) = watch::channel(playing(&players.borrow_and_update()));
+
+    tokio::spawn(async move {
+        while players.changed().await.is_ok() {
+            let players = players.borrow_and_update();
+            debug!("MPRIS players: {:?}", *players);
+            let now_playing = playing(&players);
+            sender
+                .send_if_modified(|current| std::mem::replace(current, now_playing) != now_playing);
+        }
+    });
+
+    receiver
+}
+
+/// Names of the players that are playing, without the MPRIS bus name
+/// prefix (`spotify`, `firefox.instance_1_84`).
+pub fn playing(players: &Players) -> impl Iterator<Item = &str> {
+    players
+        .iter()
+        .filter(|(_, status)| **status == PlaybackStatus::Playing)
+        .map(|(name, _)| short_name(name))
+}
+
+fn short_name(name: &str) -> &str {
+    name.strip_prefix(MPRIS_PREFIX).unwrap_or(name)
+}
+
+/// Starts watching the session bus. The returned receiver always holds
+/// every player currently on the bus that `filter` allows.
+pub async fn watch_players(filter: PlayerFilter) -> Result<watch::Receiver<Players>, dbus::Error> {
+    let (resource, conn) = dbus_tokio::connection::new_session_sync()?;
+
+    let resource = tokio::spawn(async {
+        let err = resource.await;
+        error!("Lost connection to the session bus: {}", err);
+    });
+
+    let (owner_match, mut owner_changes) = conn
+        .add_match(MatchRule::new_signal(DBUS_NAME, "NameOwnerChanged").with_sender(DBUS_NAME))
+        .await?
+        .stream::<(String, String, String)>();
+
+    let (properties_match, mut property_changes) = conn
+        .add_match(
+            PropertiesPropertiesChanged::match_rule(None, Some(&MPRIS_PATH.into())).static_clone(),
+        )
+        .await?
+        .stream::<PropertiesPropertiesChanged>();
+
+    let mut tracked = Tracked::new();
+
+    for name in list_players(&conn).await? {
+        let Ok(owner) = name_owner(&conn, &name).await else {
+            continue;
+        };
+        let status = playback_status(&conn, &name).await;
+        tracked.insert(name, (owner, status));
+    }
+
+    let (sender, receiver) = watch::channel(players(&tracked, &filter));
+
+    tokio::spawn(async move {
+        // Dropping these would remove the match rules.
+        let _matches = (owner_match, properties_match);
+
+        loop {
+            tokio::select! {
+                Some((_, (name, _, new_owner))) = owner_changes.next() => {
+                    if !name.starts_with(MPRIS_PREFIX) {
+                        continue;
+                    }
+
+                    if new_owner.is_empty() {
+                        tracked.remove(&name);
+                    } else {
+                        let status = playback_status(&conn, &name).await;
+                        tracked.insert(name, (new_owner, status));
+                    }
+                }
+                Some((message, changed)) = property_changes.next() => {
+                    if changed.interface_name != PLAYER_INTERFACE {
+                        continue;
+                    }
+
+                    let Some(unique_name) = message.sender() else {
+                        continue;
+                    };
+
+                    let reported = prop_cast::<String>(&changed.changed_properties, "PlaybackStatus");
+                    let invalidated = changed.invalidated_properties.iter().any(|p| p == "PlaybackStatus");
+
+                    let status = match reported {
+                        Some(status) => status.parse().unwrap_or(PlaybackStatus::Stopped),
+                        None if invalidated => playback_status(&conn, &unique_name).await,
+                        None => continue,
+                    };
+
+                    for (owner, tracked_status) in tracked.values_mut() {
+                        if *owner == *unique_name {
+                            *tracked_status = status;
+                        }
+                    }
+                }
+                else => break,
+            }
+
+            let current = players(&tracked, &filter);
+            sender.send_if_modified(|previous| {
+                let changed = *previous != current;
+                *previous = current;
+                changed
+            });
+        }
+
+        resource.abort();
+    });
+
+    Ok(receiver)
+}
+
+fn players(tracked: &Tracked, filter: &PlayerFilter) -> Players {
+    tracked
+        .iter()
+        .filter(|(name, _)| filter.allows(short_name(name)))
+        .map(|(name, (_, status))| (name.clone(), *status))
+        .collect()
+}
+
+async fn list_players(conn: &Arc<SyncConnection>) -> Result<Vec<String>, dbus::Error> {
+    let proxy = Proxy::new(DBUS_NAME, DBUS_PATH, CALL_TIMEOUT, conn.clone());
+    let (names,): (Vec<String>,) = proxy.method_call(DBUS_NAME, "ListNames", ()).await?;
+
+    Ok(names
+        .into_iter()
+        .filter(|name| name.starts_with(MPRIS_PREFIX))
+        .collect())
+}
+
+async fn name_owner(conn: &Arc<SyncConnection>, name: &str) -> Result<String, dbus::Error> {
+    let proxy = Proxy::new(DBUS_NAME, DBUS_PATH, CALL_TIMEOUT, conn.clone());
+    let (owner,): (String,) = proxy
+        .method_call(DBUS_NAME, "GetNameOwner", (name,))
+        .await?;
+
+    Ok(owner)
+}
+
+/// Players that do not answer are treated as stopped.
+async fn playback_status(conn: &Arc<SyncConnection>, name: &str) -> PlaybackStatus {
+    let proxy = Proxy::new(name, MPRIS_PATH, CALL_TIMEOUT, conn.clone());
+
+    proxy
+        .get::<String>(PLAYER_INTERFACE, "PlaybackStatus")
+        .await
+        .ok()
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(PlaybackStatus::Stopped)
+}
@@ -0,0 +1,119 @@
+/*
+
+Output stream detection on the PulseAudio (or pipewire-pulse) server
+
+Subscribes to server events through `pactl subscribe` and re-reads the
+sink inputs whenever one is created, changed or removed. A stream counts
+as audio when it is neither corked (paused) nor muted, which also catches
+players without MPRIS such as games, browsers, calls or `aplay`.
+
+*/
+
+use super::{PlaybackSource, SourceError};
+use futures::{FutureExt, future::BoxFuture};
+use log::warn;
+use std::{io, process::Stdio};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::watch,
+};
+
+const PACTL: &str = "pactl";
+
+pub struct PulseSource;
+
+impl PlaybackSource for PulseSource {
+    fn name(&self) -> &'static str {
+        "PulseAudio"
+    }
+
+    fn start(self: Box<Self>) -> BoxFuture<'static, Result<watch::Receiver<bool>, SourceError>> {
+        async {
+            let mut subscription = pactl()
+                .arg("subscribe")
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+
+            let stdout = subscription
+                .stdout
+                .take()
+                .ok_or_else(|| io::Error::other("pactl subscribe has no output"))?;
+
+            let (sender, receiver) = watch::channel(active_streams().await?);
+
+            tokio::spawn(async move {
+                // Keeps `pactl subscribe` alive for as long as we read from it.
+                let _subscription = subscription;
+                let mut lines = BufReader::new(stdout).lines();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if !line.contains("sink-input") {
+                        continue;
+                    }
+
+                    match active_streams().await {
+                        Ok(active) => {
+                            sender.send_if_modified(|current| {
+                                std::mem::replace(current, active) != active
+                            });
+                        }
+                        Err(err) => warn!("Unable to list PulseAudio streams: {}", err),
+                    }
+                }
+
+                warn!("PulseAudio event subscription ended.");
+            });
+
+            Ok(receiver)
+        }
+        .boxed()
+    }
+}
+
+/// `pactl` with output suitable for parsing.
+pub fn pactl() -> Command {
+    let mut command = Command::new(PACTL);
+    // The output is parsed, so keep it untranslated.
+    command.env("LC_ALL", "C").stdin(Stdio::null());
+    command
+}
+
+async fn active_streams() -> io::Result<bool> {
+    let output = pactl()
+        .args(["list", "sink-inputs"])
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    Ok(has_active_stream(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Looks for a sink input that is neither corked nor muted in the output
+/// of `pactl list sink-inputs`.
+pub fn has_active_stream(listing: &str) -> bool {
+    let mut streams = Vec::new();
+
+    for line in listing.lines() {
+        let line = line.trim();
+
+        if line.starts_with("Sink Input #") {
+            streams.push((false, false));
+        } else if let Some(stream) = streams.last_mut() {
+            match line {
+                "Corked: yes" => stream.0 = true,
+                "Mute: yes" => stream.1 = true,
+                _ => {}
+            }
+        }
+    }
+
+    streams.iter().any(|(corked, muted)| !corked && !muted)
+}
@@ -0,0 +1,181 @@
+/*
+
+Client for HAS presets
+
+Reads the hearing aid type and preset records of an LE Audio hearing aid
+and selects the active preset through the Hearing Aid Preset Control
+Point, for `reasha preset` and for the daemon, which switches to a media
+preset while audio plays.
+
+*/
+
+use crate::{
+    backend::BluetoothDevice,
+    has::{
+        ACTIVE_PRESET_INDEX_U16, Features, HAS_SERVICE_U16, HEARING_AID_FEATURES_U16, MessageError,
+        PRESET_CONTROL_POINT_U16, Preset, PresetCommand, PresetMessage,
+    },
+};
+use bluer::{Uuid, UuidExt, gatt::WriteOp};
+use futures::StreamExt;
+use std::{fmt, time::Duration};
+
+/// How long a device may take between two Read Preset Responses.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum PresetError {
+    Bluetooth(bluer::Error),
+    Message(MessageError),
+    /// A characteristic that should hold a byte is empty.
+    Empty(&'static str),
+    /// The device stopped answering before the last preset.
+    NoResponse,
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Bluetooth(err) => write!(f, "{}", err),
+            PresetError::Message(err) => write!(f, "{}", err),
+            PresetError::Empty(characteristic) => write!(f, "{} is empty", characteristic),
+            PresetError::NoResponse => write!(
+                f,
+                "no answer on the preset control point within {}s",
+                RESPONSE_TIMEOUT.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<bluer::Error> for PresetError {
+    fn from(err: bluer::Error) -> Self {
+        PresetError::Bluetooth(err)
+    }
+}
+
+impl From<MessageError> for PresetError {
+    fn from(err: MessageError) -> Self {
+        PresetError::Message(err)
+    }
+}
+
+/// A preset as given by the user, by index or by name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresetSelector {
+    Index(u8),
+    /// Matched ignoring ASCII case.
+    Name(String),
+}
+
+impl PresetSelector {
+    pub fn find<'a>(&self, presets: &'a [Preset]) -> Option<&'a Preset> {
+        presets.iter().find(|preset| match self {
+            PresetSelector::Index(index) => preset.index == *index,
+            PresetSelector::Name(name) => preset.name.eq_ignore_ascii_case(name),
+        })
+    }
+}
+
+/// A number is an index, anything else a name.
+impl From<&str> for PresetSelector {
+    fn from(text: &str) -> Self {
+        match text.parse() {
+            Ok(index) => PresetSelector::Index(index),
+            Err(_) => PresetSelector::Name(text.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for PresetSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetSelector::Index(index) => write!(f, "{}", index),
+            PresetSelector::Name(name) => write!(f, "`{}`", name),
+        }
+    }
+}
+
+async fn read_byte<D: BluetoothDevice>(
+    device: &D,
+    characteristic: u16,
+    name: &'static str,
+) -> Result<u8, PresetError> {
+    let bytes = device
+        .read_characteristic(
+            Uuid::from_u16(HAS_SERVICE_U16),
+            Uuid::from_u16(characteristic),
+        )
+        .await?;
+
+    bytes.first().copied().ok_or(PresetError::Empty(name))
+}
+
+pub async fn read_features<D: BluetoothDevice>(device: &D) -> Result<Features, PresetError> {
+    read_byte(device, HEARING_AID_FEATURES_U16, "Hearing Aid Features")
+        .await
+        .map(Features)
+}
+
+/// Index of the active preset, 0 if none is.
+pub async fn active_preset<D: BluetoothDevice>(device: &D) -> Result<u8, PresetError> {
+    read_byte(device, ACTIVE_PRESET_INDEX_U16, "Active Preset Index").await
+}
+
+/// Reads all preset records, in the order of their indices.
+pub async fn read_presets<D: BluetoothDevice>(device: &D) -> Result<Vec<Preset>, PresetError> {
+    let service = Uuid::from_u16(HAS_SERVICE_U16);
+    let control_point = Uuid::from_u16(PRESET_CONTROL_POINT_U16);
+
+    // Subscribe first so that no response is missed.
+    let mut messages = device.notify_characteristic(service, control_point).await?;
+
+    let request = PresetCommand::ReadPresets {
+        start_index: 1,
+        count: u8::MAX,
+    };
+    device
+        .write_characteristic(service, control_point, request.to_bytes(), WriteOp::Request)
+        .await?;
+
+    let mut presets = Vec::new();
+    loop {
+        let Ok(Some(bytes)) = tokio::time::timeout(RESPONSE_TIMEOUT, messages.next()).await else {
+            return Err(PresetError::NoResponse);
+        };
+
+        // Changes may be indicated in between; the list read is current.
+        if let PresetMessage::ReadResponse { last, preset } = PresetMessage::parse(&bytes)? {
+            presets.push(preset);
+            if last {
+                return Ok(presets);
+            }
+        }
+    }
+}
+
+/// Selects a preset. With `synced`, the device passes the change on to the
+/// other side of its set.
+pub async fn set_active<D: BluetoothDevice>(
+    device: &D,
+    index: u8,
+    synced: bool,
+) -> Result<(), PresetError> {
+    let command = if synced {
+        PresetCommand::SetActiveSynced(index)
+    } else {
+        PresetCommand::SetActive(index)
+    };
+
+    device
+        .write_characteristic(
+            Uuid::from_u16(HAS_SERVICE_U16),
+            Uuid::from_u16(PRESET_CONTROL_POINT_U16),
+            command.to_bytes(),
+            WriteOp::Request,
+        )
+        .await?;
+    Ok(())
+}
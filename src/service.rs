@@ -0,0 +1,287 @@
+/*
+
+D-Bus service for status and control
+
+Publishes the `org.reasha.Manager` interface at `/org/reasha/Manager` on
+the session bus, under the name `org.reasha`, so that other tools can see
+what the daemon is doing and steer it:
+
+    ListDevices() -> a{sa{sv}}             every managed device by address
+    GetDevice(s address) -> a{sv}
+    Connect(s address)                     until the mode is set again
+    Disconnect(s address)                  until the mode is set again
+    SetMode(s address, s mode)             on-playback, always, manual, never,
+                                           also before the device is managed
+    signal StateChanged(s address, s state, s mode)
+
+A device is described by `Address`, `Name`, `Protocol`, `State` and `Mode`,
+and by `Battery` (percent), `RSSI`, `Side`, `HiSyncId`, `PSM` and `MTU`
+(the send MTU of the audio channel, with `probe_l2cap`) once known.
+Requests are handed to the device's task and answered right away; the
+outcome shows up in `StateChanged`. `Client` is the other end, used by the
+command line to reach a running daemon.
+
+*/
+
+use crate::{
+    state::Mode,
+    status::{DeviceStatus, Registry, Request, UnknownDevice},
+};
+use bluer::Address;
+use dbus::{
+    MethodErr,
+    arg::{PropMap, RefArg, Variant},
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+    nonblock::{Proxy, SyncConnection, stdintf::org_freedesktop_dbus::RequestNameReply},
+};
+use dbus_crossroads::Crossroads;
+use log::error;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+
+pub const BUS_NAME: &str = "org.reasha";
+pub const PATH: &str = "/org/reasha/Manager";
+pub const INTERFACE: &str = "org.reasha.Manager";
+
+const UNKNOWN_DEVICE: &str = "org.reasha.Error.UnknownDevice";
+
+/// How long the client waits for the daemon to answer.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connects to the session bus and publishes the service there.
+pub async fn serve(registry: Registry) -> Result<(), dbus::Error> {
+    let (resource, conn) = dbus_tokio::connection::new_session_sync()?;
+
+    tokio::spawn(async {
+        let err = resource.await;
+        error!("Lost connection to the session bus: {}", err);
+    });
+
+    publish(&conn, registry).await
+}
+
+/// Publishes the service on `conn`, which may be any bus.
+pub async fn publish(conn: &Arc<SyncConnection>, registry: Registry) -> Result<(), dbus::Error> {
+    let reply = conn.request_name(BUS_NAME, false, false, true).await?;
+    if reply != RequestNameReply::PrimaryOwner {
+        return Err(dbus::Error::new_custom(
+            "org.reasha.Error.NameTaken",
+            &format!("{BUS_NAME} is already owned, is reASHA running twice?"),
+        ));
+    }
+
+    let mut crossroads = Crossroads::new();
+    let token = crossroads.register(INTERFACE, |b| {
+        b.signal::<(String, String, String), _>("StateChanged", ("address", "state", "mode"));
+
+        b.method(
+            "ListDevices",
+            (),
+            ("devices",),
+            |_, registry: &mut Registry, ()| {
+                let devices: HashMap<String, PropMap> = registry
+                    .devices()
+                    .iter()
+                    .map(|status| (status.address.to_string(), properties(status)))
+                    .collect();
+                Ok((devices,))
+            },
+        );
+        b.method(
+            "GetDevice",
+            ("address",),
+            ("device",),
+            |_, registry: &mut Registry, (address,): (String,)| {
+                let address = parse_address(&address)?;
+                let status = registry
+                    .device(address)
+                    .ok_or(UnknownDevice(address))
+                    .map_err(unknown_device)?;
+                Ok((properties(&status),))
+            },
+        );
+        b.method(
+            "Connect",
+            ("address",),
+            (),
+            |_, registry: &mut Registry, (address,): (String,)| {
+                request(registry, &address, Request::Connect)
+            },
+        );
+        b.method(
+            "Disconnect",
+            ("address",),
+            (),
+            |_, registry: &mut Registry, (address,): (String,)| {
+                request(registry, &address, Request::Disconnect)
+            },
+        );
+        b.method(
+            "SetMode",
+            ("address", "mode"),
+            (),
+            |_, registry: &mut Registry, (address, mode): (String, String)| {
+                let address = parse_address(&address)?;
+                let mode: Mode = mode.parse().map_err(|message: String| {
+                    MethodErr::from(("org.freedesktop.DBus.Error.InvalidArgs", message))
+                })?;
+                registry.set_mode(address, mode);
+                Ok(())
+            },
+        );
+    });
+    crossroads.insert(PATH, &[token], registry.clone());
+
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, conn| {
+            let _ = crossroads.handle_message(message, conn);
+            true
+        }),
+    );
+
+    let conn = Arc::clone(conn);
+    let mut changes = registry.changes();
+    tokio::spawn(async move {
+        loop {
+            let status = match changes.recv().await {
+                Ok(status) => status,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let signal =
+                dbus::Message::signal(&PATH.into(), &INTERFACE.into(), &"StateChanged".into())
+                    .append3(
+                        status.address.to_string(),
+                        status.state.to_string(),
+                        status.mode.to_string(),
+                    );
+            if conn.send(signal).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn request(registry: &Registry, address: &str, request: Request) -> Result<(), MethodErr> {
+    let address = parse_address(address)?;
+    registry.request(address, request).map_err(unknown_device)
+}
+
+fn parse_address(address: &str) -> Result<Address, MethodErr> {
+    address.parse().map_err(|_| {
+        MethodErr::from((
+            "org.freedesktop.DBus.Error.InvalidArgs",
+            format!("`{address}` is not a Bluetooth address"),
+        ))
+    })
+}
+
+fn unknown_device(err: UnknownDevice) -> MethodErr {
+    MethodErr::from((UNKNOWN_DEVICE, err.to_string()))
+}
+
+/// The `a{sv}` describing a device.
+fn properties(status: &DeviceStatus) -> PropMap {
+    let mut properties = PropMap::new();
+    let mut insert = |key: &str, value: Box<dyn RefArg>| {
+        properties.insert(key.to_owned(), Variant(value));
+    };
+
+    insert("Address", Box::new(status.address.to_string()));
+    insert("Name", Box::new(status.name.clone()));
+    insert("Protocol", Box::new(status.protocol.to_string()));
+    insert("State", Box::new(status.state.to_string()));
+    insert("Mode", Box::new(status.mode.to_string()));
+    if let Some(battery) = status.battery {
+        insert("Battery", Box::new(battery));
+    }
+    if let Some(rssi) = status.rssi {
+        insert("RSSI", Box::new(rssi));
+    }
+    if let Some(side) = status.side {
+        insert("Side", Box::new(side.to_string()));
+    }
+    if let Some(hisyncid) = status.hisyncid {
+        insert("HiSyncId", Box::new(hisyncid.to_string()));
+    }
+    if let Some(psm) = status.psm {
+        insert("PSM", Box::new(psm));
+    }
+    if let Some(mtu) = status.mtu {
+        insert("MTU", Box::new(mtu as u32));
+    }
+
+    properties
+}
+
+/// Calls the service of a running daemon.
+pub struct Client {
+    conn: Arc<SyncConnection>,
+}
+
+impl Client {
+    /// Connects to the session bus.
+    pub fn connect() -> Result<Client, dbus::Error> {
+        let (resource, conn) = dbus_tokio::connection::new_session_sync()?;
+
+        tokio::spawn(async {
+            let err = resource.await;
+            error!("Lost connection to the session bus: {}", err);
+        });
+
+        Ok(Client { conn })
+    }
+
+    fn proxy(&self) -> Proxy<'_, &SyncConnection> {
+        Proxy::new(BUS_NAME, PATH, CALL_TIMEOUT, &*self.conn)
+    }
+
+    /// The `a{sv}` describing every managed device, by address.
+    pub async fn devices(&self) -> Result<HashMap<String, PropMap>, dbus::Error> {
+        let (devices,): (HashMap<String, PropMap>,) = self
+            .proxy()
+            .method_call(INTERFACE, "ListDevices", ())
+            .await?;
+        Ok(devices)
+    }
+
+    /// The `a{sv}` describing a managed device.
+    pub async fn device(&self, address: Address) -> Result<PropMap, dbus::Error> {
+        let (device,): (PropMap,) = self
+            .proxy()
+            .method_call(INTERFACE, "GetDevice", (address.to_string(),))
+            .await?;
+        Ok(device)
+    }
+
+    pub async fn set_mode(&self, address: Address, mode: Mode) -> Result<(), dbus::Error> {
+        self.proxy()
+            .method_call(
+                INTERFACE,
+                "SetMode",
+                (address.to_string(), mode.to_string()),
+            )
+            .await
+    }
+}
+
+/// Whether `err` means that the daemon does not manage the device asked for.
+pub fn is_unknown_device(err: &dbus::Error) -> bool {
+    err.name() == Some(UNKNOWN_DEVICE)
+}
+
+/// Whether `err` means that no daemon is on the bus to answer.
+pub fn is_not_running(err: &dbus::Error) -> bool {
+    matches!(
+        err.name(),
+        Some(
+            "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner"
+        )
+    )
+}
@@ -0,0 +1,882 @@
+/*
+
+Per-device connection state machine
+
+The machine is pure: it is fed inputs together with the current time and
+answers with the action to perform, if any. Timers are expressed as a
+deadline that the driver turns into an `Input::Timer` once it passes, so
+tests can drive it with any clock.
+
+*/
+
+use std::{
+    fmt,
+    hash::{BuildHasher, RandomState},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Advertisements seen after at least this long without one mean the
+/// device came back into range.
+pub const REAPPEAR_GAP: Duration = Duration::from_secs(30);
+
+/// Per-device timing that keeps short pauses from cycling the link.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    /// How long audio must stay unwanted before disconnecting.
+    pub idle_timeout: Duration,
+    /// How long a link is kept at least once it is up.
+    pub min_connected: Duration,
+    /// How failed connection attempts are retried.
+    pub retry: RetryPolicy,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(15),
+            min_connected: Duration::from_secs(30),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// How failed connection attempts are retried.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Delay after the first failure.
+    pub initial_delay: Duration,
+    /// Upper bound for the delay before jitter.
+    pub max_delay: Duration,
+    /// Factor the delay grows by after each failure.
+    pub multiplier: f64,
+    /// Random spread applied to each delay, as a fraction of it.
+    pub jitter: f64,
+    /// Failed attempts per playback session before cooling down.
+    pub max_attempts: u32,
+    /// Pause after `max_attempts` failures.
+    pub cooldown: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.25,
+            max_attempts: 6,
+            cooldown: Duration::from_secs(600),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after `attempt` failures, given a uniform
+    /// random `unit` in `[0, 1)`.
+    pub fn delay(&self, attempt: u32, unit: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let spread = 1.0 + self.jitter * (unit * 2.0 - 1.0);
+
+        Duration::try_from_secs_f64(base * spread).unwrap_or(self.max_delay)
+    }
+}
+
+/// Who decides whether a device should be connected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Connected while audio is wanted.
+    #[default]
+    OnPlayback,
+    /// Connected all the time.
+    Always,
+    /// Only connected and disconnected on request, any other link is kept.
+    Manual,
+    /// Kept disconnected.
+    Never,
+}
+
+impl Mode {
+    /// What the machine is told, given whether audio is wanted and whether
+    /// a connection was requested or refused, which overrides the mode.
+    pub fn input(self, audio: bool, requested: Option<bool>) -> Input {
+        match (requested, self) {
+            (Some(true), _) => Input::Audio(true),
+            (Some(false), _) => Input::Release,
+            (None, Mode::OnPlayback) => Input::Audio(audio),
+            (None, Mode::Always) => Input::Audio(true),
+            (None, Mode::Manual) => Input::Hold,
+            (None, Mode::Never) => Input::Release,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    Connecting,
+    Connected,
+    Disconnecting,
+    Backoff,
+    Removed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// Whether audio output is currently wanted.
+    Audio(bool),
+    /// The link is no longer wanted and should go now, without waiting for
+    /// the idle timeout or the minimum connected time.
+    Release,
+    /// Whatever link there is, made here or elsewhere, is kept, and none is
+    /// made, until audio is wanted or the link released.
+    Hold,
+    /// BlueZ changed the device's `Connected` property.
+    Link(bool),
+    ConnectSucceeded,
+    ConnectFailed,
+    DisconnectFailed,
+    /// An advertisement from the device was seen.
+    Advertised,
+    /// The action last returned was dropped before it was performed.
+    Cancelled,
+    /// The deadline returned by [`Machine::deadline`] has passed.
+    Timer,
+    /// BlueZ removed the device.
+    Removed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Connect,
+    Disconnect,
+}
+
+#[derive(Clone, Debug)]
+pub struct Machine {
+    policy: Policy,
+    state: State,
+    audio: bool,
+    link: bool,
+    retry_at: Option<Instant>,
+    /// Failed connection attempts in this playback session.
+    attempts: u32,
+    /// No connection attempts before this.
+    cooldown_until: Option<Instant>,
+    last_advertised: Option<Instant>,
+    /// State of the jitter generator.
+    rng: u64,
+    /// When the current link came up.
+    connected_since: Option<Instant>,
+    /// When audio stopped being wanted.
+    idle_since: Option<Instant>,
+    /// When the link was released, until it is wanted again.
+    released_at: Option<Instant>,
+    /// Whether the link is held as it is.
+    held: bool,
+}
+
+impl Machine {
+    /// Starts in `Connected` or `Idle` depending on the current link. Feed
+    /// an `Input::Audio` afterwards to get the first action.
+    pub fn new(policy: Policy, now: Instant, link: bool, audio: bool) -> Self {
+        Self {
+            policy,
+            state: if link { State::Connected } else { State::Idle },
+            audio,
+            link,
+            retry_at: None,
+            attempts: 0,
+            cooldown_until: None,
+            last_advertised: None,
+            rng: RandomState::new().hash_one(now),
+            connected_since: link.then_some(now),
+            idle_since: (link && !audio).then_some(now),
+            released_at: None,
+            held: false,
+        }
+    }
+
+    /// Makes the jitter reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = seed;
+        self
+    }
+
+    /// Failed connection attempts in the current playback session.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// When the driver should feed `Input::Timer`, if at all.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Backoff => self.retry_at,
+            State::Connected if !self.audio => self.disconnect_at(),
+            _ => None,
+        }
+    }
+
+    pub fn handle(&mut self, now: Instant, input: Input) -> Option<Action> {
+        if self.state == State::Removed {
+            return None;
+        }
+
+        match input {
+            Input::Removed => {
+                self.state = State::Removed;
+                return None;
+            }
+            Input::Audio(wanted) => {
+                if wanted && !self.audio && !self.cooling_down(now) {
+                    // A new playback session gets a fresh set of attempts.
+                    self.attempts = 0;
+                }
+                self.audio = wanted;
+                self.released_at = None;
+                self.held = false;
+                if wanted {
+                    self.idle_since = None;
+                } else if self.idle_since.is_none() {
+                    self.idle_since = Some(now);
+                }
+            }
+            Input::Release => {
+                self.audio = false;
+                self.held = false;
+                self.idle_since.get_or_insert(now);
+                self.released_at.get_or_insert(now);
+            }
+            Input::Hold => {
+                self.audio = false;
+                self.held = true;
+                self.idle_since = None;
+                self.released_at = None;
+            }
+            Input::Link(up) => {
+                self.link = up;
+                if !up {
+                    self.connected_since = None;
+                }
+                match (self.state, up) {
+                    (State::Idle | State::Connecting | State::Backoff, true) => {
+                        self.enter_connected(now);
+                    }
+                    (State::Connected | State::Disconnecting, false) => self.state = State::Idle,
+                    _ => {}
+                }
+            }
+            Input::ConnectSucceeded => {
+                self.link = true;
+                if self.state == State::Connecting {
+                    self.enter_connected(now);
+                }
+            }
+            Input::ConnectFailed => {
+                if self.state == State::Connecting {
+                    self.connect_failed(now);
+                }
+            }
+            Input::DisconnectFailed => {
+                if self.state == State::Disconnecting {
+                    self.back_off(now + self.policy.retry.initial_delay);
+                }
+            }
+            Input::Advertised => {
+                let reappeared = self
+                    .last_advertised
+                    .is_none_or(|seen| now.duration_since(seen) >= REAPPEAR_GAP);
+                self.last_advertised = Some(now);
+
+                if reappeared {
+                    self.reset_retries();
+                    if self.state == State::Backoff {
+                        self.settle(now);
+                    }
+                }
+            }
+            Input::Cancelled => match self.state {
+                State::Connecting => self.state = State::Idle,
+                State::Disconnecting => self.state = State::Connected,
+                _ => {}
+            },
+            Input::Timer => {
+                if self.state == State::Backoff && self.retry_at.is_some_and(|at| now >= at) {
+                    self.settle(now);
+                }
+            }
+        }
+
+        // Nothing left to retry once the link already matches what is wanted.
+        if self.state == State::Backoff && (self.audio == self.link || self.held) {
+            self.settle(now);
+        }
+
+        match self.state {
+            State::Idle if self.audio => match self.cooldown_until {
+                Some(until) if now < until => {
+                    self.back_off(until);
+                    None
+                }
+                _ => {
+                    if self.cooldown_until.take().is_some() {
+                        self.attempts = 0;
+                    }
+                    self.state = State::Connecting;
+                    Some(Action::Connect)
+                }
+            },
+            State::Connected if !self.audio && self.disconnect_at().is_some_and(|at| now >= at) => {
+                self.state = State::Disconnecting;
+                Some(Action::Disconnect)
+            }
+            _ => None,
+        }
+    }
+
+    /// The earliest time an unwanted link may be dropped.
+    fn disconnect_at(&self) -> Option<Instant> {
+        if self.held {
+            return None;
+        }
+        if self.released_at.is_some() {
+            return self.released_at;
+        }
+
+        let idle_until = self.idle_since? + self.policy.idle_timeout;
+
+        Some(match self.connected_since {
+            Some(since) => idle_until.max(since + self.policy.min_connected),
+            None => idle_until,
+        })
+    }
+
+    fn enter_connected(&mut self, now: Instant) {
+        self.reset_retries();
+        self.connected_since.get_or_insert(now);
+        if !self.audio && !self.held && self.idle_since.is_none() {
+            self.idle_since = Some(now);
+        }
+        self.state = State::Connected;
+    }
+
+    fn connect_failed(&mut self, now: Instant) {
+        let retry = self.policy.retry;
+        self.attempts += 1;
+
+        if retry.max_attempts > 0 && self.attempts >= retry.max_attempts {
+            let until = now + retry.cooldown;
+            self.cooldown_until = Some(until);
+            self.back_off(until);
+        } else {
+            let unit = self.next_unit();
+            self.back_off(now + retry.delay(self.attempts, unit));
+        }
+    }
+
+    fn cooling_down(&self, now: Instant) -> bool {
+        self.cooldown_until.is_some_and(|until| now < until)
+    }
+
+    fn reset_retries(&mut self) {
+        self.attempts = 0;
+        self.cooldown_until = None;
+    }
+
+    fn back_off(&mut self, until: Instant) {
+        self.state = State::Backoff;
+        self.retry_at = Some(until);
+    }
+
+    /// Uniform value in `[0, 1)` from a xorshift generator.
+    fn next_unit(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Leaves `Backoff` for the state matching the link.
+    fn settle(&mut self, now: Instant) {
+        self.retry_at = None;
+        if self.link {
+            self.enter_connected(now);
+        } else {
+            self.state = State::Idle;
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mode::OnPlayback => "on-playback",
+            Mode::Always => "always",
+            Mode::Manual => "manual",
+            Mode::Never => "never",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on-playback" => Ok(Mode::OnPlayback),
+            "always" => Ok(Mode::Always),
+            "manual" => Ok(Mode::Manual),
+            "never" => Ok(Mode::Never),
+            other => Err(format!(
+                "unknown mode `{other}`, expected `on-playback`, `always`, `manual` or `never`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            State::Idle => "idle",
+            State::Connecting => "connecting",
+            State::Connected => "connected",
+            State::Disconnecting => "disconnecting",
+            State::Backoff => "backoff",
+            State::Removed => "removed",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            idle_timeout: secs(15),
+            min_connected: secs(30),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// A machine that connected at `start` while audio was wanted.
+    fn connected(start: Instant) -> Machine {
+        let mut machine = Machine::new(policy(), start, false, true);
+        assert_eq!(
+            machine.handle(start, Input::Audio(true)),
+            Some(Action::Connect)
+        );
+        assert_eq!(machine.handle(start, Input::ConnectSucceeded), None);
+        assert_eq!(machine.state(), State::Connected);
+        machine
+    }
+
+    #[test]
+    fn removed_is_final() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        assert_eq!(machine.handle(start, Input::Removed), None);
+        assert_eq!(machine.state(), State::Removed);
+        assert_eq!(machine.deadline(), None);
+
+        for input in [
+            Input::Link(false),
+            Input::Audio(true),
+            Input::Advertised,
+            Input::Timer,
+        ] {
+            assert_eq!(machine.handle(start + secs(1), input), None);
+            assert_eq!(machine.state(), State::Removed);
+        }
+    }
+
+    #[test]
+    fn cancelled_connect_goes_back_to_idle() {
+        let start = Instant::now();
+        let mut machine = Machine::new(policy(), start, false, false);
+        assert_eq!(
+            machine.handle(start, Input::Audio(true)),
+            Some(Action::Connect)
+        );
+
+        // Issued again as long as audio is still wanted.
+        assert_eq!(
+            machine.handle(start, Input::Cancelled),
+            Some(Action::Connect)
+        );
+        assert_eq!(machine.state(), State::Connecting);
+
+        machine.handle(start, Input::Audio(false));
+        assert_eq!(machine.handle(start, Input::Cancelled), None);
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn cancelled_disconnect_keeps_the_link() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        assert_eq!(
+            machine.handle(start + secs(60), Input::Release),
+            Some(Action::Disconnect)
+        );
+        machine.handle(start + secs(61), Input::Audio(true));
+        assert_eq!(machine.handle(start + secs(61), Input::Cancelled), None);
+        assert_eq!(machine.state(), State::Connected);
+    }
+
+    #[test]
+    fn lost_link_is_made_again_while_wanted() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        assert_eq!(
+            machine.handle(start + secs(5), Input::Link(false)),
+            Some(Action::Connect)
+        );
+        assert_eq!(machine.state(), State::Connecting);
+    }
+
+    #[test]
+    fn lost_link_while_disconnecting_is_the_disconnect() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+        machine.handle(start + secs(60), Input::Release);
+        assert_eq!(machine.state(), State::Disconnecting);
+
+        assert_eq!(machine.handle(start + secs(61), Input::Link(false)), None);
+        assert_eq!(machine.state(), State::Idle);
+
+        // Not wanted, so a lost link while idle changes nothing either.
+        assert_eq!(machine.handle(start + secs(62), Input::Link(false)), None);
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn link_coming_up_ends_the_backoff() {
+        let start = Instant::now();
+        let mut machine = Machine::new(policy(), start, false, true).with_seed(1);
+        machine.handle(start, Input::Audio(true));
+        assert_eq!(machine.handle(start, Input::ConnectFailed), None);
+        assert_eq!(machine.state(), State::Backoff);
+        assert_eq!(machine.attempts(), 1);
+
+        assert_eq!(machine.handle(start + secs(1), Input::Link(true)), None);
+        assert_eq!(machine.state(), State::Connected);
+        assert_eq!(machine.attempts(), 0);
+        assert_eq!(machine.deadline(), None);
+    }
+
+    #[test]
+    fn link_coming_up_while_idle_is_taken_up() {
+        let start = Instant::now();
+        let mut machine = Machine::new(policy(), start, false, false);
+        machine.handle(start, Input::Audio(false));
+
+        assert_eq!(machine.handle(start + secs(1), Input::Link(true)), None);
+        assert_eq!(machine.state(), State::Connected);
+        // Unwanted, so it goes once both timers have run out.
+        assert_eq!(machine.deadline(), Some(start + secs(31)));
+    }
+
+    #[test]
+    fn disconnects_after_idle_timeout() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        let stopped = start + secs(60);
+        assert_eq!(machine.handle(stopped, Input::Audio(false)), None);
+        assert_eq!(machine.deadline(), Some(stopped + secs(15)));
+
+        assert_eq!(machine.handle(stopped + secs(14), Input::Timer), None);
+        assert_eq!(machine.state(), State::Connected);
+        assert_eq!(
+            machine.handle(stopped + secs(15), Input::Timer),
+            Some(Action::Disconnect)
+        );
+        assert_eq!(machine.state(), State::Disconnecting);
+
+        assert_eq!(machine.handle(stopped + secs(16), Input::Link(false)), None);
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn short_pause_keeps_the_link() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        let stopped = start + secs(60);
+        machine.handle(stopped, Input::Audio(false));
+        assert_eq!(machine.handle(stopped + secs(5), Input::Audio(true)), None);
+        assert_eq!(machine.deadline(), None);
+        assert_eq!(machine.handle(stopped + secs(30), Input::Timer), None);
+        assert_eq!(machine.state(), State::Connected);
+    }
+
+    #[test]
+    fn min_connected_holds_the_link() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        // Idle timeout would end at 20s, the minimum connected time at 30s.
+        assert_eq!(machine.handle(start + secs(5), Input::Audio(false)), None);
+        assert_eq!(machine.deadline(), Some(start + secs(30)));
+
+        assert_eq!(machine.handle(start + secs(20), Input::Timer), None);
+        assert_eq!(machine.state(), State::Connected);
+        assert_eq!(
+            machine.handle(start + secs(30), Input::Timer),
+            Some(Action::Disconnect)
+        );
+    }
+
+    #[test]
+    fn release_skips_the_timers() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        assert_eq!(
+            machine.handle(start + secs(1), Input::Release),
+            Some(Action::Disconnect)
+        );
+    }
+
+    #[test]
+    fn held_link_is_kept_whoever_made_it() {
+        let start = Instant::now();
+        let mut machine = Machine::new(policy(), start, false, false);
+        assert_eq!(machine.handle(start, Input::Hold), None);
+        assert_eq!(machine.state(), State::Idle);
+
+        // Connected from elsewhere.
+        assert_eq!(machine.handle(start + secs(5), Input::Link(true)), None);
+        assert_eq!(machine.state(), State::Connected);
+        assert_eq!(machine.deadline(), None);
+        assert_eq!(machine.handle(start + secs(600), Input::Timer), None);
+        assert_eq!(machine.state(), State::Connected);
+
+        // Dropped from elsewhere, and not made again.
+        assert_eq!(machine.handle(start + secs(601), Input::Link(false)), None);
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn release_ends_the_hold() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        assert_eq!(machine.handle(start + secs(1), Input::Hold), None);
+        assert_eq!(machine.deadline(), None);
+        assert_eq!(
+            machine.handle(start + secs(2), Input::Release),
+            Some(Action::Disconnect)
+        );
+    }
+
+    #[test]
+    fn playback_resuming_while_disconnecting_reconnects() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        machine.handle(start + secs(60), Input::Audio(false));
+        assert_eq!(
+            machine.handle(start + secs(75), Input::Timer),
+            Some(Action::Disconnect)
+        );
+
+        assert_eq!(machine.handle(start + secs(76), Input::Audio(true)), None);
+        assert_eq!(machine.state(), State::Disconnecting);
+        assert_eq!(
+            machine.handle(start + secs(77), Input::Link(false)),
+            Some(Action::Connect)
+        );
+        assert_eq!(machine.state(), State::Connecting);
+    }
+
+    #[test]
+    fn playback_resuming_when_disconnect_fails_keeps_the_link() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        machine.handle(start + secs(60), Input::Audio(false));
+        machine.handle(start + secs(75), Input::Timer);
+        machine.handle(start + secs(76), Input::Audio(true));
+
+        assert_eq!(
+            machine.handle(start + secs(77), Input::DisconnectFailed),
+            None
+        );
+        assert_eq!(machine.state(), State::Connected);
+    }
+
+    #[test]
+    fn cancelled_disconnect_is_issued_again() {
+        let start = Instant::now();
+        let mut machine = connected(start);
+
+        machine.handle(start + secs(60), Input::Audio(false));
+        machine.handle(start + secs(75), Input::Timer);
+        assert_eq!(
+            machine.handle(start + secs(75), Input::Cancelled),
+            Some(Action::Disconnect)
+        );
+        assert_eq!(machine.state(), State::Disconnecting);
+    }
+
+    fn retrying(retry: RetryPolicy, start: Instant) -> Machine {
+        let policy = Policy { retry, ..policy() };
+        let mut machine = Machine::new(policy, start, false, true).with_seed(0x5eed);
+        assert_eq!(
+            machine.handle(start, Input::Audio(true)),
+            Some(Action::Connect)
+        );
+        machine
+    }
+
+    /// Fails the pending attempt at `now` and returns the delay until the
+    /// retry.
+    fn fail(machine: &mut Machine, now: Instant) -> Duration {
+        assert_eq!(machine.handle(now, Input::ConnectFailed), None);
+        assert_eq!(machine.state(), State::Backoff);
+        machine.deadline().expect("retry deadline") - now
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let retry = RetryPolicy {
+            jitter: 0.0,
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        };
+        let mut now = Instant::now();
+        let mut machine = retrying(retry, now);
+
+        for expected in [2, 4, 8, 16, 32, 60, 60] {
+            let delay = fail(&mut machine, now);
+            assert_eq!(delay, secs(expected));
+
+            assert_eq!(machine.handle(now + delay / 2, Input::Timer), None);
+            now += delay;
+            assert_eq!(machine.handle(now, Input::Timer), Some(Action::Connect));
+        }
+        assert_eq!(machine.attempts(), 7);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let retry = RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        };
+        let mut now = Instant::now();
+        let mut machine = retrying(retry, now);
+        let mut delays = Vec::new();
+
+        for attempt in 1..=20 {
+            let delay = fail(&mut machine, now);
+            let base = retry.delay(attempt, 0.5).as_secs_f64();
+            let ratio = delay.as_secs_f64() / base;
+            assert!((0.75..1.25).contains(&ratio), "attempt {attempt}: {ratio}");
+
+            delays.push(delay);
+            now += delay;
+            machine.handle(now, Input::Timer);
+        }
+
+        // The seed makes the sequence reproducible, and it does spread.
+        let mut again = retrying(retry, Instant::now());
+        let mut now = Instant::now();
+        for &delay in &delays {
+            assert_eq!(fail(&mut again, now), delay);
+            now += delay;
+            again.handle(now, Input::Timer);
+        }
+        assert!(delays[10..].iter().any(|&delay| delay != delays[10]));
+    }
+
+    #[test]
+    fn delay_bounds() {
+        let retry = RetryPolicy::default();
+        assert_eq!(retry.delay(1, 0.0), Duration::from_millis(1500));
+        assert_eq!(retry.delay(1, 0.5), secs(2));
+        assert!(retry.delay(1, 0.999_999) < Duration::from_millis(2500));
+        assert_eq!(retry.delay(u32::MAX, 0.5), secs(60));
+    }
+
+    #[test]
+    fn cooldown_after_max_attempts() {
+        let retry = RetryPolicy {
+            jitter: 0.0,
+            max_attempts: 3,
+            cooldown: secs(600),
+            ..RetryPolicy::default()
+        };
+        let mut now = Instant::now();
+        let mut machine = retrying(retry, now);
+
+        for _ in 0..2 {
+            let delay = fail(&mut machine, now);
+            now += delay;
+            assert_eq!(machine.handle(now, Input::Timer), Some(Action::Connect));
+        }
+        assert_eq!(fail(&mut machine, now), secs(600));
+
+        // A new playback session does not cut the cooldown short.
+        machine.handle(now + secs(10), Input::Audio(false));
+        assert_eq!(machine.handle(now + secs(20), Input::Audio(true)), None);
+        assert_eq!(machine.state(), State::Backoff);
+        assert_eq!(machine.attempts(), 3);
+
+        assert_eq!(machine.handle(now + secs(599), Input::Timer), None);
+        assert_eq!(
+            machine.handle(now + secs(600), Input::Timer),
+            Some(Action::Connect)
+        );
+        assert_eq!(machine.attempts(), 0);
+    }
+
+    #[test]
+    fn reappearing_device_is_retried_at_once() {
+        let retry = RetryPolicy {
+            jitter: 0.0,
+            max_attempts: 3,
+            ..RetryPolicy::default()
+        };
+        let start = Instant::now();
+        let mut machine = retrying(retry, start);
+        machine.handle(start, Input::Advertised);
+
+        let mut now = start;
+        for _ in 0..2 {
+            now += fail(&mut machine, now);
+            machine.handle(now, Input::Timer);
+        }
+        assert_eq!(fail(&mut machine, now), secs(600));
+
+        // Still in range: advertisements within the gap change nothing.
+        let soon = start + REAPPEAR_GAP / 2;
+        assert_eq!(machine.handle(soon, Input::Advertised), None);
+        assert_eq!(machine.state(), State::Backoff);
+
+        let back = soon + REAPPEAR_GAP;
+        assert_eq!(
+            machine.handle(back, Input::Advertised),
+            Some(Action::Connect)
+        );
+        assert_eq!(machine.attempts(), 0);
+    }
+}
@@ -0,0 +1,266 @@
+/*
+
+Live status of the managed devices
+
+Every device task keeps an entry in the `Registry` up to date and takes
+requests through it, so that the D-Bus service can report on devices and
+control them without knowing about the tasks. Changes of state or mode are
+broadcast as well, for the `StateChanged` signal.
+
+Modes set at runtime are remembered by address, also for devices that are
+gone or not managed yet, so that they survive a device task being restarted
+and can be saved across runs of the daemon.
+
+*/
+
+use crate::{
+    asha::{HiSyncId, Side},
+    capability::Protocol,
+    state::{Mode, State},
+};
+use bluer::Address;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::{broadcast, mpsc, watch};
+
+/// Changes kept for subscribers that fall behind.
+const CHANGES_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceStatus {
+    pub address: Address,
+    pub name: String,
+    pub protocol: Protocol,
+    pub state: State,
+    pub mode: Mode,
+    /// Battery level in percent.
+    pub battery: Option<u8>,
+    pub rssi: Option<i16>,
+    pub side: Option<Side>,
+    pub hisyncid: Option<HiSyncId>,
+    /// From LE_PSM_OUT, once read.
+    pub psm: Option<u16>,
+    /// Send MTU of the audio channel, once probed.
+    pub mtu: Option<usize>,
+}
+
+/// Something asked of a device task from outside.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// Connect and keep the device connected, whatever its mode, until the
+    /// mode is set again.
+    Connect,
+    /// Disconnect and keep the device disconnected, whatever its mode,
+    /// until the mode is set again.
+    Disconnect,
+    SetMode(Mode),
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Connect => write!(f, "connect"),
+            Request::Disconnect => write!(f, "disconnect"),
+            Request::SetMode(mode) => write!(f, "mode {mode}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownDevice(pub Address);
+
+impl fmt::Display for UnknownDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not a managed device", self.0)
+    }
+}
+
+impl std::error::Error for UnknownDevice {}
+
+struct Entry {
+    status: DeviceStatus,
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+/// Every managed device, shared between the supervisor and the service.
+#[derive(Clone)]
+pub struct Registry {
+    devices: Arc<Mutex<BTreeMap<Address, Entry>>>,
+    modes: watch::Sender<BTreeMap<Address, Mode>>,
+    changes: broadcast::Sender<DeviceStatus>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            devices: Arc::default(),
+            modes: watch::Sender::default(),
+            changes: broadcast::Sender::new(CHANGES_CAPACITY),
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry that starts out remembering `modes`.
+    pub fn with_modes(modes: BTreeMap<Address, Mode>) -> Self {
+        let registry = Self::default();
+        registry.modes.send_replace(modes);
+        registry
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<Address, Entry>> {
+        self.devices.lock().expect("device registry poisoned")
+    }
+
+    /// Remembers the mode of a device, telling whether it changed.
+    fn remember(&self, address: Address, mode: Mode) -> bool {
+        self.modes
+            .send_if_modified(|modes| modes.insert(address, mode) != Some(mode))
+    }
+
+    /// Lists a device until the returned registration is dropped. Requests
+    /// for it arrive on the receiver. A mode set at runtime for the device
+    /// replaces the one in `status`.
+    pub fn register(
+        &self,
+        mut status: DeviceStatus,
+    ) -> (Registration, mpsc::UnboundedReceiver<Request>) {
+        let address = status.address;
+        let (requests, receiver) = mpsc::unbounded_channel();
+
+        let mut devices = self.lock();
+        if let Some(mode) = self.mode(address) {
+            status.mode = mode;
+        }
+        let _ = self.changes.send(status.clone());
+        devices.insert(
+            address,
+            Entry {
+                status,
+                requests: requests.clone(),
+            },
+        );
+
+        let registration = Registration {
+            registry: self.clone(),
+            address,
+            requests,
+        };
+        (registration, receiver)
+    }
+
+    /// All devices, ordered by address.
+    pub fn devices(&self) -> Vec<DeviceStatus> {
+        self.lock()
+            .values()
+            .map(|entry| entry.status.clone())
+            .collect()
+    }
+
+    pub fn device(&self, address: Address) -> Option<DeviceStatus> {
+        self.lock().get(&address).map(|entry| entry.status.clone())
+    }
+
+    /// Switches a managed device to `mode`. Any other device is only
+    /// remembered to start in it once it is managed.
+    pub fn set_mode(&self, address: Address, mode: Mode) {
+        // Held so that the device cannot be registered in between.
+        let devices = self.lock();
+        let sent = devices
+            .get(&address)
+            .is_some_and(|entry| entry.requests.send(Request::SetMode(mode)).is_ok());
+
+        if !sent {
+            self.remember(address, mode);
+        }
+    }
+
+    pub fn request(&self, address: Address, request: Request) -> Result<(), UnknownDevice> {
+        let devices = self.lock();
+        let entry = devices.get(&address).ok_or(UnknownDevice(address))?;
+
+        entry
+            .requests
+            .send(request)
+            .map_err(|_| UnknownDevice(address))
+    }
+
+    /// The mode last set at runtime for a device, if any.
+    pub fn mode(&self, address: Address) -> Option<Mode> {
+        self.modes.borrow().get(&address).copied()
+    }
+
+    /// Every mode set at runtime, ordered by address.
+    pub fn modes(&self) -> BTreeMap<Address, Mode> {
+        self.modes.borrow().clone()
+    }
+
+    /// Receives every mode set at runtime whenever one of them changes.
+    pub fn watch_modes(&self) -> watch::Receiver<BTreeMap<Address, Mode>> {
+        self.modes.subscribe()
+    }
+
+    /// Receives a device's status whenever its state or mode changes.
+    pub fn changes(&self) -> broadcast::Receiver<DeviceStatus> {
+        self.changes.subscribe()
+    }
+}
+
+/// A device's entry in the registry. Removed when dropped.
+pub struct Registration {
+    registry: Registry,
+    address: Address,
+    /// Tells this entry apart from a later one for the same device, which
+    /// may be registered before this one is dropped.
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Registration {
+    fn entry<'a>(&self, devices: &'a mut BTreeMap<Address, Entry>) -> Option<&'a mut Entry> {
+        devices
+            .get_mut(&self.address)
+            .filter(|entry| entry.requests.same_channel(&self.requests))
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut DeviceStatus)) {
+        let mut devices = self.registry.lock();
+        let Some(entry) = self.entry(&mut devices) else {
+            return;
+        };
+
+        let (state, mode) = (entry.status.state, entry.status.mode);
+        f(&mut entry.status);
+
+        if entry.status.mode != mode {
+            self.registry.remember(self.address, entry.status.mode);
+        }
+        if (entry.status.state, entry.status.mode) != (state, mode) {
+            let _ = self.registry.changes.send(entry.status.clone());
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut devices = self.registry.lock();
+        if self.entry(&mut devices).is_none() {
+            return;
+        }
+        let Some(mut entry) = devices.remove(&self.address) else {
+            return;
+        };
+        drop(devices);
+
+        if entry.status.state != State::Removed {
+            entry.status.state = State::Removed;
+            let _ = self.registry.changes.send(entry.status);
+        }
+    }
+}
@@ -0,0 +1,697 @@
+/*
+
+Streaming audio from userspace
+
+Some BlueZ and PipeWire builds have no ASHA sink: the profile connects,
+but nothing ever sends audio to the aids. `reasha stream` does it instead.
+It opens the L2CAP channel to every aid of the set, sends Start on
+AudioControlPoint and then one G.722 packet per aid every 20 ms, paced by
+the clock rather than by the source.
+
+The kernel only sends while an aid grants credits. Each aid has a short
+queue of its own in front of its channel; an aid that stops granting
+credits fills its queue and loses packets, instead of holding back the
+other side. Both sides share the sequence numbers, so they stay in step.
+
+When the source ends, the last frame is padded with silence and sent like
+the others. An aid still not taking packets a second later is given up on.
+
+*/
+
+mod resample;
+pub mod wav;
+
+pub use resample::Resampler;
+
+use crate::{
+    asha::{
+        AudioType, Codecs, ControlCommand, Side,
+        audio::{FRAME_DURATION, PACKET_BYTES, Packetizer, SAMPLE_RATE},
+    },
+    backend::{AudioChannel, BluetoothDevice},
+    control::{self, ControlError},
+    device::{self, ReadError},
+    device_log,
+    logging::DeviceSpan,
+};
+use bluer::Address;
+use log::Level;
+use std::{collections::VecDeque, fmt, future::Future, io, path::Path, time::Duration};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+use wav::WavError;
+
+/// Packets queued per aid, 80 ms of audio.
+const QUEUE_PACKETS: usize = 4;
+
+/// How long queued packets may take to go out once the stream ends.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bytes read from the source at a time.
+const READ_SIZE: usize = 4096;
+
+/// Layout of raw 16-bit little-endian PCM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub rate: u32,
+    pub channels: u16,
+}
+
+impl Default for PcmFormat {
+    fn default() -> Self {
+        Self {
+            rate: SAMPLE_RATE,
+            channels: 1,
+        }
+    }
+}
+
+impl fmt::Display for PcmFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz, {} channel(s)", self.rate, self.channels)
+    }
+}
+
+pub type Source = Box<dyn AsyncRead + Unpin + Send>;
+
+#[derive(Debug)]
+pub enum StreamError {
+    Source(io::Error),
+    Wav(WavError),
+    Read(ReadError),
+    Control(ControlError),
+    Bluetooth(bluer::Error),
+    /// The device cannot take G.722 at 16 kHz over an L2CAP channel.
+    Unsupported(Address),
+    /// Every aid dropped out of the stream.
+    Disconnected,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Source(err) => write!(f, "unable to read the audio source: {}", err),
+            StreamError::Wav(err) => write!(f, "invalid WAV file: {}", err),
+            StreamError::Read(err) => write!(f, "{}", err),
+            StreamError::Control(err) => write!(f, "{}", err),
+            StreamError::Bluetooth(err) => write!(f, "{}", err),
+            StreamError::Unsupported(address) => write!(
+                f,
+                "{} does not support G.722 at 16 kHz over an L2CAP channel",
+                address
+            ),
+            StreamError::Disconnected => write!(f, "every hearing aid left the stream"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<ReadError> for StreamError {
+    fn from(err: ReadError) -> Self {
+        StreamError::Read(err)
+    }
+}
+
+impl From<ControlError> for StreamError {
+    fn from(err: ControlError) -> Self {
+        StreamError::Control(err)
+    }
+}
+
+impl From<bluer::Error> for StreamError {
+    fn from(err: bluer::Error) -> Self {
+        StreamError::Bluetooth(err)
+    }
+}
+
+/// Opens a file or named pipe, or stdin without a path. A WAV header is
+/// read if there is one; otherwise the input is raw PCM in `format`.
+pub async fn open_source(
+    path: Option<&Path>,
+    format: PcmFormat,
+) -> Result<(Source, PcmFormat), StreamError> {
+    let mut source: Source = match path {
+        Some(path) => Box::new(File::open(path).await.map_err(StreamError::Source)?),
+        None => Box::new(tokio::io::stdin()),
+    };
+
+    let mut magic = [0; 4];
+    let mut read = 0;
+    while read < magic.len() {
+        match source.read(&mut magic[read..]).await {
+            Ok(0) => break,
+            Ok(length) => read += length,
+            Err(err) => return Err(StreamError::Source(err)),
+        }
+    }
+
+    if read == magic.len() && wav::is_riff(&magic) {
+        let format = wav::read_header(&mut source)
+            .await
+            .map_err(StreamError::Wav)?;
+        return Ok((source, format));
+    }
+
+    // Raw PCM: put the bytes looked at back in front.
+    let source = io::Cursor::new(magic[..read].to_vec()).chain(source);
+    Ok((Box::new(source), format))
+}
+
+/// One aid receiving the stream.
+struct Sink<D> {
+    device: D,
+    span: DeviceSpan,
+    /// The channel of the input this aid plays, `None` for a mix of all.
+    side: Option<Side>,
+    packetizer: Packetizer,
+    /// Packets encoded but not yet due.
+    ready: VecDeque<[u8; PACKET_BYTES]>,
+    queue: mpsc::Sender<[u8; PACKET_BYTES]>,
+    sender: JoinHandle<bluer::Result<()>>,
+    sent: u64,
+    dropped: u64,
+}
+
+impl<D: BluetoothDevice> Sink<D> {
+    /// Opens the audio channel and starts the task feeding it. Unless
+    /// both aids of a set play, the aid gets all channels mixed.
+    async fn open(device: D, binaural: bool) -> Result<Self, StreamError> {
+        let advertisement = device::advertisement(&device).await;
+        let span = DeviceSpan::new(device.address(), advertisement.display_name());
+
+        let properties = device::read_properties(&device).await?;
+        if !properties.codecs.supports(Codecs::G722_16KHZ)
+            || !properties.feature_map.coc_streaming()
+        {
+            return Err(StreamError::Unsupported(device.address()));
+        }
+        let side = binaural.then(|| properties.capabilities.side());
+
+        let psm = device::read_psm(&device).await?;
+        let mut channel = device.open_l2cap(psm).await?;
+        let info = channel.info();
+        if info.send_mtu < PACKET_BYTES {
+            return Err(StreamError::Unsupported(device.address()));
+        }
+
+        device_log!(
+            span,
+            Level::Info,
+            "Opened the audio channel to {} on PSM 0x{:04x} (send MTU {}).",
+            span.name,
+            psm,
+            info.send_mtu
+        );
+
+        let (queue, mut packets) = mpsc::channel::<[u8; PACKET_BYTES]>(QUEUE_PACKETS);
+        let sender = tokio::spawn(async move {
+            while let Some(packet) = packets.recv().await {
+                channel.send(&packet).await?;
+            }
+            Ok(())
+        });
+
+        Ok(Self {
+            device,
+            span,
+            side,
+            packetizer: Packetizer::new(),
+            ready: VecDeque::new(),
+            queue,
+            sender,
+            sent: 0,
+            dropped: 0,
+        })
+    }
+
+    /// Picks this aid's sample from an input frame.
+    fn sample(&self, frame: &[i16]) -> i16 {
+        match (self.side, frame) {
+            (Some(Side::Left), [left, _, ..]) => *left,
+            (Some(Side::Right), [_, right, ..]) => *right,
+            _ => {
+                let sum: i32 = frame.iter().map(|&sample| i32::from(sample)).sum();
+                (sum / frame.len() as i32) as i16
+            }
+        }
+    }
+
+    fn encode(&mut self, pcm: &[i16], channels: usize) {
+        let samples: Vec<i16> = pcm
+            .chunks_exact(channels)
+            .map(|frame| self.sample(frame))
+            .collect();
+        let packets = self.packetizer.push(&samples);
+        self.ready.extend(packets);
+    }
+
+    /// Pads the last frame once the source ended.
+    fn finish(&mut self) {
+        self.ready.extend(self.packetizer.finish());
+    }
+
+    /// Hands the next packet to the channel. Returns false once the
+    /// channel is gone.
+    fn send_next(&mut self) -> bool {
+        let Some(packet) = self.ready.pop_front() else {
+            return true;
+        };
+
+        match self.queue.try_send(packet) {
+            Ok(()) => self.sent += 1,
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    device_log!(
+                        self.span,
+                        Level::Warn,
+                        "{} is out of credits, dropping packets.",
+                        self.span.name
+                    );
+                }
+                self.dropped += 1;
+            }
+            Err(TrySendError::Closed(_)) => return false,
+        }
+        true
+    }
+
+    /// Waits for queued packets to go out and logs why the channel closed,
+    /// if it did on its own.
+    async fn close(self) -> (D, DeviceSpan) {
+        let Sink {
+            device,
+            span,
+            queue,
+            mut sender,
+            sent,
+            dropped,
+            ..
+        } = self;
+        drop(queue);
+
+        let Ok(finished) = tokio::time::timeout(CLOSE_TIMEOUT, &mut sender).await else {
+            sender.abort();
+            let _ = sender.await;
+            device_log!(
+                span,
+                Level::Warn,
+                "{} took no more packets, gave up on the last ones.",
+                span.name
+            );
+            return (device, span);
+        };
+
+        match finished {
+            Ok(Ok(())) => device_log!(
+                span,
+                Level::Info,
+                "Sent {} packets to {}, dropped {}.",
+                sent,
+                span.name,
+                dropped
+            ),
+            Ok(Err(err)) => device_log!(
+                span,
+                Level::Warn,
+                "The audio channel to {} failed after {} packets: {}",
+                span.name,
+                sent,
+                err
+            ),
+            Err(err) => device_log!(
+                span,
+                Level::Warn,
+                "Sending to {} failed: {}",
+                span.name,
+                err
+            ),
+        }
+
+        (device, span)
+    }
+}
+
+/// Streams `source` to `devices`, the aids of one set, until the source
+/// ends or `stop` completes. The aids are sent Stop either way.
+pub async fn run<D: BluetoothDevice>(
+    devices: Vec<D>,
+    mut source: impl AsyncRead + Unpin,
+    format: PcmFormat,
+    volume: i8,
+    stop: impl Future<Output = ()>,
+) -> Result<(), StreamError> {
+    let binaural = devices.len() > 1;
+    let channels = usize::from(format.channels);
+
+    let mut sinks = Vec::new();
+    for device in devices {
+        sinks.push(Sink::open(device, binaural).await?);
+    }
+
+    let start = ControlCommand::Start {
+        codec: Codecs::G722_16KHZ,
+        audio_type: AudioType::Media,
+        volume,
+        other_side_connected: binaural,
+    };
+    for sink in &sinks {
+        control::send(&sink.device, start).await?;
+    }
+
+    let mut resampler = Resampler::new(format.rate, SAMPLE_RATE, channels);
+    let mut ticks = tokio::time::interval(FRAME_DURATION);
+    let mut buffer = vec![0; READ_SIZE];
+    let mut bytes = Vec::new();
+    let mut resampled = Vec::new();
+
+    let pump = async {
+        let mut ended = false;
+        while !sinks.is_empty() {
+            while !ended && sinks[0].ready.is_empty() {
+                let read = source
+                    .read(&mut buffer)
+                    .await
+                    .map_err(StreamError::Source)?;
+                if read == 0 {
+                    for sink in &mut sinks {
+                        sink.finish();
+                    }
+                    ended = true;
+                    break;
+                }
+                bytes.extend_from_slice(&buffer[..read]);
+
+                // Only whole frames, the rest waits for the next read.
+                let whole = bytes.len() - bytes.len() % (2 * channels);
+                let pcm: Vec<i16> = bytes
+                    .drain(..whole)
+                    .collect::<Vec<u8>>()
+                    .chunks_exact(2)
+                    .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                    .collect();
+
+                resampled.clear();
+                resampler.push(&pcm, &mut resampled);
+                for sink in &mut sinks {
+                    sink.encode(&resampled, channels);
+                }
+            }
+            if sinks[0].ready.is_empty() {
+                return Ok(());
+            }
+
+            ticks.tick().await;
+
+            let mut index = 0;
+            while index < sinks.len() {
+                if sinks[index].send_next() {
+                    index += 1;
+                } else {
+                    sinks.remove(index).close().await;
+                }
+            }
+        }
+
+        Err(StreamError::Disconnected)
+    };
+
+    let result = tokio::select! {
+        result = pump => result,
+        () = stop => Ok(()),
+    };
+
+    for sink in sinks {
+        let (device, span) = sink.close().await;
+        if let Err(err) = control::send(&device, ControlCommand::Stop).await {
+            device_log!(
+                span,
+                Level::Warn,
+                "Could not stop the stream on {}: {}",
+                span.name,
+                err
+            );
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asha::audio::FRAME_SAMPLES,
+        asha::{self, ASHA_SERVICE_U16, Capabilities, FeatureMap, HiSyncId, ReadOnlyProperties},
+        backend::{
+            BluetoothBackend, ChannelInfo,
+            mock::{CHANNEL_CREDITS, Call, MockBackend, MockDevice},
+        },
+    };
+    use bluer::{Uuid, UuidExt};
+    use std::collections::HashMap;
+
+    const PSM: u16 = 0x0081;
+    const LEFT: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x01]);
+    const RIGHT: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x02]);
+
+    /// A connected aid of a binaural set that streams G.722 over CoC and
+    /// accepts every command.
+    fn aid(backend: &MockBackend, address: Address, side: Side) {
+        let properties = ReadOnlyProperties {
+            version: 1,
+            capabilities: Capabilities(0x02 | u8::from(side == Side::Right)),
+            hisyncid: HiSyncId([1, 2, 3, 4, 5, 6, 7, 8]),
+            feature_map: FeatureMap(0x01),
+            render_delay: 160,
+            codecs: Codecs(1 << Codecs::G722_16KHZ),
+        };
+
+        backend.add_device(MockDevice {
+            address,
+            connected: true,
+            gatt_services: vec![Uuid::from_u16(ASHA_SERVICE_U16)],
+            characteristics: HashMap::from([
+                (
+                    asha::READ_ONLY_PROPERTIES_UUID,
+                    properties.to_bytes().to_vec(),
+                ),
+                (asha::LE_PSM_OUT_UUID, PSM.to_le_bytes().to_vec()),
+                (asha::AUDIO_CONTROL_POINT_UUID, Vec::new()),
+                (asha::AUDIO_STATUS_POINT_UUID, vec![0]),
+            ]),
+            channels: HashMap::from([(
+                PSM,
+                ChannelInfo {
+                    psm: PSM,
+                    send_mtu: 251,
+                    recv_mtu: 251,
+                },
+            )]),
+            ..MockDevice::default()
+        });
+        backend.respond_to_writes(address, asha::AUDIO_CONTROL_POINT_UUID, |_| {
+            vec![(asha::AUDIO_STATUS_POINT_UUID, vec![0])]
+        });
+    }
+
+    /// Takes the device end of the channel once it is open and collects
+    /// every packet until it closes.
+    fn receive(backend: &MockBackend, address: Address) -> JoinHandle<Vec<Vec<u8>>> {
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            let mut channel = loop {
+                match backend.accept_channel(address, PSM) {
+                    Some(channel) => break channel,
+                    None => tokio::time::sleep(Duration::from_millis(1)).await,
+                }
+            };
+            let mut packets = Vec::new();
+            while let Some(packet) = channel.recv().await {
+                packets.push(packet);
+            }
+            packets
+        })
+    }
+
+    /// A WAV file of `frames` frames of stereo 48 kHz PCM, a different tone
+    /// on each channel.
+    fn wav(frames: usize) -> Vec<u8> {
+        let mut samples = Vec::new();
+        for i in 0..frames {
+            let t = i as f64 / 48_000.0;
+            for frequency in [440.0, 1000.0] {
+                let sample = (8000.0 * (2.0 * std::f64::consts::PI * frequency * t).sin()) as i16;
+                samples.extend(sample.to_le_bytes());
+            }
+        }
+
+        let mut format = Vec::new();
+        format.extend(1u16.to_le_bytes());
+        format.extend(2u16.to_le_bytes());
+        format.extend(48_000u32.to_le_bytes());
+        format.extend((48_000u32 * 4).to_le_bytes());
+        format.extend(4u16.to_le_bytes());
+        format.extend(16u16.to_le_bytes());
+
+        let mut file = Vec::new();
+        file.extend(b"RIFF");
+        file.extend((36 + samples.len() as u32).to_le_bytes());
+        file.extend(b"WAVEfmt ");
+        file.extend((format.len() as u32).to_le_bytes());
+        file.extend(format);
+        file.extend(b"data");
+        file.extend((samples.len() as u32).to_le_bytes());
+        file.extend(samples);
+        file
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wav_to_both_aids() {
+        let backend = MockBackend::new();
+        aid(&backend, LEFT, Side::Left);
+        aid(&backend, RIGHT, Side::Right);
+        let left = receive(&backend, LEFT);
+        let right = receive(&backend, RIGHT);
+
+        // 200 ms of audio, ten packets.
+        let mut source = io::Cursor::new(wav(9600));
+        let mut magic = [0; 4];
+        source.read_exact(&mut magic).await.unwrap();
+        assert!(wav::is_riff(&magic));
+        let format = wav::read_header(&mut source).await.unwrap();
+        assert_eq!(
+            format,
+            PcmFormat {
+                rate: 48_000,
+                channels: 2
+            }
+        );
+
+        let devices = vec![
+            backend.device(LEFT).unwrap(),
+            backend.device(RIGHT).unwrap(),
+        ];
+        run(devices, source, format, -10, std::future::pending())
+            .await
+            .unwrap();
+
+        let left = left.await.unwrap();
+        let right = right.await.unwrap();
+        assert_eq!(left.len(), 10);
+        assert_eq!(right.len(), 10);
+        for (sequence, (left, right)) in left.iter().zip(&right).enumerate() {
+            assert_eq!(left.len(), PACKET_BYTES);
+            assert_eq!(right.len(), PACKET_BYTES);
+            assert_eq!(usize::from(left[0]), sequence);
+            assert_eq!(usize::from(right[0]), sequence);
+        }
+        // Each aid plays its own channel.
+        assert_ne!(left[5][1..], right[5][1..]);
+
+        for address in [LEFT, RIGHT] {
+            let commands: Vec<_> = backend
+                .calls(address)
+                .into_iter()
+                .filter_map(|call| match call {
+                    Call::WriteCharacteristic(_, value) => ControlCommand::parse(&value),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                commands,
+                [
+                    ControlCommand::Start {
+                        codec: Codecs::G722_16KHZ,
+                        audio_type: AudioType::Media,
+                        volume: -10,
+                        other_side_connected: true,
+                    },
+                    ControlCommand::Stop,
+                ]
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pads_the_last_frame() {
+        let backend = MockBackend::new();
+        aid(&backend, LEFT, Side::Left);
+        let left = receive(&backend, LEFT);
+
+        // Two and a half frames of raw 16 kHz mono PCM.
+        let samples = 2 * FRAME_SAMPLES + FRAME_SAMPLES / 2;
+        let source = io::Cursor::new(vec![0x10; 2 * samples]);
+
+        let devices = vec![backend.device(LEFT).unwrap()];
+        run(
+            devices,
+            source,
+            PcmFormat::default(),
+            0,
+            std::future::pending(),
+        )
+        .await
+        .unwrap();
+
+        let left = left.await.unwrap();
+        let sequences: Vec<u8> = left.iter().map(|packet| packet[0]).collect();
+        assert_eq!(sequences, [0, 1, 2]);
+        assert!(left.iter().all(|packet| packet.len() == PACKET_BYTES));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_aid_loses_packets_alone() {
+        let backend = MockBackend::new();
+        aid(&backend, LEFT, Side::Left);
+        aid(&backend, RIGHT, Side::Right);
+        // Nothing ever reads from the left aid's channel.
+        let received = receive(&backend, RIGHT);
+
+        let mut left = Sink::open(backend.device(LEFT).unwrap(), true)
+            .await
+            .unwrap();
+        let mut right = Sink::open(backend.device(RIGHT).unwrap(), true)
+            .await
+            .unwrap();
+
+        // Twenty packets of stereo silence for each aid.
+        let pcm = vec![0; 2 * 20 * FRAME_SAMPLES];
+        left.encode(&pcm, 2);
+        right.encode(&pcm, 2);
+
+        let mut ticks = tokio::time::interval(FRAME_DURATION);
+        for _ in 0..20 {
+            ticks.tick().await;
+            assert!(left.send_next());
+            assert!(right.send_next());
+        }
+
+        // The left channel holds a packet per credit, the task feeding it
+        // one more and the queue the rest; the others are dropped.
+        let held = CHANNEL_CREDITS + 1 + QUEUE_PACKETS;
+        assert_eq!(left.sent, held as u64);
+        assert_eq!(left.dropped, 20 - held as u64);
+        assert_eq!(right.sent, 20);
+        assert_eq!(right.dropped, 0);
+
+        left.close().await;
+        right.close().await;
+
+        let received = received.await.unwrap();
+        let sequences: Vec<u8> = received.iter().map(|packet| packet[0]).collect();
+        assert_eq!(sequences, (0..20).collect::<Vec<u8>>());
+
+        // What reached the left aid is the start of the stream, in order.
+        let mut channel = backend.accept_channel(LEFT, PSM).unwrap();
+        let mut sequences = Vec::new();
+        while let Some(packet) = channel.recv().await {
+            sequences.push(packet[0]);
+        }
+        assert_eq!(sequences, (0..CHANNEL_CREDITS as u8).collect::<Vec<u8>>());
+    }
+}
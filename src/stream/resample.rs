@@ -0,0 +1,64 @@
+/*
+
+Sample rate conversion
+
+Linear interpolation between neighbouring input frames. It is cheap and
+has no latency to speak of, which matters more for a fallback path into
+hearing aids than the aliasing it lets through when downsampling music.
+
+*/
+
+/// Converts interleaved 16-bit PCM from one rate to another, keeping the
+/// channel layout.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    channels: usize,
+    /// Input frames per output frame.
+    step: f64,
+    /// Position of the next output frame, in input frames after `previous`.
+    position: f64,
+    /// The last input frame of the previous call.
+    previous: Vec<i16>,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: usize) -> Self {
+        Self {
+            channels,
+            step: f64::from(from) / f64::from(to),
+            position: 0.0,
+            previous: vec![0; channels],
+        }
+    }
+
+    /// Resamples whole frames of `input`, appending to `output`.
+    pub fn push(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        let frames = input.len() / self.channels;
+        let frame = |index: usize| -> &[i16] {
+            if index == 0 {
+                &self.previous
+            } else {
+                &input[(index - 1) * self.channels..index * self.channels]
+            }
+        };
+
+        // Frame 0 is `previous`, so `frames` is the last one available.
+        while self.position < frames as f64 {
+            let index = self.position as usize;
+            let fraction = self.position - index as f64;
+            let (a, b) = (frame(index), frame(index + 1));
+
+            output.extend(a.iter().zip(b).map(|(&a, &b)| {
+                let (a, b) = (f64::from(a), f64::from(b));
+                (a + (b - a) * fraction).round() as i16
+            }));
+
+            self.position += self.step;
+        }
+
+        if frames > 0 {
+            self.previous = frame(frames).to_vec();
+            self.position -= frames as f64;
+        }
+    }
+}
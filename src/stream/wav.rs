@@ -0,0 +1,270 @@
+/*
+
+WAV headers
+
+A RIFF file is a `RIFF` header naming the form (`WAVE`) and a list of
+chunks, each an id, a little-endian length and the payload, padded to an
+even length. Only the `fmt ` chunk matters for playback; everything up to
+the `data` chunk is skipped and the samples follow it until the end of the
+stream. The data length is not relied on, since tools writing to a pipe
+cannot know it in advance.
+
+*/
+
+use super::PcmFormat;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Format chunks are 16 to 40 bytes; anything much longer is garbage.
+const MAX_FORMAT_LENGTH: u32 = 256;
+
+#[derive(Debug)]
+pub enum WavError {
+    Io(std::io::Error),
+    /// A RIFF file of another form than `WAVE`.
+    NotWave,
+    /// The data chunk came before the format chunk.
+    MissingFormat,
+    InvalidFormat,
+    /// Anything but integer PCM.
+    UnsupportedEncoding(u16),
+    UnsupportedBits(u16),
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavError::Io(err) => write!(f, "{}", err),
+            WavError::NotWave => write!(f, "not a WAVE file"),
+            WavError::MissingFormat => write!(f, "no format chunk before the data"),
+            WavError::InvalidFormat => write!(f, "invalid format chunk"),
+            WavError::UnsupportedEncoding(tag) => {
+                write!(f, "format 0x{:04x} is not supported, only PCM is", tag)
+            }
+            WavError::UnsupportedBits(bits) => {
+                write!(f, "{}-bit samples are not supported, only 16-bit", bits)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
+
+impl From<std::io::Error> for WavError {
+    fn from(err: std::io::Error) -> Self {
+        WavError::Io(err)
+    }
+}
+
+/// Whether the first bytes of a stream are a RIFF header.
+pub fn is_riff(magic: &[u8; 4]) -> bool {
+    magic == b"RIFF"
+}
+
+/// Reads the rest of the header after the `RIFF` magic, leaving `reader` at
+/// the first sample.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<PcmFormat, WavError> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).await?;
+    if &header[4..] != b"WAVE" {
+        return Err(WavError::NotWave);
+    }
+
+    let mut format = None;
+
+    loop {
+        let mut chunk = [0; 8];
+        reader.read_exact(&mut chunk).await?;
+        let length = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+
+        match &chunk[..4] {
+            b"data" => return format.ok_or(WavError::MissingFormat),
+            b"fmt " => {
+                if length > MAX_FORMAT_LENGTH {
+                    return Err(WavError::InvalidFormat);
+                }
+                let mut payload = vec![0; length as usize];
+                reader.read_exact(&mut payload).await?;
+                format = Some(parse_format(&payload)?);
+            }
+            _ => {
+                tokio::io::copy(&mut reader.take(length.into()), &mut tokio::io::sink()).await?;
+            }
+        }
+
+        if length % 2 == 1 {
+            reader.read_exact(&mut [0]).await?;
+        }
+    }
+}
+
+/// Decodes the payload of a `fmt ` chunk.
+pub fn parse_format(payload: &[u8]) -> Result<PcmFormat, WavError> {
+    let field = |offset: usize| -> Result<u16, WavError> {
+        payload
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .ok_or(WavError::InvalidFormat)
+    };
+
+    // Extensible files name the encoding in a sub-format GUID that starts
+    // with the plain format tag.
+    let tag = match field(0)? {
+        FORMAT_EXTENSIBLE => field(24)?,
+        tag => tag,
+    };
+    let channels = field(2)?;
+    let rate = u32::from(field(4)?) | u32::from(field(6)?) << 16;
+    let bits = field(14)?;
+
+    if tag != FORMAT_PCM {
+        return Err(WavError::UnsupportedEncoding(tag));
+    }
+    if bits != 16 {
+        return Err(WavError::UnsupportedBits(bits));
+    }
+    if channels == 0 || rate == 0 {
+        return Err(WavError::InvalidFormat);
+    }
+
+    Ok(PcmFormat { rate, channels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `fmt ` payload for plain PCM.
+    fn format(channels: u16, rate: u32, bits: u16) -> Vec<u8> {
+        let block = channels * bits / 8;
+        let mut payload = Vec::new();
+        payload.extend(FORMAT_PCM.to_le_bytes());
+        payload.extend(channels.to_le_bytes());
+        payload.extend(rate.to_le_bytes());
+        payload.extend((rate * u32::from(block)).to_le_bytes());
+        payload.extend(block.to_le_bytes());
+        payload.extend(bits.to_le_bytes());
+        payload
+    }
+
+    fn chunk(id: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((payload.len() as u32).to_le_bytes());
+        chunk.extend(payload);
+        if payload.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    /// A header as it follows the `RIFF` magic.
+    fn header(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut header = 0u32.to_le_bytes().to_vec();
+        header.extend(b"WAVE");
+        for chunk in chunks {
+            header.extend(chunk);
+        }
+        header
+    }
+
+    async fn read(bytes: Vec<u8>) -> Result<PcmFormat, WavError> {
+        read_header(&mut bytes.as_slice()).await
+    }
+
+    #[tokio::test]
+    async fn skips_other_chunks_and_padding() {
+        let mut bytes = header(&[
+            chunk(b"LIST", b"odd"),
+            chunk(b"fmt ", &format(2, 44_100, 16)),
+            chunk(b"data", &[]),
+        ]);
+        bytes.extend([1, 2, 3, 4]);
+
+        let mut reader = bytes.as_slice();
+        let format = read_header(&mut reader).await.unwrap();
+        assert_eq!(
+            format,
+            PcmFormat {
+                rate: 44_100,
+                channels: 2
+            }
+        );
+        assert_eq!(reader, [1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn extensible_format() {
+        let mut payload = format(1, 16_000, 16);
+        payload[..2].copy_from_slice(&FORMAT_EXTENSIBLE.to_le_bytes());
+        payload.extend([22, 0, 16, 0, 0, 0, 0, 0]);
+        payload.extend(FORMAT_PCM.to_le_bytes());
+        payload.extend([0; 14]);
+
+        let bytes = header(&[chunk(b"fmt ", &payload), chunk(b"data", &[])]);
+        assert_eq!(read(bytes).await.unwrap(), PcmFormat::default());
+    }
+
+    #[tokio::test]
+    async fn not_wave() {
+        let mut bytes = header(&[]);
+        bytes[4..8].copy_from_slice(b"AVI ");
+        assert!(matches!(read(bytes).await, Err(WavError::NotWave)));
+    }
+
+    #[tokio::test]
+    async fn data_before_format() {
+        let bytes = header(&[chunk(b"data", &[])]);
+        assert!(matches!(read(bytes).await, Err(WavError::MissingFormat)));
+    }
+
+    #[tokio::test]
+    async fn oversized_format_chunk() {
+        let mut bytes = header(&[]);
+        bytes.extend(b"fmt ");
+        bytes.extend((MAX_FORMAT_LENGTH + 1).to_le_bytes());
+        assert!(matches!(read(bytes).await, Err(WavError::InvalidFormat)));
+    }
+
+    #[tokio::test]
+    async fn truncated_header() {
+        let bytes = header(&[chunk(b"fmt ", &format(1, 16_000, 16))]);
+        for length in [0, 6, bytes.len() - 3, bytes.len()] {
+            match read(bytes[..length].to_vec()).await {
+                Err(WavError::Io(err)) => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof)
+                }
+                other => panic!("{length} bytes: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_formats() {
+        assert!(matches!(
+            parse_format(&format(1, 16_000, 16)[..15]),
+            Err(WavError::InvalidFormat)
+        ));
+        assert!(matches!(
+            parse_format(&format(1, 16_000, 24)),
+            Err(WavError::UnsupportedBits(24))
+        ));
+        assert!(matches!(
+            parse_format(&format(0, 16_000, 16)),
+            Err(WavError::InvalidFormat)
+        ));
+        assert!(matches!(
+            parse_format(&format(1, 0, 16)),
+            Err(WavError::InvalidFormat)
+        ));
+
+        let mut float = format(1, 16_000, 16);
+        float[..2].copy_from_slice(&3u16.to_le_bytes());
+        assert!(matches!(
+            parse_format(&float),
+            Err(WavError::UnsupportedEncoding(3))
+        ));
+    }
+}
@@ -0,0 +1,1677 @@
+/*
+
+Supervisor owning one task per managed device
+
+Devices are keyed by address so that a repeated `DeviceAdded` never starts
+a second task but counts as the device advertising again, and
+`DeviceRemoved` stops the task for good.
+
+Each task runs a `state::Machine` fed by device property changes and the
+audio signal. While a device is connected, the results it reports on
+AudioStatusPoint for commands from the audio stack are logged, and with
+volume sync on it gets every change of the mirrored volume. HAS devices go
+through the same machine; they connect over LE Audio, log their presets
+and switch to a configured media preset while audio is wanted.
+
+Every task keeps its device's entry in the status registry current and
+takes requests from it. The device's mode decides whether the audio signal
+or those requests drive the machine.
+
+When an adapter is switched off the tasks of its devices are stopped, and
+started again once it is back on. When bluetoothd goes away every task is
+stopped and the supervisor returns, to be rebuilt from scratch. With
+discovery in bursts the supervisor runs the scans, and a device not heard
+recently waits for one before it is connected.
+
+The supervisor only talks to the Bluetooth stack through a
+`BluetoothBackend`.
+
+*/
+
+use crate::{
+    asha::{self, ReadOnlyProperties, StatusError},
+    backend::{AdapterEvent, AudioChannel, BluetoothBackend, BluetoothDevice, DeviceEvent},
+    binaural::{Member, Membership, SetKey, Sets},
+    capability::{Capability, Protocol, Rejection},
+    config::{Config, DeviceConfig, Discovery},
+    control, device, device_log,
+    discovery::Bursts,
+    has::Preset,
+    logging::DeviceSpan,
+    playback::mpris::{self, Players},
+    preset::{self, PresetError},
+    state::{Action, Input, Machine, Mode, State},
+    status::{DeviceStatus, Registration, Registry, Request},
+};
+use bluer::Address;
+use futures::{Stream, StreamExt, stream::BoxStream};
+use log::{Level, debug, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+
+/// What every device follows besides its own state.
+#[derive(Clone)]
+pub struct Signals {
+    /// Whether audio is wanted, from the playback sources.
+    pub audio: watch::Receiver<bool>,
+    /// The MPRIS players, if followed, to pick a media preset or the
+    /// devices to connect by.
+    pub players: Option<watch::Receiver<Players>>,
+    /// The ASHA Volume value to mirror onto connected devices, if any.
+    pub volume: Option<watch::Receiver<i8>>,
+}
+
+/// Why the supervisor stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    DiscoveryEnded,
+    ServiceLost,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::DiscoveryEnded => write!(f, "discovery ended"),
+            Stop::ServiceLost => write!(f, "bluetoothd went away"),
+        }
+    }
+}
+
+pub struct Supervisor<B: BluetoothBackend> {
+    config: Arc<Config>,
+    backend: B,
+    signals: Signals,
+    registry: Registry,
+    sets: Sets,
+    devices: HashMap<Address, DeviceTask>,
+    /// Adapters reported off, by index.
+    off: HashSet<usize>,
+    /// With discovery in bursts, and the task running them while the
+    /// supervisor does.
+    bursts: Option<Bursts>,
+    scanning: Option<JoinHandle<()>>,
+}
+
+struct DeviceTask {
+    inbox: mpsc::UnboundedSender<Input>,
+    handle: JoinHandle<()>,
+    /// The adapter the device is handled on.
+    adapter: usize,
+}
+
+impl<B: BluetoothBackend> Supervisor<B> {
+    pub fn new(config: Arc<Config>, backend: B, signals: Signals, registry: Registry) -> Self {
+        let bursts = match config.bluetooth.discovery {
+            Discovery::Continuous => None,
+            Discovery::Bursts { duration, interval } => Some(Bursts::new(duration, interval)),
+        };
+
+        Self {
+            config,
+            backend,
+            signals,
+            registry,
+            sets: Sets::new(),
+            devices: HashMap::new(),
+            off: HashSet::new(),
+            bursts,
+            scanning: None,
+        }
+    }
+
+    /// Handles adapter events until the stream ends or bluetoothd goes away,
+    /// and then stops every device task. While an adapter is off its
+    /// devices are let go of, and they are taken up again once it is back
+    /// on. With discovery in bursts, `events` should come from `watch` and
+    /// the scans run alongside.
+    pub async fn run(&mut self, events: impl Stream<Item = AdapterEvent>) -> Stop {
+        let mut events = std::pin::pin!(events);
+
+        if let Some(bursts) = self.bursts.clone() {
+            let backend = self.backend.clone();
+            self.scanning = Some(tokio::spawn(async move { bursts.run(backend).await }));
+        }
+
+        let stop = loop {
+            match events.next().await {
+                Some(AdapterEvent::DeviceAdded(address)) => self.device_added(address),
+                Some(AdapterEvent::DeviceRemoved(address)) => self.device_removed(address),
+                Some(AdapterEvent::Powered {
+                    adapter,
+                    powered: true,
+                }) => self.powered_on(adapter).await,
+                Some(AdapterEvent::Powered {
+                    adapter,
+                    powered: false,
+                }) => self.powered_off(adapter).await,
+                Some(AdapterEvent::ServiceLost) => break Stop::ServiceLost,
+                None => break Stop::DiscoveryEnded,
+            }
+        };
+
+        self.shutdown().await;
+        stop
+    }
+
+    /// Stops every device task and waits for it, so that the devices are
+    /// gone from the registry and their sets before anything is rebuilt.
+    async fn shutdown(&mut self) {
+        if let Some(scanning) = self.scanning.take() {
+            scanning.abort();
+            let _ = scanning.await;
+        }
+        self.stop_devices().await;
+    }
+
+    async fn stop_devices(&mut self) {
+        for (_, task) in self.devices.drain() {
+            task.handle.abort();
+            let _ = task.handle.await;
+        }
+    }
+
+    /// Lets go of the adapter's devices, as nothing can be done with them
+    /// until it is back. Those on other adapters carry on.
+    async fn powered_off(&mut self, adapter: usize) {
+        self.off.insert(adapter);
+        info!(
+            "{} is off, waiting for it to be switched on.",
+            self.backend.adapter_name(adapter)
+        );
+
+        let stopped: Vec<Address> = self
+            .devices
+            .iter()
+            .filter(|(_, task)| task.adapter == adapter)
+            .map(|(address, _)| *address)
+            .collect();
+        for address in stopped {
+            if let Some(task) = self.devices.remove(&address) {
+                task.handle.abort();
+                let _ = task.handle.await;
+            }
+        }
+    }
+
+    /// Takes up every device of the adapter again.
+    async fn powered_on(&mut self, adapter: usize) {
+        self.off.remove(&adapter);
+        info!("{} was switched on.", self.backend.adapter_name(adapter));
+
+        match self.backend.devices().await {
+            Ok(addresses) => {
+                for address in addresses {
+                    if self.backend.adapter_index(address) == adapter {
+                        self.device_added(address);
+                    }
+                }
+            }
+            Err(err) => warn!(
+                "Could not list the devices of {}: {}",
+                self.backend.adapter_name(adapter),
+                err
+            ),
+        }
+    }
+
+    fn device_added(&mut self, address: Address) {
+        // Taken up once its adapter is on.
+        let adapter = self.backend.adapter_index(address);
+        if self.off.contains(&adapter) {
+            return;
+        }
+
+        if let Some(task) = self.devices.get(&address)
+            && !task.handle.is_finished()
+        {
+            let _ = task.inbox.send(Input::Advertised);
+            return;
+        }
+
+        let Ok(device) = self.backend.device(address) else {
+            return;
+        };
+
+        let (inbox, inputs) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(manage_device(
+            Arc::clone(&self.config),
+            self.sets.clone(),
+            self.registry.clone(),
+            device,
+            self.signals.clone(),
+            self.bursts.clone(),
+            inputs,
+        ));
+
+        self.devices.insert(
+            address,
+            DeviceTask {
+                inbox,
+                handle,
+                adapter,
+            },
+        );
+    }
+
+    fn device_removed(&mut self, address: Address) {
+        let Some(task) = self.devices.remove(&address) else {
+            return;
+        };
+
+        if task.inbox.send(Input::Removed).is_err() {
+            // The task already finished; the device was never managed.
+            return;
+        }
+
+        info!("Device removed: {}", address);
+    }
+}
+
+impl<B: BluetoothBackend> Drop for Supervisor<B> {
+    fn drop(&mut self) {
+        if let Some(scanning) = &self.scanning {
+            scanning.abort();
+        }
+        for task in self.devices.values() {
+            task.handle.abort();
+        }
+    }
+}
+
+async fn manage_device<D: BluetoothDevice>(
+    config: Arc<Config>,
+    sets: Sets,
+    registry: Registry,
+    device: D,
+    signals: Signals,
+    bursts: Option<Bursts>,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+) {
+    let address = device.address();
+    let mut advertisement = device::advertisement(&device).await;
+    let properties = sets.properties(address);
+    advertisement.identity.hisyncid = properties.map(|properties| properties.hisyncid);
+
+    let Some(device_config) = config.find(&advertisement.identity) else {
+        debug!("{} is not managed.", address);
+        return;
+    };
+
+    let span = DeviceSpan::new(address, advertisement.display_name());
+
+    let force = device_config.options.force_asha;
+    let Some(capability) =
+        check_capability(&device, &mut advertisement, force, &span, &mut inputs).await
+    else {
+        return;
+    };
+
+    device_log!(
+        span,
+        Level::Info,
+        "{} device found: {} (matched by {}, {})",
+        capability.protocol(),
+        span.name,
+        device_config.matcher,
+        capability
+    );
+
+    let advertised = advertisement.asha_service_data.as_deref().and_then(|data| {
+        Some((
+            asha::truncated_hisyncid(data)?,
+            asha::advertised_capabilities(data)?,
+        ))
+    });
+
+    // Until ReadOnlyProperties have been read, the advertisement is all
+    // there is to find the other side by.
+    let membership = match (properties, advertised) {
+        (Some(properties), _) => Some(join_set(&sets, address, &properties)),
+        (None, Some((truncated, capabilities))) => Some(sets.join(
+            address,
+            SetKey {
+                truncated: truncated.try_into().unwrap_or_default(),
+                hisyncid: None,
+            },
+            Member {
+                side: Some(capabilities.side()),
+                binaural: capabilities.binaural(),
+                ..Member::default()
+            },
+        )),
+        (None, None) => None,
+    };
+
+    let rssi = device.rssi().await.ok().flatten();
+    let configured = device_config.options.mode;
+    let (registration, requests) = registry.register(DeviceStatus {
+        address,
+        name: span.name.clone(),
+        protocol: capability.protocol(),
+        state: State::Idle,
+        mode: configured,
+        battery: device.battery().await.ok().flatten(),
+        rssi,
+        side: properties.map(|properties| properties.capabilities.side()),
+        hisyncid: properties.map(|properties| properties.hisyncid),
+        psm: None,
+        mtu: None,
+    });
+
+    // A mode set at runtime takes precedence.
+    let mode = registry
+        .device(address)
+        .map_or(configured, |status| status.mode);
+    if mode != Mode::default() {
+        device_log!(span, Level::Info, "{} is in {} mode.", span.name, mode);
+    }
+
+    let mut driver = Driver {
+        device: &device,
+        span: &span,
+        protocol: capability.protocol(),
+        device_config,
+        sets,
+        membership,
+        registration,
+        waiting: None,
+        psm: None,
+        presets: Vec::new(),
+        switched: None,
+        mode,
+        requested: None,
+        playing: false,
+        bursts,
+        seen: rssi.map(|_| Instant::now()),
+    };
+    driver.run(signals, inputs, requests).await;
+}
+
+/// Checks whether a device is a hearing aid. One that advertises neither
+/// service and has not resolved its services yet is not known to be one or
+/// not, so it is checked again whenever it connects, resolves its services
+/// or advertises, until it is removed.
+async fn check_capability<D: BluetoothDevice>(
+    device: &D,
+    advertisement: &mut device::Advertisement,
+    force: bool,
+    span: &DeviceSpan,
+    inputs: &mut mpsc::UnboundedReceiver<Input>,
+) -> Option<Capability> {
+    // Watched before the first check so that no change in between is missed.
+    let mut events = match device.events().await {
+        Ok(events) => events,
+        Err(err) => {
+            device_log!(
+                span,
+                Level::Error,
+                "Could not watch {} for changes: {}",
+                span.name,
+                err
+            );
+            return None;
+        }
+    };
+
+    let mut logged = false;
+    loop {
+        match device::check_capability(device, advertisement, force).await {
+            Ok(capability) => return Some(capability),
+            Err(Rejection::ServicesUnresolved) if !logged => {
+                device_log!(
+                    span,
+                    Level::Info,
+                    "{} {}; checking again once they are.",
+                    span.name,
+                    Rejection::ServicesUnresolved
+                );
+                logged = true;
+            }
+            Err(Rejection::ServicesUnresolved) => {}
+            Err(rejection) => {
+                device_log!(span, Level::Info, "Ignoring {}: {}.", span.name, rejection);
+                return None;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                input = inputs.recv() => match input {
+                    Some(Input::Advertised) => break,
+                    Some(Input::Removed) | None => return None,
+                    Some(_) => {}
+                },
+                Some(event) = events.next() => match event {
+                    DeviceEvent::Connected(true)
+                    | DeviceEvent::ServicesResolved(true)
+                    | DeviceEvent::Rssi(_) => break,
+                    _ => {}
+                },
+            }
+        }
+
+        // A new advertisement may carry the service data the last one lacked.
+        let hisyncid = advertisement.identity.hisyncid;
+        *advertisement = device::advertisement(device).await;
+        advertisement.identity.hisyncid = hisyncid;
+    }
+}
+
+fn join_set(sets: &Sets, address: Address, properties: &ReadOnlyProperties) -> Membership {
+    sets.join(
+        address,
+        SetKey::new(properties.hisyncid),
+        Member {
+            side: Some(properties.capabilities.side()),
+            binaural: properties.capabilities.binaural(),
+            ..Member::default()
+        },
+    )
+}
+
+/// An action held back until the other side of the set is ready for it.
+#[derive(Clone, Copy)]
+struct Waiting {
+    action: Action,
+    until: Instant,
+}
+
+/// A preset selected while audio is wanted, and the one it replaced.
+#[derive(Clone, Copy)]
+struct Switched {
+    previous: u8,
+    selected: u8,
+}
+
+/// Runs the state machine of one device.
+struct Driver<'a, D> {
+    device: &'a D,
+    span: &'a DeviceSpan,
+    protocol: Protocol,
+    device_config: &'a DeviceConfig,
+    sets: Sets,
+    membership: Option<Membership>,
+    registration: Registration,
+    waiting: Option<Waiting>,
+    /// From LE_PSM_OUT, read whenever the services resolve.
+    psm: Option<u16>,
+    /// The presets of a HAS device, read whenever the services resolve.
+    presets: Vec<Preset>,
+    switched: Option<Switched>,
+    mode: Mode,
+    /// A connection requested or refused from outside, which overrides
+    /// the mode until it is set again. Never saved, unlike the mode.
+    requested: Option<bool>,
+    /// Whether audio is wanted by the playback sources.
+    playing: bool,
+    /// With discovery in bursts, to look for the device before connecting
+    /// it if it was neither heard advertising nor connected since `seen`.
+    bursts: Option<Bursts>,
+    seen: Option<Instant>,
+}
+
+impl<D: BluetoothDevice> Driver<'_, D> {
+    /// Runs until the device is removed.
+    async fn run(
+        &mut self,
+        signals: Signals,
+        mut inputs: mpsc::UnboundedReceiver<Input>,
+        mut requests: mpsc::UnboundedReceiver<Request>,
+    ) {
+        let span = self.span;
+        let Signals {
+            mut audio,
+            mut players,
+            mut volume,
+        } = signals;
+
+        let mut device_events = match self.device.events().await {
+            Ok(events) => events,
+            Err(err) => {
+                device_log!(
+                    span,
+                    Level::Error,
+                    "Could not watch {} for changes: {}",
+                    span.name,
+                    err
+                );
+                return;
+            }
+        };
+
+        let link = self.device.is_connected().await.unwrap_or(false);
+        let wanted = *audio.borrow_and_update();
+        self.playing = self.wants_audio(wanted, &players);
+
+        // AudioStatusPoint notifications while connected.
+        let mut statuses = None;
+        let mut resolved = link && self.device.is_services_resolved().await.unwrap_or(false);
+
+        if resolved {
+            statuses = self.services_resolved(&mut volume).await;
+            self.follow_playback(&players).await;
+        }
+        self.update_member(|member| member.connected = link);
+
+        let input = self.mode.input(self.playing, self.requested);
+        let policy = self.device_config.options.policy();
+        let mut machine = Machine::new(policy, Instant::now(), link, input == Input::Audio(true));
+        self.registration
+            .update(|status| status.state = machine.state());
+        let mut pending = Some(input);
+
+        while machine.state() != State::Removed {
+            if let Some(waiting) = self.waiting
+                && self.may_proceed(waiting)
+            {
+                self.waiting = None;
+                pending = Some(self.perform(waiting.action).await);
+                self.update_member(|member| member.pending = None);
+            }
+
+            let input = match pending.take() {
+                Some(input) => input,
+                None => {
+                    let deadline = machine.deadline();
+                    let waiting_until = self.waiting.map(|waiting| waiting.until);
+                    tokio::select! {
+                        Some(input) = inputs.recv() => input,
+                        Some(request) = requests.recv() => self.request(request),
+                        Some(event) = device_events.next() => match event {
+                            DeviceEvent::Connected(connected) => {
+                                self.seen = Some(Instant::now());
+                                Input::Link(connected)
+                            }
+                            DeviceEvent::Rssi(rssi) => {
+                                self.seen = Some(Instant::now());
+                                self.registration.update(|status| status.rssi = Some(rssi));
+                                Input::Advertised
+                            }
+                            DeviceEvent::Battery(battery) => {
+                                self.registration
+                                    .update(|status| status.battery = Some(battery));
+                                continue;
+                            }
+                            DeviceEvent::ServicesResolved(now_resolved) => {
+                                resolved = now_resolved;
+                                if resolved {
+                                    statuses = self.services_resolved(&mut volume).await;
+                                    self.follow_playback(&players).await;
+                                } else {
+                                    statuses = None;
+                                    self.switched = None;
+                                }
+                                continue;
+                            }
+                        },
+                        () = changed(&mut volume) => {
+                            if resolved {
+                                self.sync_volume(&mut volume).await;
+                            }
+                            continue;
+                        }
+                        () = changed(&mut players) => {
+                            let wanted = self.wants_audio(*audio.borrow(), &players);
+                            let was_playing = std::mem::replace(&mut self.playing, wanted);
+                            if resolved && (self.playing || was_playing) {
+                                self.follow_playback(&players).await;
+                            }
+                            if self.playing == was_playing {
+                                continue;
+                            }
+                            self.mode.input(self.playing, self.requested)
+                        }
+                        status = next_status(&mut statuses) => {
+                            self.log_status(status);
+                            continue;
+                        }
+                        Ok(()) = audio.changed() => {
+                            let wanted = *audio.borrow_and_update();
+                            self.playing = self.wants_audio(wanted, &players);
+                            if resolved {
+                                self.follow_playback(&players).await;
+                            }
+                            self.mode.input(self.playing, self.requested)
+                        }
+                        () = sleep_until(deadline) => Input::Timer,
+                        () = partner_changed(&mut self.membership, waiting_until) => continue,
+                        () = sleep_until(waiting_until) => continue,
+                        else => return,
+                    }
+                }
+            };
+
+            device_log!(
+                span,
+                Level::Trace,
+                "Input {:?} in state {}",
+                input,
+                machine.state()
+            );
+
+            let previous = machine.state();
+            let now = Instant::now();
+            let mut action = machine.handle(now, input);
+
+            if let Input::Link(up) = input {
+                self.update_member(|member| member.connected = up);
+            }
+
+            // An action still waiting for the other side is dropped once
+            // it is no longer wanted.
+            let wanted = match input {
+                Input::Audio(wanted) => Some(wanted),
+                Input::Release => Some(false),
+                _ => None,
+            };
+            if let (Some(waiting), Some(wanted)) = (self.waiting, wanted)
+                && wanted != (waiting.action == Action::Connect)
+            {
+                self.waiting = None;
+                self.update_member(|member| member.pending = None);
+                action = machine.handle(now, Input::Cancelled);
+            }
+
+            if machine.state() != previous {
+                self.registration
+                    .update(|status| status.state = machine.state());
+                device_log!(
+                    span,
+                    Level::Info,
+                    "{}: {} -> {}",
+                    span.name,
+                    previous,
+                    machine.state()
+                );
+
+                if machine.state() == State::Backoff
+                    && let Some(retry_at) = machine.deadline()
+                {
+                    device_log!(
+                        span,
+                        Level::Info,
+                        "{}: retrying in {:.1}s after {} failed attempt(s).",
+                        span.name,
+                        retry_at.duration_since(now).as_secs_f64(),
+                        machine.attempts()
+                    );
+                }
+            }
+
+            if let Some(action) = action {
+                let timeout = self.device_config.options.binaural_timeout;
+                self.waiting = Some(Waiting {
+                    action,
+                    until: now + timeout,
+                });
+                self.update_member(|member| member.pending = Some(action));
+            }
+        }
+    }
+
+    /// Applies a request from outside and returns what the machine is told
+    /// now.
+    fn request(&mut self, request: Request) -> Input {
+        let span = self.span;
+
+        match request {
+            Request::Connect | Request::Disconnect => {
+                self.requested = Some(request == Request::Connect);
+                device_log!(
+                    span,
+                    Level::Info,
+                    "{}: {} requested, overriding {} mode.",
+                    span.name,
+                    request,
+                    self.mode
+                );
+            }
+            Request::SetMode(mode) => {
+                self.mode = mode;
+                self.requested = None;
+                self.registration.update(|status| status.mode = mode);
+                device_log!(span, Level::Info, "{}: now in {} mode.", span.name, mode);
+            }
+        }
+
+        self.mode.input(self.playing, self.requested)
+    }
+
+    fn update_member(&self, f: impl FnOnce(&mut Member)) {
+        if let Some(membership) = &self.membership {
+            membership.update(f);
+        }
+    }
+
+    /// Whether a held back action may go ahead, either together with the
+    /// other side or alone once it has waited long enough.
+    fn may_proceed(&mut self, waiting: Waiting) -> bool {
+        let span = self.span;
+        let Some(membership) = &mut self.membership else {
+            return true;
+        };
+
+        if membership.ready(waiting.action) {
+            return true;
+        }
+
+        if Instant::now() < waiting.until {
+            return false;
+        }
+
+        device_log!(
+            span,
+            Level::Info,
+            "{}: other side not ready after {:.1}s, {} alone.",
+            span.name,
+            self.device_config.options.binaural_timeout.as_secs_f64(),
+            match waiting.action {
+                Action::Connect => "connecting",
+                Action::Disconnect => "disconnecting",
+            }
+        );
+        true
+    }
+
+    /// Everything done once the GATT database is available after
+    /// connecting. Returns the AudioStatusPoint notifications.
+    async fn services_resolved(
+        &mut self,
+        volume: &mut Option<watch::Receiver<i8>>,
+    ) -> Option<BoxStream<'static, Result<(), StatusError>>> {
+        if self.protocol == Protocol::Has {
+            self.read_presets().await;
+            return None;
+        }
+
+        self.read_properties().await;
+        self.read_psm().await;
+        if self.device_config.options.probe_l2cap {
+            self.probe_l2cap().await;
+        }
+
+        let statuses = self.watch_statuses().await;
+        self.sync_volume(volume).await;
+        statuses
+    }
+
+    async fn read_psm(&mut self) {
+        let span = self.span;
+
+        match device::read_psm(self.device).await {
+            Ok(psm) => {
+                if self.psm != Some(psm) {
+                    device_log!(
+                        span,
+                        Level::Debug,
+                        "{} streams audio on PSM 0x{:04x}.",
+                        span.name,
+                        psm
+                    );
+                }
+                self.psm = Some(psm);
+                self.registration.update(|status| status.psm = Some(psm));
+            }
+            Err(err) => device_log!(
+                span,
+                Level::Warn,
+                "Could not read LE_PSM_OUT of {}: {}",
+                span.name,
+                err
+            ),
+        }
+    }
+
+    /// Checks that the audio channel can be opened, now that the profile
+    /// is connected.
+    async fn probe_l2cap(&self) {
+        let span = self.span;
+        let Some(psm) = self.psm else {
+            return;
+        };
+
+        match self
+            .device
+            .open_l2cap(psm)
+            .await
+            .map(|channel| channel.info())
+        {
+            Ok(channel) => {
+                device_log!(
+                    span,
+                    Level::Info,
+                    "L2CAP channel to {} on PSM 0x{:04x} is reachable (send MTU {}, receive MTU {}).",
+                    span.name,
+                    psm,
+                    channel.send_mtu,
+                    channel.recv_mtu
+                );
+                self.registration
+                    .update(|status| status.mtu = Some(channel.send_mtu));
+            }
+            Err(err) => device_log!(
+                span,
+                Level::Warn,
+                "Could not open an L2CAP channel to {} on PSM 0x{:04x}: {}",
+                span.name,
+                psm,
+                err
+            ),
+        }
+    }
+
+    /// Reads ReadOnlyProperties once per device and moves the device into
+    /// the set of its full HiSyncId.
+    async fn read_properties(&mut self) {
+        let span = self.span;
+        let address = self.device.address();
+
+        let properties = match self.sets.properties(address) {
+            Some(properties) => properties,
+            None => {
+                let properties = match device::read_properties(self.device).await {
+                    Ok(properties) => properties,
+                    Err(err) => {
+                        device_log!(
+                            span,
+                            Level::Warn,
+                            "Could not read ReadOnlyProperties of {}: {}",
+                            span.name,
+                            err
+                        );
+                        return;
+                    }
+                };
+
+                device_log!(
+                    span,
+                    Level::Info,
+                    "{} is the {} side of HiSyncId {}{}.",
+                    span.name,
+                    properties.capabilities.side(),
+                    properties.hisyncid,
+                    if properties.capabilities.binaural() {
+                        ""
+                    } else {
+                        " (monaural)"
+                    }
+                );
+
+                self.sets.remember(address, properties);
+                properties
+            }
+        };
+
+        self.registration.update(|status| {
+            status.side = Some(properties.capabilities.side());
+            status.hisyncid = Some(properties.hisyncid);
+        });
+
+        if self
+            .membership
+            .as_ref()
+            .is_some_and(|membership| membership.key().hisyncid == Some(properties.hisyncid))
+        {
+            return;
+        }
+
+        // Leave the old set before joining, it may be the same one.
+        self.membership = None;
+
+        let membership = join_set(&self.sets, address, &properties);
+        let waiting = self.waiting;
+        membership.update(|member| {
+            member.connected = true;
+            member.pending = waiting.map(|waiting| waiting.action);
+        });
+        self.membership = Some(membership);
+    }
+
+    async fn watch_statuses(&self) -> Option<BoxStream<'static, Result<(), StatusError>>> {
+        let span = self.span;
+
+        match control::statuses(self.device).await {
+            Ok(statuses) => Some(statuses),
+            Err(err) => {
+                device_log!(
+                    span,
+                    Level::Warn,
+                    "Could not watch AudioStatusPoint of {}: {}",
+                    span.name,
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Reads and logs the hearing aid type and presets of a HAS device.
+    async fn read_presets(&mut self) {
+        let span = self.span;
+
+        let result = async {
+            let features = preset::read_features(self.device).await?;
+            let presets = preset::read_presets(self.device).await?;
+            let active = preset::active_preset(self.device).await?;
+            Ok::<_, PresetError>((features, presets, active))
+        };
+
+        match result.await {
+            Ok((features, presets, active)) => {
+                device_log!(
+                    span,
+                    Level::Info,
+                    "{} is a {} hearing aid with {} preset(s), the active one is {}.",
+                    span.name,
+                    features.hearing_aid_type(),
+                    presets.len(),
+                    preset_name(&presets, active)
+                );
+                self.presets = presets;
+            }
+            Err(err) => device_log!(
+                span,
+                Level::Warn,
+                "Could not read the presets of {}: {}",
+                span.name,
+                err
+            ),
+        }
+    }
+
+    /// Whether the playback sources want audio on this device. With players
+    /// configured for it, one of them must be playing as well.
+    fn wants_audio(&self, wanted: bool, players: &Option<watch::Receiver<Players>>) -> bool {
+        match players {
+            Some(players) if wanted => self
+                .device_config
+                .options
+                .follows(mpris::playing(&players.borrow())),
+            _ => wanted,
+        }
+    }
+
+    /// Selects the configured media preset of a HAS device while audio is
+    /// wanted and the one it replaced once audio stops. A preset chosen by
+    /// hand in the meantime is left alone.
+    async fn follow_playback(&mut self, players: &Option<watch::Receiver<Players>>) {
+        let (device, span) = (self.device, self.span);
+        let options = &self.device_config.options;
+        if self.protocol != Protocol::Has || !options.switches_presets() {
+            return;
+        }
+
+        let target = if self.playing {
+            match players {
+                Some(players) => options.preset_for(mpris::playing(&players.borrow())),
+                None => options.preset_for(std::iter::empty()),
+            }
+        } else {
+            None
+        };
+
+        let active = match preset::active_preset(device).await {
+            Ok(active) => active,
+            Err(err) => {
+                device_log!(
+                    span,
+                    Level::Warn,
+                    "Could not read the active preset of {}: {}",
+                    span.name,
+                    err
+                );
+                return;
+            }
+        };
+
+        let (index, previous) = match (target, self.switched) {
+            (Some(target), switched) => {
+                let Some(preset) = target.find(&self.presets) else {
+                    device_log!(span, Level::Warn, "{} has no preset {}.", span.name, target);
+                    return;
+                };
+                if !preset.available {
+                    device_log!(
+                        span,
+                        Level::Warn,
+                        "Preset `{}` of {} is unavailable.",
+                        preset.name,
+                        span.name
+                    );
+                    return;
+                }
+                if preset.index == active {
+                    return;
+                }
+                let previous = match switched {
+                    Some(switched) if switched.selected == active => switched.previous,
+                    _ => active,
+                };
+                (preset.index, Some(previous))
+            }
+            (None, Some(switched)) => {
+                self.switched = None;
+                // No preset was active before, so there is none to go back to.
+                if switched.previous == 0 {
+                    return;
+                }
+                if switched.selected != active {
+                    device_log!(
+                        span,
+                        Level::Debug,
+                        "The preset of {} was changed during playback, keeping it.",
+                        span.name
+                    );
+                    return;
+                }
+                (switched.previous, None)
+            }
+            (None, None) => return,
+        };
+
+        // Each aid of a set is switched by its own task, so nothing is
+        // passed on to the other side.
+        match preset::set_active(device, index, false).await {
+            Ok(()) => {
+                device_log!(
+                    span,
+                    Level::Info,
+                    "Switched {} to preset {} {}.",
+                    span.name,
+                    preset_name(&self.presets, index),
+                    if previous.is_some() {
+                        "for playback"
+                    } else {
+                        "after playback"
+                    }
+                );
+                self.switched = previous.map(|previous| Switched {
+                    previous,
+                    selected: index,
+                });
+            }
+            Err(err) => device_log!(
+                span,
+                Level::Warn,
+                "Could not select preset {} on {}: {}",
+                preset_name(&self.presets, index),
+                span.name,
+                err
+            ),
+        }
+    }
+
+    /// Writes the current mirrored volume, if volume sync is on. Only ASHA
+    /// has a volume of its own.
+    async fn sync_volume(&self, volume: &mut Option<watch::Receiver<i8>>) {
+        let span = self.span;
+        if self.protocol != Protocol::Asha {
+            return;
+        }
+        let Some(volume) = volume.as_mut().map(|volume| *volume.borrow_and_update()) else {
+            return;
+        };
+
+        match device::set_volume(self.device, volume).await {
+            Ok(()) => device_log!(
+                span,
+                Level::Debug,
+                "Set the volume of {} to {} dB.",
+                span.name,
+                volume
+            ),
+            Err(err) => device_log!(
+                span,
+                Level::Warn,
+                "Could not set the volume of {}: {}",
+                span.name,
+                err
+            ),
+        }
+    }
+
+    fn log_status(&self, status: Result<(), StatusError>) {
+        let span = self.span;
+
+        match status {
+            Ok(()) => device_log!(
+                span,
+                Level::Debug,
+                "{} accepted an AudioControlPoint command.",
+                span.name
+            ),
+            Err(err) => device_log!(
+                span,
+                Level::Warn,
+                "{} rejected an AudioControlPoint command: {}",
+                span.name,
+                err
+            ),
+        }
+    }
+
+    async fn perform(&self, action: Action) -> Input {
+        let (device, span) = (self.device, self.span);
+
+        match action {
+            Action::Connect => {
+                if let Some(bursts) = &self.bursts
+                    && bursts.is_stale(self.seen)
+                {
+                    device_log!(
+                        span,
+                        Level::Debug,
+                        "{} was not heard recently, scanning before connecting.",
+                        span.name
+                    );
+                    if !bursts.scan_now().await {
+                        device_log!(
+                            span,
+                            Level::Debug,
+                            "No scan ended in time, connecting {} anyway.",
+                            span.name
+                        );
+                    }
+                }
+                match device::connect(
+                    device,
+                    span,
+                    self.protocol,
+                    self.device_config.options.auto_trust,
+                )
+                .await
+                {
+                    Ok(_) => {
+                        device_log!(span, Level::Info, "Connected {}.", span.name);
+                        Input::ConnectSucceeded
+                    }
+                    Err(err) => {
+                        device_log!(
+                            span,
+                            Level::Warn,
+                            "Could not connect {}: {}",
+                            span.name,
+                            err
+                        );
+                        Input::ConnectFailed
+                    }
+                }
+            }
+            Action::Disconnect => match device.disconnect().await {
+                Ok(_) => {
+                    device_log!(span, Level::Info, "Disconnected {}.", span.name);
+                    Input::Link(false)
+                }
+                Err(err) => {
+                    device_log!(
+                        span,
+                        Level::Warn,
+                        "Could not disconnect {}: {}",
+                        span.name,
+                        err
+                    );
+                    Input::DisconnectFailed
+                }
+            },
+        }
+    }
+}
+
+/// The name of a preset by index, for logging.
+fn preset_name(presets: &[Preset], index: u8) -> String {
+    presets
+        .iter()
+        .find(|preset| preset.index == index)
+        .map_or_else(
+            || "unknown".to_owned(),
+            |preset| format!("`{}`", preset.name),
+        )
+}
+
+/// Resolves when a partner changes while an action is held back.
+async fn partner_changed(membership: &mut Option<Membership>, waiting_until: Option<Instant>) {
+    match membership {
+        Some(membership) if waiting_until.is_some() => membership.changed().await,
+        _ => std::future::pending().await,
+    }
+}
+
+/// Resolves with the next AudioStatusPoint result, forgetting the
+/// subscription once it ends.
+async fn next_status(
+    statuses: &mut Option<BoxStream<'static, Result<(), StatusError>>>,
+) -> Result<(), StatusError> {
+    if let Some(stream) = statuses {
+        if let Some(status) = stream.next().await {
+            return status;
+        }
+        *statuses = None;
+    }
+    std::future::pending().await
+}
+
+/// Resolves when an optional signal, such as the mirrored volume, changes.
+async fn changed<T>(signal: &mut Option<watch::Receiver<T>>) {
+    if let Some(receiver) = signal {
+        if receiver.changed().await.is_ok() {
+            return;
+        }
+        // Nobody updates it any more.
+        *signal = None;
+    }
+    std::future::pending().await
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asha::ASHA_SERVICE_U16,
+        backend::{
+            ChannelInfo,
+            mock::{Call, MockBackend, MockDevice},
+            multi::MultiBackend,
+        },
+    };
+    use bluer::{Uuid, UuidExt};
+    use std::{path::Path, time::Duration};
+
+    const AID: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    const OTHER: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x77]);
+
+    /// A supervisor managing `AID` over a mock backend, running in a task.
+    struct Harness<B = MockBackend> {
+        backend: B,
+        registry: Registry,
+        audio: watch::Sender<bool>,
+        supervisor: JoinHandle<Stop>,
+    }
+
+    impl<B: BluetoothBackend> Harness<B> {
+        async fn start(backend: B, options: &str) -> Self {
+            let config = Config::parse(
+                &format!("[[device]]\naddress = \"{AID}\"\n{options}"),
+                Path::new("reasha.toml"),
+            )
+            .unwrap();
+            let registry = Registry::new();
+            let (audio, receiver) = watch::channel(false);
+            let signals = Signals {
+                audio: receiver,
+                players: None,
+                volume: None,
+            };
+
+            let mut supervisor =
+                Supervisor::new(Arc::new(config), backend.clone(), signals, registry.clone());
+            let events = backend.discover().await.unwrap();
+            let supervisor = tokio::spawn(async move { supervisor.run(events).await });
+
+            Self {
+                backend,
+                registry,
+                audio,
+                supervisor,
+            }
+        }
+
+        fn state(&self) -> Option<State> {
+            self.registry.device(AID).map(|status| status.state)
+        }
+    }
+
+    fn aid() -> MockDevice {
+        MockDevice {
+            address: AID,
+            name: Some("Aid".to_owned()),
+            rssi: Some(-60),
+            uuids: [Uuid::from_u16(ASHA_SERVICE_U16)].into(),
+            gatt_services: vec![Uuid::from_u16(ASHA_SERVICE_U16)],
+            ..MockDevice::default()
+        }
+    }
+
+    /// Waits for `condition` to hold, as the tasks run on their own. The
+    /// tests run on a paused clock, which only moves on once every task
+    /// waits, so this takes no real time and gives up after a minute of
+    /// paused time.
+    async fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting until {what}");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Moves the paused clock on by `duration` and lets every task run
+    /// until all of them wait again, so that what has not happened by then
+    /// is not going to.
+    async fn run_for(duration: Duration) {
+        tokio::time::advance(duration).await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connects_while_audio_is_wanted() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        let harness = Harness::start(backend, "idle_timeout = \"100ms\"\nmin_connected = 0").await;
+
+        eventually("registered", || harness.state() == Some(State::Idle)).await;
+        assert!(harness.backend.calls(AID).is_empty());
+
+        harness.audio.send_replace(true);
+        eventually("connected", || harness.state() == Some(State::Connected)).await;
+        assert!(harness.backend.is_connected(AID));
+        assert_eq!(
+            harness.backend.calls(AID)[..2],
+            [
+                Call::SetTrusted(true),
+                Call::ConnectProfile(Uuid::from_u16(ASHA_SERVICE_U16))
+            ]
+        );
+
+        harness.audio.send_replace(false);
+        eventually("disconnected", || harness.state() == Some(State::Idle)).await;
+        assert!(!harness.backend.is_connected(AID));
+        assert_eq!(harness.backend.calls(AID).last(), Some(&Call::Disconnect));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_after_the_link_drops() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        let harness = Harness::start(backend, "[device.retry]\ninitial_delay = \"50ms\"").await;
+        harness.audio.send_replace(true);
+        eventually("connected", || harness.backend.is_connected(AID)).await;
+
+        harness.backend.refuse_connections(AID, 1);
+        harness.backend.drop_link(AID);
+        eventually("backing off", || harness.state() == Some(State::Backoff)).await;
+        eventually("connected again", || harness.backend.is_connected(AID)).await;
+
+        let connects = harness
+            .backend
+            .calls(AID)
+            .iter()
+            .filter(|call| matches!(call, Call::ConnectProfile(_)))
+            .count();
+        assert_eq!(connects, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaves_other_devices_alone() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice {
+            address: OTHER,
+            ..aid()
+        });
+        // Resolved without either service, so not a hearing aid.
+        backend.add_device(MockDevice {
+            uuids: Default::default(),
+            gatt_services: Vec::new(),
+            connected: true,
+            ..aid()
+        });
+        let harness = Harness::start(backend, "mode = \"always\"").await;
+
+        run_for(Duration::from_millis(200)).await;
+        assert!(harness.registry.devices().is_empty());
+        assert!(harness.backend.calls(AID).is_empty());
+        assert!(harness.backend.calls(OTHER).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unresolved_device_is_checked_again() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice {
+            uuids: Default::default(),
+            ..aid()
+        });
+        let harness = Harness::start(backend, "").await;
+
+        run_for(Duration::from_millis(100)).await;
+        assert_eq!(harness.state(), None);
+
+        // Nothing new in the advertisement.
+        harness.backend.advertise(AID, -50);
+        run_for(Duration::from_millis(100)).await;
+        assert_eq!(harness.state(), None);
+
+        // Connected from elsewhere, revealing the ASHA service.
+        harness.backend.establish_link(AID);
+        eventually("registered", || harness.state() == Some(State::Connected)).await;
+        assert_eq!(
+            harness.registry.device(AID).map(|status| status.protocol),
+            Some(Protocol::Asha)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_override_the_mode_without_changing_it() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        let harness = Harness::start(backend, "").await;
+        eventually("registered", || harness.state().is_some()).await;
+
+        harness.registry.request(AID, Request::Connect).unwrap();
+        eventually("connected", || harness.state() == Some(State::Connected)).await;
+
+        // Playback stopping does not undo the request.
+        harness.audio.send_replace(true);
+        harness.audio.send_replace(false);
+        run_for(Duration::from_millis(100)).await;
+        assert!(harness.backend.is_connected(AID));
+
+        harness.registry.request(AID, Request::Disconnect).unwrap();
+        eventually("disconnected", || harness.state() == Some(State::Idle)).await;
+
+        // Playback does not undo it either.
+        harness.audio.send_replace(true);
+        run_for(Duration::from_millis(100)).await;
+        assert!(!harness.backend.is_connected(AID));
+
+        let status = harness.registry.device(AID).unwrap();
+        assert_eq!(status.mode, Mode::OnPlayback);
+        assert!(harness.registry.modes().is_empty());
+
+        // Setting the mode again ends the override.
+        harness
+            .registry
+            .request(AID, Request::SetMode(Mode::OnPlayback))
+            .unwrap();
+        eventually("connected", || harness.state() == Some(State::Connected)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_the_psm_and_channel_mtu() {
+        let backend = MockBackend::new();
+        let channel = ChannelInfo {
+            psm: 0x0081,
+            send_mtu: 167,
+            recv_mtu: 251,
+        };
+        backend.add_device(MockDevice {
+            characteristics: [(asha::LE_PSM_OUT_UUID, vec![0x81, 0x00])].into(),
+            channels: [(0x0081, channel)].into(),
+            ..aid()
+        });
+        let harness = Harness::start(backend, "mode = \"always\"\nprobe_l2cap = true").await;
+
+        eventually("probed", || {
+            harness
+                .registry
+                .device(AID)
+                .is_some_and(|status| status.mtu.is_some())
+        })
+        .await;
+        let status = harness.registry.device(AID).unwrap();
+        assert_eq!((status.psm, status.mtu), (Some(0x0081), Some(167)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn manual_mode_keeps_a_link_made_elsewhere() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        let harness = Harness::start(backend, "mode = \"manual\"").await;
+        eventually("registered", || harness.state() == Some(State::Idle)).await;
+
+        harness.backend.establish_link(AID);
+        eventually("connected", || harness.state() == Some(State::Connected)).await;
+        harness.audio.send_replace(true);
+        harness.audio.send_replace(false);
+        run_for(Duration::from_millis(100)).await;
+        assert!(harness.backend.is_connected(AID));
+        assert!(!harness.backend.calls(AID).contains(&Call::Disconnect));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_go_of_devices_while_powered_off() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        let harness = Harness::start(backend, "").await;
+        harness.audio.send_replace(true);
+        eventually("connected", || harness.state() == Some(State::Connected)).await;
+
+        harness.backend.set_powered(false);
+        eventually("let go of", || harness.state().is_none()).await;
+        assert!(!harness.backend.is_connected(AID));
+
+        harness.backend.set_powered(true);
+        eventually("connected again", || {
+            harness.state() == Some(State::Connected)
+        })
+        .await;
+        assert!(!harness.supervisor.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_devices_of_other_adapters_while_one_is_off() {
+        let first = MockBackend::named("hci0");
+        first.add_device(aid());
+        let second = MockBackend::named("hci1");
+        second.add_device(MockDevice {
+            address: OTHER,
+            ..aid()
+        });
+        let backend = MultiBackend::new(vec![first.clone(), second.clone()]);
+        let harness = Harness::start(
+            backend,
+            &format!("mode = \"always\"\n[[device]]\naddress = \"{OTHER}\"\nmode = \"always\""),
+        )
+        .await;
+        let state = |address| harness.registry.device(address).map(|status| status.state);
+        eventually("both connected", || {
+            state(AID) == Some(State::Connected) && state(OTHER) == Some(State::Connected)
+        })
+        .await;
+
+        second.set_powered(false);
+        eventually("let go of", || state(OTHER).is_none()).await;
+        assert_eq!(state(AID), Some(State::Connected));
+        assert!(first.is_connected(AID));
+        assert!(!first.calls(AID).contains(&Call::Disconnect));
+
+        second.set_powered(true);
+        eventually("connected again", || state(OTHER) == Some(State::Connected)).await;
+        assert_eq!(
+            first
+                .calls(AID)
+                .iter()
+                .filter(|call| matches!(call, Call::ConnectProfile(_)))
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scans_for_a_device_not_heard_before_connecting() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice {
+            rssi: None,
+            ..aid()
+        });
+        let started = Instant::now();
+        let harness = Harness::start(
+            backend,
+            "mode = \"always\"\n[bluetooth]\ndiscovery = \"bursts\"\nscan_duration = \"200ms\"\nscan_interval = \"1m\"",
+        )
+        .await;
+
+        eventually("connected", || harness.backend.is_connected(AID)).await;
+        // A whole burst ran before connecting.
+        assert!(harness.backend.scans() >= 1);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_an_adapter_that_is_off() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        backend.set_powered(false);
+        let harness = Harness::start(backend, "mode = \"always\"").await;
+
+        run_for(Duration::from_millis(100)).await;
+        assert_eq!(harness.state(), None);
+        assert!(harness.backend.calls(AID).is_empty());
+
+        harness.backend.set_powered(true);
+        eventually("connected", || harness.state() == Some(State::Connected)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_when_bluetoothd_goes_away() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        let harness = Harness::start(backend.clone(), "").await;
+        harness.audio.send_replace(true);
+        eventually("connected", || harness.state() == Some(State::Connected)).await;
+
+        backend.stop_service();
+        let stop = tokio::time::timeout(Duration::from_secs(5), harness.supervisor)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stop, Stop::ServiceLost);
+        assert!(harness.registry.devices().is_empty());
+
+        // Rebuilt from scratch once it is back.
+        backend.start_service();
+        let harness = Harness::start(backend, "").await;
+        harness.audio.send_replace(true);
+        eventually("connected again", || {
+            harness.state() == Some(State::Connected)
+        })
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removed_device_leaves_the_registry() {
+        let backend = MockBackend::new();
+        backend.add_device(aid());
+        let harness = Harness::start(backend, "").await;
+        eventually("registered", || harness.state().is_some()).await;
+
+        harness.backend.remove_device(AID);
+        eventually("removed", || harness.state().is_none()).await;
+
+        harness.backend.add_device(aid());
+        eventually("registered again", || harness.state().is_some()).await;
+        assert!(!harness.supervisor.is_finished());
+    }
+}
@@ -0,0 +1,123 @@
+/*
+
+Mirroring the system volume onto hearing aids
+
+Follows the volume and mute state of the default sink on the PulseAudio
+(or pipewire-pulse) server, the same way the pulse playback source follows
+streams: `pactl subscribe` signals a change, `pactl get-sink-volume` and
+`get-sink-mute` tell the new state. The result is turned into the single
+ASHA Volume value that every connected aid receives, so both sides of a
+set always play at the same level.
+
+*/
+
+use crate::{asha, playback::pulse::pactl};
+use log::warn;
+use std::{io, process::Stdio};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::watch,
+};
+
+const DEFAULT_SINK: &str = "@DEFAULT_SINK@";
+
+/// PulseAudio's 100%.
+const NORMAL_VOLUME: f64 = 65536.0;
+
+/// Watches the default sink. The receiver holds the ASHA Volume value
+/// matching its volume and mute state.
+pub async fn watch_default_sink() -> io::Result<watch::Receiver<i8>> {
+    let mut subscription = pactl()
+        .arg("subscribe")
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdout = subscription
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("pactl subscribe has no output"))?;
+
+    let (sender, receiver) = watch::channel(sink_volume().await?);
+
+    tokio::spawn(async move {
+        let _subscription = subscription;
+        let mut lines = BufReader::new(stdout).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            // Volume changes arrive as sink changes, a new default sink as
+            // a server change.
+            if !line.contains("on sink #") && !line.contains("on server") {
+                continue;
+            }
+
+            match sink_volume().await {
+                Ok(volume) => {
+                    sender.send_if_modified(|current| std::mem::replace(current, volume) != volume);
+                }
+                Err(err) => warn!("Unable to read the default sink volume: {}", err),
+            }
+        }
+
+        warn!("PulseAudio event subscription ended, no longer following the sink volume.");
+    });
+
+    Ok(receiver)
+}
+
+async fn sink_volume() -> io::Result<i8> {
+    let volume = pactl_output(&["get-sink-volume", DEFAULT_SINK]).await?;
+    let mute = pactl_output(&["get-sink-mute", DEFAULT_SINK]).await?;
+
+    let muted = mute.trim() == "Mute: yes";
+    let volume = parse_sink_volume(&volume)
+        .ok_or_else(|| io::Error::other(format!("unexpected sink volume `{}`", volume.trim())))?;
+
+    Ok(if muted {
+        asha::MUTED
+    } else {
+        asha_volume(volume)
+    })
+}
+
+async fn pactl_output(args: &[&str]) -> io::Result<String> {
+    let output = pactl().args(args).stderr(Stdio::piped()).output().await?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Reads the loudest channel from the output of `pactl get-sink-volume`,
+/// as a fraction of 100%:
+///
+/// `Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: ...`
+pub fn parse_sink_volume(output: &str) -> Option<f64> {
+    let line = output.lines().next()?.strip_prefix("Volume:")?;
+
+    line.split(',')
+        .map(|channel| {
+            let (_, value) = channel.rsplit_once(':')?;
+            value.split('/').next()?.trim().parse::<u32>().ok()
+        })
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .max()
+        .map(|raw| f64::from(raw) / NORMAL_VOLUME)
+}
+
+/// Converts a sink volume to the ASHA Volume attenuation. PulseAudio
+/// volumes are cubic, so the attenuation is 60 dB per decade; anything
+/// above 100% plays at full volume.
+pub fn asha_volume(volume: f64) -> i8 {
+    if volume <= 0.0 {
+        return asha::MUTED;
+    }
+
+    let db = 60.0 * volume.log10();
+    db.round().clamp(f64::from(asha::MUTED) + 1.0, 0.0) as i8
+}
@@ -0,0 +1,304 @@
+/*
+
+The D-Bus service on a bus of its own
+
+Each test starts a private `dbus-daemon`, publishes `org.reasha.Manager`
+on it for a registry holding one device and talks to the service the way
+other tools would, over a second connection, or runs the command line
+against it.
+
+*/
+
+use bluer::Address;
+use dbus::{
+    arg::{PropMap, prop_cast},
+    channel::Channel,
+    message::MatchRule,
+    nonblock::{Proxy, SyncConnection},
+};
+use futures::StreamExt;
+use reasha::{
+    capability::Protocol,
+    service::{self, BUS_NAME, INTERFACE, PATH},
+    state::{Mode, State},
+    status::{DeviceStatus, Registry, Request},
+};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+const AID: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+
+/// A `dbus-daemon` that lives as long as the test.
+struct Bus {
+    daemon: Child,
+    address: String,
+}
+
+impl Bus {
+    fn start() -> Bus {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("could not start dbus-daemon");
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+
+        Bus {
+            daemon,
+            address: address.trim().to_owned(),
+        }
+    }
+
+    fn connect(&self) -> Arc<SyncConnection> {
+        let mut channel = Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        let (resource, conn) = dbus_tokio::connection::from_channel(channel).unwrap();
+        tokio::spawn(async {
+            resource.await;
+        });
+        conn
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+fn status() -> DeviceStatus {
+    DeviceStatus {
+        address: AID,
+        name: "Aid".to_owned(),
+        protocol: Protocol::Asha,
+        state: State::Idle,
+        mode: Mode::OnPlayback,
+        battery: Some(80),
+        rssi: None,
+        side: None,
+        hisyncid: None,
+        psm: Some(0x0081),
+        mtu: None,
+    }
+}
+
+/// Publishes `registry` and returns a client connection to the service.
+async fn serve(bus: &Bus, registry: &Registry) -> Arc<SyncConnection> {
+    service::publish(&bus.connect(), registry.clone())
+        .await
+        .unwrap();
+    bus.connect()
+}
+
+fn proxy(conn: &SyncConnection) -> Proxy<'_, &SyncConnection> {
+    Proxy::new(BUS_NAME, PATH, Duration::from_secs(5), conn)
+}
+
+async fn set_mode(conn: &SyncConnection, mode: &str) -> Result<(), dbus::Error> {
+    proxy(conn)
+        .method_call(INTERFACE, "SetMode", (AID.to_string(), mode))
+        .await
+}
+
+#[tokio::test]
+async fn lists_and_describes_devices() {
+    let bus = Bus::start();
+    let registry = Registry::new();
+    let _registration = registry.register(status());
+    let client = serve(&bus, &registry).await;
+
+    let (devices,): (HashMap<String, PropMap>,) = proxy(&client)
+        .method_call(INTERFACE, "ListDevices", ())
+        .await
+        .unwrap();
+    assert_eq!(devices.len(), 1);
+    let device = &devices[&AID.to_string()];
+    assert_eq!(prop_cast::<String>(device, "Name").unwrap(), "Aid");
+    assert_eq!(prop_cast::<String>(device, "Protocol").unwrap(), "ASHA");
+    assert_eq!(prop_cast::<String>(device, "State").unwrap(), "idle");
+    assert_eq!(prop_cast::<String>(device, "Mode").unwrap(), "on-playback");
+    assert_eq!(prop_cast::<u8>(device, "Battery"), Some(&80));
+    assert!(!device.contains_key("RSSI"));
+    assert_eq!(prop_cast::<u16>(device, "PSM"), Some(&0x0081));
+    assert!(!device.contains_key("MTU"));
+
+    let (device,): (PropMap,) = proxy(&client)
+        .method_call(INTERFACE, "GetDevice", (AID.to_string(),))
+        .await
+        .unwrap();
+    assert_eq!(
+        prop_cast::<String>(&device, "Address").unwrap(),
+        &AID.to_string()
+    );
+
+    let unknown: Result<(PropMap,), _> = proxy(&client)
+        .method_call(INTERFACE, "GetDevice", ("11:22:33:44:55:77",))
+        .await;
+    assert_eq!(
+        unknown.unwrap_err().name(),
+        Some("org.reasha.Error.UnknownDevice")
+    );
+
+    let invalid: Result<(PropMap,), _> = proxy(&client)
+        .method_call(INTERFACE, "GetDevice", ("hearing aid",))
+        .await;
+    assert_eq!(
+        invalid.unwrap_err().name(),
+        Some("org.freedesktop.DBus.Error.InvalidArgs")
+    );
+}
+
+#[tokio::test]
+async fn hands_requests_to_the_device() {
+    let bus = Bus::start();
+    let registry = Registry::new();
+    let (_registration, mut requests) = registry.register(status());
+    let client = serve(&bus, &registry).await;
+    let address = AID.to_string();
+
+    proxy(&client)
+        .method_call::<(), _, _, _>(INTERFACE, "Connect", (&address,))
+        .await
+        .unwrap();
+    proxy(&client)
+        .method_call::<(), _, _, _>(INTERFACE, "Disconnect", (&address,))
+        .await
+        .unwrap();
+    set_mode(&client, "always").await.unwrap();
+
+    assert_eq!(requests.recv().await, Some(Request::Connect));
+    assert_eq!(requests.recv().await, Some(Request::Disconnect));
+    assert_eq!(requests.recv().await, Some(Request::SetMode(Mode::Always)));
+
+    let invalid = set_mode(&client, "sometimes").await;
+    assert_eq!(
+        invalid.unwrap_err().name(),
+        Some("org.freedesktop.DBus.Error.InvalidArgs")
+    );
+    assert!(requests.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn signals_state_changes() {
+    let bus = Bus::start();
+    let registry = Registry::new();
+    let (registration, _requests) = registry.register(status());
+    let client = serve(&bus, &registry).await;
+
+    let rule = MatchRule::new_signal(INTERFACE, "StateChanged");
+    let (_match, mut signals) = client
+        .add_match(rule)
+        .await
+        .unwrap()
+        .stream::<(String, String, String)>();
+
+    registration.update(|status| status.state = State::Connecting);
+    // Neither state nor mode changed, so nothing is sent.
+    registration.update(|status| status.rssi = Some(-60));
+    registration.update(|status| status.mode = Mode::Never);
+    drop(registration);
+
+    let mut next = async || {
+        let (_, args) = tokio::time::timeout(Duration::from_secs(5), signals.next())
+            .await
+            .expect("no StateChanged signal")
+            .unwrap();
+        args
+    };
+    let address = AID.to_string();
+    assert_eq!(
+        next().await,
+        (
+            address.clone(),
+            "connecting".to_owned(),
+            "on-playback".to_owned()
+        )
+    );
+    assert_eq!(
+        next().await,
+        (address.clone(), "connecting".to_owned(), "never".to_owned())
+    );
+    assert_eq!(
+        next().await,
+        (address, "removed".to_owned(), "never".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn refuses_a_second_instance() {
+    let bus = Bus::start();
+    let _client = serve(&bus, &Registry::new()).await;
+
+    let err = service::publish(&bus.connect(), Registry::new())
+        .await
+        .unwrap_err();
+    assert_eq!(err.name(), Some("org.reasha.Error.NameTaken"));
+}
+
+#[tokio::test]
+async fn remembers_the_mode_of_unmanaged_devices() {
+    let bus = Bus::start();
+    let registry = Registry::new();
+    let mut modes = registry.watch_modes();
+    let client = serve(&bus, &registry).await;
+
+    set_mode(&client, "never").await.unwrap();
+    modes.changed().await.unwrap();
+    assert_eq!(*modes.borrow_and_update(), [(AID, Mode::Never)].into());
+
+    // Once managed, the device starts in it rather than its configured mode.
+    let _registration = registry.register(status());
+    let (device,): (PropMap,) = proxy(&client)
+        .method_call(INTERFACE, "GetDevice", (AID.to_string(),))
+        .await
+        .unwrap();
+    assert_eq!(prop_cast::<String>(&device, "Mode").unwrap(), "never");
+}
+
+#[tokio::test]
+async fn status_asks_the_daemon() {
+    let bus = Bus::start();
+    let registry = Registry::new();
+    let _registration = registry.register(status());
+    let _client = serve(&bus, &registry).await;
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_reasha"))
+        .arg("status")
+        .env("DBUS_SESSION_BUS_ADDRESS", &bus.address)
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let row = stdout
+        .lines()
+        .find(|line| line.starts_with(&AID.to_string()))
+        .expect("no row for the device");
+    let columns: Vec<&str> = row.split_whitespace().collect();
+    assert_eq!(
+        columns,
+        [
+            &AID.to_string(),
+            "Aid",
+            "ASHA",
+            "idle",
+            "on-playback",
+            "80%",
+            "-",
+            "0x0081",
+            "-"
+        ]
+    );
+}